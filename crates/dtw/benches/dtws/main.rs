@@ -19,7 +19,7 @@ fn bench_dtws(c: &mut Criterion) {
                 // We include the instantiation of the DTWImpl in the benchmark
                 let distancefunc = dtw::dtw::STRACDistance::default();
                 let dtw = dtw::dtw::StandardDTW::new(&distancefunc);
-                let _distance = dtw.calculate(Box::new(x.clone()), Box::new(y.clone()));
+                let _distance = dtw.calculate(&x, &y);
 
                 cumulative_duration += start.elapsed();
             }
@@ -41,7 +41,7 @@ fn bench_dtws(c: &mut Criterion) {
 
                 // We include the instantiation of the DTWImpl in the benchmark
                 let dtw = dtw::dtw::UnsafeDTW::new(&distancefunc);
-                let _distance = dtw.calculate(Box::new(x.clone()), Box::new(y.clone()));
+                let _distance = dtw.calculate(&x, &y);
 
                 cumulative_duration += start.elapsed();
             }
@@ -62,7 +62,50 @@ fn bench_dtws(c: &mut Criterion) {
                 let distancefunc = dtw::dtw::STRACDistance::default();
                 // We include the instantiation of the DTWImpl in the benchmark
                 let dtw = dtw::dtw::FixedDTW::new(&distancefunc);
-                let _distance = dtw.calculate(Box::new(x.clone()), Box::new(y.clone()));
+                let _distance = dtw.calculate(&x, &y);
+
+                cumulative_duration += start.elapsed();
+            }
+
+            cumulative_duration
+        })
+    });
+
+    group.bench_function(BenchmarkId::new("fixed dtw i32 kernel/1000", 0), |b| {
+        b.iter_custom(|iters| {
+            let mut cumulative_duration = Duration::new(0, 0);
+            // Create two arrays full of random elements
+            let x: Vec<usize> = (0..1000).map(|_| rand::random()).collect();
+            let y: Vec<usize> = (0..1000).map(|_| rand::random()).collect();
+
+            for _ in 0..iters {
+                let start = std::time::Instant::now();
+                let distancefunc = dtw::dtw::STRACDistance::default();
+                // We include the instantiation of the DTWImpl in the benchmark
+                let dtw =
+                    dtw::dtw::FixedDTW::new(&distancefunc).with_kernel(dtw::simd::Kernel::I32);
+                let _distance = dtw.calculate(&x, &y);
+
+                cumulative_duration += start.elapsed();
+            }
+
+            cumulative_duration
+        })
+    });
+
+    group.bench_function(BenchmarkId::new("wavefront dtw/1000", 0), |b| {
+        b.iter_custom(|iters| {
+            let mut cumulative_duration = Duration::new(0, 0);
+            // Create two arrays full of random elements
+            let x: Vec<usize> = (0..1000).map(|_| rand::random()).collect();
+            let y: Vec<usize> = (0..1000).map(|_| rand::random()).collect();
+
+            for _ in 0..iters {
+                let start = std::time::Instant::now();
+                let distancefunc = dtw::dtw::STRACDistance::default();
+                // We include the instantiation of the DTWImpl in the benchmark
+                let dtw = dtw::dtw::WavefrontDTW::new(&distancefunc).with_chunk(256);
+                let _distance = dtw.calculate(&x, &y);
 
                 cumulative_duration += start.elapsed();
             }
@@ -85,7 +128,7 @@ fn bench_dtws(c: &mut Criterion) {
 
                 let fastdtw = FastDTW::new(&distance, 2, 100, &dtw);
 
-                let _distance = fastdtw.calculate(Box::new(x.clone()), Box::new(y.clone()));
+                let _distance = fastdtw.calculate(&x, &y);
 
                 cumulative_duration += start.elapsed();
             }
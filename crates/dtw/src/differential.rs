@@ -0,0 +1,639 @@
+//! Differential tests of the DTW engines
+//!
+//! Every engine solves the same recurrence, so on the same traces they must agree on the cost and
+//! return well formed paths.
+
+use crate::dtw::{
+    path_cost, Distance, DynamicWindow, FastDTW, FixedDTW, FreeGaps, Match, STRACDistance,
+    StandardDTW, TieBreak, TokenID, UnsafeDTW, WavefrontDTW, WindowedDTW, DTW, OP,
+};
+use crate::matrix::Storage;
+use crate::simd::Kernel;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+/// Random traces, small alphabets give many ties, larger ones give mostly mismatches
+fn traces(rng: &mut StdRng, max_len: usize) -> (Vec<TokenID>, Vec<TokenID>) {
+    let alphabet = [2, 4, 16][rng.gen_range(0..3)];
+    let len1 = rng.gen_range(0..=max_len);
+    let len2 = rng.gen_range(0..=max_len);
+    let chain1 = (0..len1).map(|_| rng.gen_range(0..alphabet)).collect();
+    let chain2 = (0..len2).map(|_| rng.gen_range(0..alphabet)).collect();
+    (chain1, chain2)
+}
+
+/// Costs that are exact in binary, so the order of the sums does not change the result
+fn distance(rng: &mut StdRng) -> STRACDistance {
+    let gap = [0.5, 1.0, 2.0][rng.gen_range(0..3)];
+    let mismatch = [1.0, 1.5, 3.0][rng.gen_range(0..3)];
+    let mut distance = STRACDistance::new(gap, mismatch, 0.0);
+    distance.gap_open = [0.0, 0.0, 0.5, 2.0][rng.gen_range(0..4)];
+    distance.insertion_cost = [None, Some(0.5), Some(4.0)][rng.gen_range(0..3)];
+    distance.deletion_cost = [None, Some(1.0), Some(2.5)][rng.gen_range(0..3)];
+    distance
+}
+
+/// A window as large as the matrix, with it `WindowedDTW` is the standard DTW
+fn full_window(n: usize, m: usize) -> DynamicWindow {
+    let mut window = DynamicWindow::new(n + 1, m);
+    window.init(n + 1);
+    window
+}
+
+/// Runs `check` on `iterations` random cases, the same ones for the same seed
+fn cases(seed: u64, iterations: usize, mut check: impl FnMut(&mut StdRng)) {
+    let mut rng = StdRng::seed_from_u64(seed);
+    for _ in 0..iterations {
+        check(&mut rng);
+    }
+}
+
+/// Free gaps at random ends of the traces
+fn free_gaps(rng: &mut StdRng) -> FreeGaps {
+    FreeGaps {
+        start1: rng.gen(),
+        end1: rng.gen(),
+        start2: rng.gen(),
+        end2: rng.gen(),
+    }
+}
+
+/// Checks that the path goes from `(n, m)`, excluded, to `(0, 0)` one move at a time, that the
+/// minimums are right and returns the cost of the moves, see `path_cost`
+fn check_path(
+    distance: &dyn Distance,
+    chain1: &[TokenID],
+    chain2: &[TokenID],
+    (path, mini, minj): &(Vec<OP>, usize, usize),
+) -> f64 {
+    let end = (chain1.len(), chain2.len());
+    assert_eq!(path.last().unwrap_or(&end), &(0, 0));
+    let cost = path_cost(distance, chain1, chain2, end, path);
+
+    let cells = || path.iter().chain(std::iter::once(&end));
+    assert_eq!(*mini, cells().map(|op| op.0).min().unwrap());
+    assert_eq!(*minj, cells().map(|op| op.1).min().unwrap());
+    cost.unwrap_or_else(|| panic!("{:?} is not a path from {:?}", path, end))
+}
+
+#[test]
+fn test_engines_agree() {
+    cases(0xd7e, 500, |rng| {
+        let distance = distance(rng);
+        let (chain1, chain2) = traces(rng, 40);
+        let (n, m) = (chain1.len(), chain2.len());
+
+        let standard = StandardDTW::new(&distance);
+        let (expected, path) = standard.calculate(&chain1, &chain2);
+        let path = path.unwrap();
+        // The path follows the moves that gave the cost
+        assert_eq!(check_path(&distance, &chain1, &chain2, &path), expected);
+
+        let (cost, unsafe_path) = UnsafeDTW::new(&distance).calculate(&chain1, &chain2);
+        assert_eq!(cost, expected);
+        assert_eq!(unsafe_path.unwrap(), path);
+
+        let (cost, fixed_path) = FixedDTW::new(&distance).calculate(&chain1, &chain2);
+        assert_eq!(cost, expected);
+        assert!(fixed_path.is_none());
+
+        // The costs are multiples of 0.5, the kernels fall back to the rows when they are not
+        // integers
+        for kernel in [Kernel::F32, Kernel::I32] {
+            let fixed = FixedDTW::new(&distance).with_kernel(kernel);
+            assert_eq!(fixed.calculate(&chain1, &chain2), (expected, None));
+        }
+
+        // The divide and conquer path is always an optimal one
+        let (cost, fixed_path) = FixedDTW::with_alignment(&distance).calculate(&chain1, &chain2);
+        assert_eq!(cost, expected);
+        let fixed_path = fixed_path.unwrap();
+        assert_eq!(
+            check_path(&distance, &chain1, &chain2, &fixed_path),
+            expected
+        );
+
+        // Chunks of one and three cells split every anti-diagonal between tasks
+        for chunk in [1, 3, 4096] {
+            let wavefront = WavefrontDTW::new(&distance).with_chunk(chunk);
+            assert_eq!(wavefront.calculate(&chain1, &chain2), (expected, None));
+        }
+
+        // A window as large as the matrix is the standard DTW
+        let (cost, windowed_path) =
+            WindowedDTW::new(full_window(n, m), &distance).calculate(&chain1, &chain2);
+        assert_eq!(cost, expected);
+        assert_eq!(windowed_path.unwrap(), path);
+
+        // So is FastDTW when the radius covers every cell
+        for min_size in [0, 1, 4] {
+            let fastdtw = FastDTW::new(&distance, usize::MAX, min_size, &standard);
+            let (cost, fast_path) = fastdtw.calculate(&chain1, &chain2);
+            assert_eq!(cost, expected);
+            assert_eq!(fast_path.unwrap(), path);
+        }
+    });
+}
+
+#[test]
+fn test_approximations_are_upper_bounds() {
+    cases(0xfa57, 300, |rng| {
+        let distance = distance(rng);
+        let (chain1, chain2) = traces(rng, 80);
+        let (n, m) = (chain1.len(), chain2.len());
+
+        let standard = StandardDTW::new(&distance);
+        let (expected, _) = standard.calculate(&chain1, &chain2);
+
+        // Small radiuses and bands may miss the optimal path, but they always contain one
+        let fixed = FixedDTW::with_alignment(&distance);
+        for radius in [0, 1, 3] {
+            let results = [
+                FastDTW::new(&distance, radius, 2, &standard).calculate(&chain1, &chain2),
+                FastDTW::new(&distance, radius, 2, &fixed).calculate(&chain1, &chain2),
+                WindowedDTW::new(DynamicWindow::sakoe_chiba(n, m, radius), &distance)
+                    .calculate(&chain1, &chain2),
+            ];
+            for (cost, path) in results {
+                assert!(cost.is_finite() && cost >= expected);
+                assert_eq!(
+                    check_path(&distance, &chain1, &chain2, &path.unwrap()),
+                    cost
+                );
+            }
+        }
+    });
+}
+
+/// Checks the path of a match as the path of the query against the tokens of the match
+fn check_match(
+    distance: &dyn Distance,
+    query: &[TokenID],
+    reference: &[TokenID],
+    m: &Match,
+) -> f64 {
+    let (path, mini, minj) = m.path.as_ref().unwrap();
+    let path = path.iter().map(|&(i, j)| (i, j - m.start)).collect();
+    let reference = &reference[m.start..m.end];
+    check_path(distance, query, reference, &(path, *mini, minj - m.start))
+}
+
+#[test]
+fn test_search() {
+    cases(0x5ea4c, 300, |rng| {
+        let distance = distance(rng);
+        let (query, reference) = traces(rng, 20);
+
+        // The best match is the cheapest alignment with any part of the reference
+        let standard = StandardDTW::new(&distance);
+        let expected = (0..=reference.len())
+            .flat_map(|end| (0..=end).map(move |start| (start, end)))
+            .map(|(start, end)| standard.calculate(&query, &reference[start..end]).0)
+            .fold(f64::INFINITY, f64::min);
+
+        let found = standard.search(&query, &reference);
+        assert_eq!(found.cost, expected);
+        assert_eq!(check_match(&distance, &query, &reference, &found), expected);
+        let (cost, path) = StandardDTW::new(&distance)
+            .subsequence()
+            .calculate(&query, &reference);
+        assert_eq!((cost, path), (found.cost, found.path.clone()));
+
+        let fixed = FixedDTW::new(&distance).search(&query, &reference);
+        assert_eq!(
+            (fixed.cost, fixed.end, fixed.path),
+            (expected, found.end, None)
+        );
+
+        // The divide and conquer path is always an optimal one
+        let aligned = FixedDTW::with_alignment(&distance).search(&query, &reference);
+        assert_eq!((aligned.start, aligned.end), (fixed.start, fixed.end));
+        assert_eq!(
+            check_match(&distance, &query, &reference, &aligned),
+            expected
+        );
+        let (cost, path) = FixedDTW::with_alignment(&distance)
+            .subsequence()
+            .calculate(&query, &reference);
+        assert_eq!(cost, expected);
+        let free = FreeGaps::subsequence();
+        assert_eq!(
+            check_free_path(&distance, &query, &reference, free, &path.unwrap()),
+            expected
+        );
+    });
+}
+
+#[test]
+fn test_occurrences() {
+    cases(0x0cc5, 300, |rng| {
+        let distance = distance(rng);
+        let (query, reference) = traces(rng, 20);
+        let threshold = rng.gen_range(0.0..query.len() as f64 + 1.0);
+
+        // Occurrences are ordered, disjoint and each one is as cheap as its block allows
+        let standard = StandardDTW::new(&distance);
+        let found = FixedDTW::new(&distance).occurrences(&query, &reference, threshold);
+        for (i, m) in found.iter().enumerate() {
+            assert!(m.start < m.end && m.end <= reference.len());
+            assert!(i == 0 || found[i - 1].end <= m.start);
+            assert!(m.cost <= threshold);
+            let block = standard.calculate(&query, &reference[m.start..m.end]).0;
+            assert_eq!(m.cost, block);
+        }
+
+        // Whenever the best match is good enough there is at least one occurrence
+        let best = standard.search(&query, &reference);
+        if best.cost <= threshold && best.start < best.end {
+            assert!(!found.is_empty());
+            let cheapest = found.iter().map(|m| m.cost).fold(f64::INFINITY, f64::min);
+            assert_eq!(cheapest, best.cost);
+        }
+
+        let aligned =
+            FixedDTW::with_alignment(&distance).occurrences(&query, &reference, threshold);
+        assert_eq!(aligned.len(), found.len());
+        for (m, a) in found.iter().zip(&aligned) {
+            assert_eq!((a.start, a.end), (m.start, m.end));
+            assert_eq!(check_match(&distance, &query, &reference, a), m.cost);
+        }
+    });
+}
+
+/// Checks a path with free gaps, which starts in any cell allowed by the gaps. Its end is not in
+/// the path, so the cheapest of the ends next to its first cell is taken
+fn check_free_path(
+    distance: &dyn Distance,
+    chain1: &[TokenID],
+    chain2: &[TokenID],
+    free: FreeGaps,
+    (path, mini, minj): &(Vec<OP>, usize, usize),
+) -> f64 {
+    let (n, m) = (chain1.len(), chain2.len());
+    let Some(&first) = path.first() else {
+        // Nothing is aligned
+        return 0.0;
+    };
+    let &(i, j) = path.last().unwrap();
+    assert!((i, j) == (0, 0) || (free.start1 && j == 0) || (free.start2 && i == 0));
+    assert_eq!((*mini, *minj), (i, j));
+
+    // The path of the block between its first and last cells is a path of the whole block
+    let ends = [(1, 1), (1, 0), (0, 1)]
+        .into_iter()
+        .map(|(di, dj)| (first.0 + di, first.1 + dj))
+        .filter(|&(ei, ej)| ei <= n && ej <= m)
+        .filter(|&(ei, ej)| (ei, ej) == (n, m) || (free.end1 && ej == m) || (free.end2 && ei == n));
+    ends.map(|(ei, ej)| {
+        let block = path.iter().map(|&(a, b)| (a - i, b - j)).collect();
+        check_path(distance, &chain1[i..ei], &chain2[j..ej], &(block, 0, 0))
+    })
+    .reduce(f64::min)
+    .expect("the path does not start next to an end")
+}
+
+#[test]
+fn test_free_gaps() {
+    cases(0xf4ee, 200, |rng| {
+        let distance = distance(rng);
+        let (chain1, chain2) = traces(rng, 12);
+        let (n, m) = (chain1.len(), chain2.len());
+        let free = free_gaps(rng);
+
+        // The cheapest global alignment between any allowed start and end
+        let starts = (0..=n)
+            .map(|i| (i, 0))
+            .filter(|&(i, _)| i == 0 || free.start1)
+            .chain((1..=m).map(|j| (0, j)).filter(|_| free.start2));
+        let ends = (0..=m)
+            .map(|j| (n, j))
+            .filter(|&(_, j)| j == m || free.end2)
+            .chain((0..n).map(|i| (i, m)).filter(|_| free.end1))
+            .collect::<Vec<_>>();
+        let standard = StandardDTW::new(&distance);
+        let expected = starts
+            .flat_map(|start| ends.iter().map(move |&end| (start, end)))
+            .filter(|(start, end)| start.0 <= end.0 && start.1 <= end.1)
+            .map(|(start, end)| {
+                let (chain1, chain2) = (&chain1[start.0..end.0], &chain2[start.1..end.1]);
+                standard.calculate(chain1, chain2).0
+            })
+            .fold(f64::INFINITY, f64::min);
+
+        let (cost, path) = StandardDTW::new(&distance)
+            .with_free_gaps(free)
+            .calculate(&chain1, &chain2);
+        assert_eq!(cost, expected);
+        let path = path.unwrap();
+        assert_eq!(
+            check_free_path(&distance, &chain1, &chain2, free, &path),
+            expected
+        );
+
+        let (cost, unsafe_path) = UnsafeDTW::new(&distance)
+            .with_free_gaps(free)
+            .calculate(&chain1, &chain2);
+        assert_eq!((cost, unsafe_path.unwrap()), (expected, path.clone()));
+
+        let (cost, windowed_path) = WindowedDTW::new(full_window(n, m), &distance)
+            .with_free_gaps(free)
+            .calculate(&chain1, &chain2);
+        assert_eq!((cost, windowed_path.unwrap()), (expected, path));
+
+        let fixed = FixedDTW::new(&distance).with_free_gaps(free);
+        assert_eq!(fixed.calculate(&chain1, &chain2), (expected, None));
+        let fixed = fixed.with_kernel(Kernel::F32);
+        assert_eq!(fixed.calculate(&chain1, &chain2), (expected, None));
+        let wavefront = WavefrontDTW::new(&distance)
+            .with_free_gaps(free)
+            .with_chunk(2);
+        assert_eq!(wavefront.calculate(&chain1, &chain2), (expected, None));
+
+        let (cost, fixed_path) = FixedDTW::with_alignment(&distance)
+            .with_free_gaps(free)
+            .calculate(&chain1, &chain2);
+        assert_eq!(cost, expected);
+        let fixed_path = fixed_path.unwrap();
+        assert_eq!(
+            check_free_path(&distance, &chain1, &chain2, free, &fixed_path),
+            expected
+        );
+    });
+}
+
+#[test]
+fn test_threshold() {
+    cases(0xab0, 400, |rng| {
+        let distance = distance(rng);
+        let (chain1, chain2) = traces(rng, 30);
+        let free = if rng.gen_bool(0.5) {
+            FreeGaps::default()
+        } else {
+            free_gaps(rng)
+        };
+
+        let fixed = FixedDTW::new(&distance).with_free_gaps(free);
+        let (expected, _) = fixed.calculate(&chain1, &chain2);
+        assert!(fixed.lower_bound(&chain1, &chain2) <= expected);
+        assert!(fixed.lower_bound(&chain2, &chain1) <= fixed.calculate(&chain2, &chain1).0);
+
+        // Costs up to the threshold are exact, the rest are infinite
+        for threshold in [0.0, expected - 0.5, expected, expected + 1.0] {
+            let above = expected > threshold;
+            let (cost, path) = FixedDTW::new(&distance)
+                .with_free_gaps(free)
+                .with_threshold(threshold)
+                .calculate(&chain1, &chain2);
+            assert_eq!(cost, if above { f64::INFINITY } else { expected });
+            assert!(path.is_none());
+
+            let (cost, path) = FixedDTW::with_alignment(&distance)
+                .with_free_gaps(free)
+                .with_threshold(threshold)
+                .calculate(&chain1, &chain2);
+            if above {
+                assert_eq!((cost, path), (f64::INFINITY, None));
+            } else {
+                assert_eq!(cost, expected);
+                let path = path.unwrap();
+                assert_eq!(
+                    check_free_path(&distance, &chain1, &chain2, free, &path),
+                    expected
+                );
+            }
+        }
+    });
+}
+
+#[test]
+fn test_integer_scores() {
+    cases(0x5c0e, 200, |rng| {
+        // Integer costs, every score holds them exactly
+        let mut distance = STRACDistance::new(
+            [1.0, 2.0][rng.gen_range(0..2)],
+            [1.0, 3.0][rng.gen_range(0..2)],
+            0.0,
+        );
+        distance.gap_open = [0.0, 2.0][rng.gen_range(0..2)];
+        let (chain1, chain2) = traces(rng, 30);
+        let (n, m) = (chain1.len(), chain2.len());
+        let free = free_gaps(rng);
+
+        // The same cells are compared, so the paths are the same too
+        let standard = StandardDTW::new(&distance).with_free_gaps(free);
+        let expected = standard.calculate(&chain1, &chain2);
+        assert_eq!(
+            standard.with_score::<u16>().calculate(&chain1, &chain2),
+            expected
+        );
+        let standard = StandardDTW::new(&distance).with_free_gaps(free);
+        assert_eq!(
+            standard.with_score::<u32>().calculate(&chain1, &chain2),
+            expected
+        );
+        let standard = StandardDTW::new(&distance).with_free_gaps(free);
+        assert_eq!(
+            standard.with_score::<i32>().calculate(&chain1, &chain2),
+            expected
+        );
+        let standard = StandardDTW::new(&distance).with_free_gaps(free);
+        assert_eq!(
+            standard.with_score::<f32>().calculate(&chain1, &chain2),
+            expected
+        );
+
+        let unsafe_dtw = UnsafeDTW::new(&distance).with_free_gaps(free);
+        assert_eq!(
+            unsafe_dtw.with_score::<u16>().calculate(&chain1, &chain2),
+            expected
+        );
+
+        let windowed = WindowedDTW::new(full_window(n, m), &distance).with_free_gaps(free);
+        assert_eq!(
+            windowed.with_score::<u32>().calculate(&chain1, &chain2),
+            expected
+        );
+
+        let standard = StandardDTW::new(&distance);
+        let expected = FastDTW::new(&distance, 1, 2, &standard).calculate(&chain1, &chain2);
+        let small = StandardDTW::new(&distance).with_score::<u16>();
+        let fastdtw = FastDTW::new(&distance, 1, 2, &small).with_score::<u16>();
+        assert_eq!(fastdtw.calculate(&chain1, &chain2), expected);
+    });
+}
+
+#[test]
+fn test_disk_storage() {
+    let dir = tempfile::tempdir().unwrap();
+    let disk = Storage::Disk(Some(dir.path().to_path_buf()));
+
+    cases(0xd15c, 50, |rng| {
+        let distance = distance(rng);
+        let (chain1, chain2) = traces(rng, 30);
+        let (n, m) = (chain1.len(), chain2.len());
+        let free = free_gaps(rng);
+
+        // The mapped matrices hold the same cells, so the paths are the same too
+        let standard = StandardDTW::new(&distance).with_free_gaps(free);
+        let expected = standard.calculate(&chain1, &chain2);
+        let standard = standard.with_storage(disk.clone());
+        assert_eq!(standard.calculate(&chain1, &chain2), expected);
+        let unsafe_dtw = UnsafeDTW::new(&distance)
+            .with_free_gaps(free)
+            .with_storage(disk.clone());
+        assert_eq!(unsafe_dtw.calculate(&chain1, &chain2), expected);
+
+        let windowed = WindowedDTW::new(full_window(n, m), &distance)
+            .with_free_gaps(free)
+            .with_storage(disk.clone());
+        assert_eq!(windowed.calculate(&chain1, &chain2), expected);
+
+        let standard = StandardDTW::new(&distance);
+        let expected = FastDTW::new(&distance, 1, 2, &standard).calculate(&chain1, &chain2);
+        let fastdtw = FastDTW::new(&distance, 1, 2, &standard).with_storage(disk.clone());
+        assert_eq!(fastdtw.calculate(&chain1, &chain2), expected);
+    });
+
+    // The temporary files are gone with their matrices
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+}
+
+#[test]
+fn test_traceback() {
+    cases(0x2b17, 300, |rng| {
+        let distance = distance(rng);
+        let (chain1, chain2) = traces(rng, 30);
+        let (n, m) = (chain1.len(), chain2.len());
+        let free = free_gaps(rng);
+
+        // The moves give a path of the cost, the ties are broken in the same way
+        let standard = StandardDTW::new(&distance).with_free_gaps(free);
+        let (expected, _) = standard.calculate(&chain1, &chain2);
+        let (cost, path) = standard.with_traceback().calculate(&chain1, &chain2);
+        assert_eq!(cost, expected);
+        let path = path.unwrap();
+        assert_eq!(
+            check_free_path(&distance, &chain1, &chain2, free, &path),
+            expected
+        );
+
+        let unsafe_dtw = UnsafeDTW::new(&distance)
+            .with_free_gaps(free)
+            .with_traceback();
+        assert_eq!(
+            unsafe_dtw.calculate(&chain1, &chain2),
+            (expected, Some(path.clone()))
+        );
+
+        let windowed = WindowedDTW::new(full_window(n, m), &distance)
+            .with_free_gaps(free)
+            .with_traceback();
+        assert_eq!(windowed.calculate(&chain1, &chain2), (expected, Some(path)));
+
+        // The rows are reused, the cells out of the band must not leak into the next rows
+        let band = DynamicWindow::sakoe_chiba(n, m, rng.gen_range(0..4));
+        let (expected, _) = WindowedDTW::new(band.clone(), &distance).calculate(&chain1, &chain2);
+        let windowed = WindowedDTW::new(band, &distance).with_traceback();
+        let (cost, path) = windowed.calculate(&chain1, &chain2);
+        assert_eq!(cost, expected);
+        if cost.is_finite() {
+            assert_eq!(
+                check_path(&distance, &chain1, &chain2, &path.unwrap()),
+                cost
+            );
+        }
+
+        let standard = StandardDTW::new(&distance).with_traceback();
+        let fastdtw = FastDTW::new(&distance, 1, 2, &standard).with_traceback();
+        let (cost, path) = fastdtw.calculate(&chain1, &chain2);
+        assert!(cost >= standard.calculate(&chain1, &chain2).0);
+        assert_eq!(
+            check_path(&distance, &chain1, &chain2, &path.unwrap()),
+            cost
+        );
+
+        // Ties can start the match somewhere else, the path is still one of the cost
+        let found = standard.search(&chain1, &chain2);
+        let Match { end, cost, .. } = StandardDTW::new(&distance).search(&chain1, &chain2);
+        assert_eq!((found.end, found.cost), (end, cost));
+        let path = found.path.unwrap();
+        let subsequence = FreeGaps::subsequence();
+        assert_eq!(
+            check_free_path(&distance, &chain1, &chain2, subsequence, &path),
+            cost
+        );
+    });
+}
+
+#[test]
+fn test_tie_break() {
+    cases(0x71e, 300, |rng| {
+        let distance = distance(rng);
+        let (chain1, chain2) = traces(rng, 30);
+        let (n, m) = (chain1.len(), chain2.len());
+        let (expected, _) = StandardDTW::new(&distance).calculate(&chain1, &chain2);
+
+        for tie in [TieBreak::Match, TieBreak::GapIn1, TieBreak::GapIn2] {
+            // The matrix and the moves break the ties in the same way
+            let standard = StandardDTW::new(&distance).with_tie_break(tie);
+            let (cost, path) = standard.calculate(&chain1, &chain2);
+            let path = path.unwrap();
+            assert_eq!(cost, expected);
+            assert_eq!(
+                path_cost(&distance, &chain1, &chain2, (n, m), &path.0),
+                Some(cost)
+            );
+            assert_eq!(check_path(&distance, &chain1, &chain2, &path), cost);
+
+            let traceback = StandardDTW::new(&distance)
+                .with_tie_break(tie)
+                .with_traceback();
+            assert_eq!(
+                traceback.calculate(&chain1, &chain2),
+                (cost, Some(path.clone()))
+            );
+            let unsafe_dtw = UnsafeDTW::new(&distance).with_tie_break(tie);
+            assert_eq!(
+                unsafe_dtw.calculate(&chain1, &chain2),
+                (cost, Some(path.clone()))
+            );
+            let windowed = WindowedDTW::new(full_window(n, m), &distance).with_tie_break(tie);
+            assert_eq!(
+                windowed.calculate(&chain1, &chain2),
+                (cost, Some(path.clone()))
+            );
+
+            // The first move of the path is the preferred one whenever it gives the cost
+            let prefers = match tie {
+                TieBreak::Match => (1, 1),
+                TieBreak::GapIn1 => (0, 1),
+                TieBreak::GapIn2 => (1, 0),
+            };
+            if let Some(&(i, j)) = path.0.first() {
+                let step = (n - i, m - j);
+                if step != prefers && n >= prefers.0 && m >= prefers.1 {
+                    let start = (n - prefers.0, m - prefers.1);
+                    let mut preferred = vec![start];
+                    let (_, rest) = StandardDTW::new(&distance)
+                        .with_tie_break(tie)
+                        .calculate(&chain1[..start.0], &chain2[..start.1]);
+                    preferred.extend(rest.unwrap().0);
+                    let preferred = path_cost(&distance, &chain1, &chain2, (n, m), &preferred);
+                    assert!(preferred.unwrap() > cost);
+                }
+            }
+
+            // The divide and conquer crosses the middle rows where the full matrix does, also
+            // with the traces swapped when the first one is longer
+            let fixed = FixedDTW::with_alignment(&distance).with_tie_break(tie);
+            assert_eq!(fixed.calculate(&chain1, &chain2), (cost, Some(path)));
+
+            let fastdtw = FastDTW::new(&distance, 1, 2, &standard).with_tie_break(tie);
+            let (cost, path) = fastdtw.calculate(&chain1, &chain2);
+            assert_eq!(
+                path_cost(&distance, &chain1, &chain2, (n, m), &path.unwrap().0),
+                Some(cost)
+            );
+        }
+    });
+}
@@ -0,0 +1,807 @@
+//! Distances between tokens
+//!
+//!
+
+use crate::dtw::{Distance, STRACDistance, TokenID};
+use crate::error::{DtwError, Result};
+use crate::parsing::ToMemoryParser;
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::io::BufRead;
+use std::path::Path;
+use std::sync::RwLock;
+
+/// Scores the gaps of a distance with its `base` distance
+macro_rules! base_gaps {
+    () => {
+        #[inline]
+        fn gap_cost(&self) -> f64 {
+            self.base.gap_cost()
+        }
+
+        #[inline]
+        fn gap_open(&self) -> f64 {
+            self.base.gap_open()
+        }
+
+        #[inline]
+        fn gap_extend(&self) -> f64 {
+            self.base.gap_extend()
+        }
+
+        #[inline]
+        fn insertion_cost(&self) -> f64 {
+            self.base.insertion_cost()
+        }
+
+        #[inline]
+        fn deletion_cost(&self) -> f64 {
+            self.base.deletion_cost()
+        }
+    };
+}
+
+/// Distance with a cost for specific pairs of tokens, e.g. `call malloc` and `call calloc` can be
+/// cheap to align while `call free` and `ret` are expensive. Pairs that are not in the matrix, and
+/// the gaps, are scored by the base distance
+pub struct MatrixDistance {
+    pub base: STRACDistance,
+    costs: HashMap<(TokenID, TokenID), f64>,
+}
+
+impl MatrixDistance {
+    pub fn new(base: STRACDistance) -> Self {
+        MatrixDistance {
+            base,
+            costs: HashMap::new(),
+        }
+    }
+
+    /// Sets the cost of aligning `a`, of the first trace, with `b`, of the second one
+    pub fn set_cost(&mut self, a: TokenID, b: TokenID, cost: f64) {
+        self.costs.insert((a, b), cost);
+    }
+
+    /// Swaps the tokens of every pair, to align the traces in the other order
+    pub fn transpose(&mut self) {
+        self.costs = self.costs.drain().map(|((a, b), c)| ((b, a), c)).collect();
+    }
+
+    /// Number of pairs with their own cost
+    pub fn len(&self) -> usize {
+        self.costs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.costs.is_empty()
+    }
+
+    /// Loads a cost matrix file, see `from_csv`. Only the CSV format is supported
+    pub fn load(path: &Path, encoder: &ToMemoryParser, base: STRACDistance) -> Result<Self> {
+        let f = std::fs::File::open(path)?;
+        Self::from_csv(std::io::BufReader::new(f), encoder, base)
+    }
+
+    /// Reads a cost matrix, one `token1,token2,cost` line per pair. Tokens with commas, quotes or
+    /// surrounding spaces are written between double quotes, with the quotes inside doubled.
+    /// Empty lines and lines starting with `#` are ignored.
+    ///
+    /// `token1` is the token of the first trace and `token2` the one of the second trace. A line
+    /// sets the cost in both directions, unless the reversed pair has its own line.
+    /// The tokens are resolved with the vocabulary of the encoder, pairs with tokens that are not
+    /// in the traces are never used and are skipped
+    pub fn from_csv<R: BufRead>(
+        reader: R,
+        encoder: &ToMemoryParser,
+        base: STRACDistance,
+    ) -> Result<Self> {
+        let mut pairs = vec![];
+        let mut lines = HashMap::new();
+        let mut skipped = 0;
+
+        for (line, row) in reader.lines().enumerate() {
+            let row = row?;
+            let line = line + 1;
+            let invalid = |reason: String| DtwError::CostMatrix { line, reason };
+            if row.trim().is_empty() || row.trim_start().starts_with('#') {
+                continue;
+            }
+
+            let fields = split_csv(&row).map_err(invalid)?;
+            let [a, b, cost] = &fields[..] else {
+                return Err(invalid(format!(
+                    "expected 3 fields but found {}",
+                    fields.len()
+                )));
+            };
+            let cost = cost
+                .parse::<f64>()
+                .map_err(|_| invalid(format!("invalid cost {:?}", cost)))?;
+            if !cost.is_finite() || cost < 0.0 {
+                return Err(invalid(format!(
+                    "the cost must be finite and not negative, found {}",
+                    cost
+                )));
+            }
+
+            let (Some(a), Some(b)) = (encoder.lookup(a), encoder.lookup(b)) else {
+                skipped += 1;
+                continue;
+            };
+            if let Some(previous) = lines.insert((a, b), line) {
+                return Err(invalid(format!("the pair is already in line {}", previous)));
+            }
+            pairs.push((a, b, cost));
+        }
+        if skipped > 0 {
+            log::debug!("Skipped {} pairs with tokens not in the traces", skipped);
+        }
+
+        let mut distance = MatrixDistance::new(base);
+        for &(a, b, cost) in &pairs {
+            distance.set_cost(a, b, cost);
+            if !lines.contains_key(&(b, a)) {
+                distance.set_cost(b, a, cost);
+            }
+        }
+        Ok(distance)
+    }
+}
+
+/// Splits a CSV line in its fields. Unquoted fields are trimmed
+fn split_csv(row: &str) -> std::result::Result<Vec<String>, String> {
+    let mut fields = vec![];
+    let mut chars = row.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+
+        let mut field = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            loop {
+                match chars.next() {
+                    Some('"') if chars.next_if_eq(&'"').is_some() => field.push('"'),
+                    Some('"') => break,
+                    Some(c) => field.push(c),
+                    None => {
+                        return Err(format!("unterminated quote in field {}", fields.len() + 1))
+                    }
+                }
+            }
+            while chars.next_if(|c| c.is_whitespace()).is_some() {}
+            if !matches!(chars.peek(), None | Some(',')) {
+                return Err(format!(
+                    "unexpected text after the quotes of field {}",
+                    fields.len() + 1
+                ));
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| *c != ',') {
+                field.push(c);
+            }
+            field.truncate(field.trim_end().len());
+        }
+        fields.push(field);
+
+        if chars.next().is_none() {
+            return Ok(fields);
+        }
+    }
+}
+
+impl Distance for MatrixDistance {
+    #[inline]
+    fn distance(&self, a: TokenID, b: TokenID) -> f64 {
+        match self.costs.get(&(a, b)) {
+            Some(cost) => *cost,
+            None => self.base.distance(a, b),
+        }
+    }
+
+    base_gaps!();
+}
+
+/// How different the text of two tokens is, from 0 for equal tokens to 1
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Similarity {
+    /// Edit distance between the tokens, over the length of the longest one
+    Levenshtein,
+    /// Jaccard distance between the sets of fields of the tokens. Fields are separated by spaces
+    /// and commas, so `mov rax, rbx` and `mov rax, rcx` share two of their four fields
+    Jaccard,
+    /// Field by field difference of tokens with the same number of fields. Numbers, decimal or
+    /// hexadecimal with `0x`, differ by their relative difference, so near addresses are near
+    /// matches. Other fields differ by 1 if they are not equal
+    Numeric,
+}
+
+impl Similarity {
+    /// Dissimilarity of two tokens, between 0 and 1
+    pub fn dissimilarity(&self, a: &str, b: &str) -> f64 {
+        if a == b {
+            return 0.0;
+        }
+        match self {
+            Similarity::Levenshtein => {
+                let a = a.chars().collect::<Vec<_>>();
+                let b = b.chars().collect::<Vec<_>>();
+                levenshtein(&a, &b) as f64 / a.len().max(b.len()) as f64
+            }
+            Similarity::Jaccard => {
+                let a = fields(a).collect::<HashSet<_>>();
+                let b = fields(b).collect::<HashSet<_>>();
+                let union = a.union(&b).count();
+                if union == 0 {
+                    return 0.0;
+                }
+                1.0 - a.intersection(&b).count() as f64 / union as f64
+            }
+            Similarity::Numeric => {
+                let a = fields(a).collect::<Vec<_>>();
+                let b = fields(b).collect::<Vec<_>>();
+                if a.len() != b.len() || a.is_empty() {
+                    return 1.0;
+                }
+                let total: f64 = a
+                    .iter()
+                    .zip(&b)
+                    .map(|(a, b)| match (number(a), number(b)) {
+                        _ if a == b => 0.0,
+                        (Some(x), Some(y)) => ((x - y).abs() / x.abs().max(y.abs())).min(1.0),
+                        _ => 1.0,
+                    })
+                    .sum();
+                total / a.len() as f64
+            }
+        }
+    }
+}
+
+/// Fields of a token, separated by spaces and commas
+fn fields(token: &str) -> impl Iterator<Item = &str> {
+    token
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|field| !field.is_empty())
+}
+
+fn number(field: &str) -> Option<f64> {
+    let (negative, field) = match field.strip_prefix('-') {
+        Some(field) => (true, field),
+        None => (false, field),
+    };
+    let value = match field
+        .strip_prefix("0x")
+        .or_else(|| field.strip_prefix("0X"))
+    {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()? as f64,
+        None => field
+            .parse::<f64>()
+            .ok()
+            .filter(|value| value.is_finite())?,
+    };
+    Some(if negative { -value } else { value })
+}
+
+/// Edit distance with unit costs, one row at a time
+fn levenshtein(a: &[char], b: &[char]) -> usize {
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.iter().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let cost = diagonal + (ca != cb) as usize;
+            diagonal = row[j + 1];
+            row[j + 1] = cost.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Largest vocabulary whose costs are all calculated beforehand, the table takes 8 bytes per pair
+pub const PRECOMPUTE_LIMIT: usize = 1024;
+
+/// Shards of the cache of the larger vocabularies. The threads of `WavefrontDTW` read it at the
+/// same time, so every shard has its own lock
+const CACHE_SHARDS: usize = 64;
+
+/// Distance that compares the text of the tokens, so tokens that only differ by a register or an
+/// address are near matches. Two different tokens cost between the match and the mismatch costs
+/// of the base distance, in proportion to their dissimilarity. The gaps are scored by the base
+/// distance.
+///
+/// The costs of small vocabularies are calculated when the distance is created, the others are
+/// calculated the first time a pair is aligned and cached, in shards that the threads can read at
+/// the same time
+pub struct ContentDistance {
+    pub base: STRACDistance,
+    similarity: Similarity,
+    tokens: Vec<String>,
+    table: Option<Vec<f64>>,
+    cache: Vec<RwLock<HashMap<(TokenID, TokenID), f64>>>,
+}
+
+impl ContentDistance {
+    /// Creates the distance for the tokens known by the encoder
+    pub fn new(base: STRACDistance, similarity: Similarity, encoder: &ToMemoryParser) -> Self {
+        let tokens = encoder
+            .vocabulary()
+            .into_iter()
+            .map(String::from)
+            .collect::<Vec<_>>();
+
+        let mut distance = ContentDistance {
+            base,
+            similarity,
+            tokens,
+            table: None,
+            cache: (0..CACHE_SHARDS).map(|_| RwLock::default()).collect(),
+        };
+        let size = distance.tokens.len();
+        if size <= PRECOMPUTE_LIMIT {
+            let mut table = vec![0.0; size * size];
+            for a in 0..size {
+                for b in a..size {
+                    let cost = distance.cost(a as TokenID, b as TokenID);
+                    table[a * size + b] = cost;
+                    table[b * size + a] = cost;
+                }
+            }
+            distance.table = Some(table);
+        }
+        distance
+    }
+
+    pub fn similarity(&self) -> Similarity {
+        self.similarity
+    }
+
+    fn cost(&self, a: TokenID, b: TokenID) -> f64 {
+        let (low, high) = (self.base.match_cost, self.base.mismatch_cost);
+        if a == b {
+            return low;
+        }
+        match (self.tokens.get(a), self.tokens.get(b)) {
+            (Some(a), Some(b)) => low + (high - low) * self.similarity.dissimilarity(a, b),
+            // Tokens out of the vocabulary can not be compared
+            _ => high,
+        }
+    }
+}
+
+impl Distance for ContentDistance {
+    #[inline]
+    fn distance(&self, a: TokenID, b: TokenID) -> f64 {
+        let size = self.tokens.len();
+        if let Some(table) = &self.table {
+            if a < size && b < size {
+                return table[a * size + b];
+            }
+        }
+        if a == b {
+            return self.base.match_cost;
+        }
+
+        // Every similarity is symmetric
+        let key = (a.min(b), a.max(b));
+        let shard = &self.cache[key.0.wrapping_mul(31).wrapping_add(key.1) % CACHE_SHARDS];
+        if let Some(cost) = shard.read().unwrap().get(&key) {
+            return *cost;
+        }
+        let cost = self.cost(a, b);
+        shard.write().unwrap().insert(key, cost);
+        cost
+    }
+
+    base_gaps!();
+}
+
+/// Cost of aligning two different tokens that match the patterns of the rule, in any order
+#[derive(Clone, Debug)]
+pub struct Rule {
+    pub first: Regex,
+    pub second: Regex,
+    pub cost: f64,
+}
+
+impl Rule {
+    fn matches(
+        &self,
+        a: &(Vec<bool>, Vec<bool>),
+        b: &(Vec<bool>, Vec<bool>),
+        index: usize,
+    ) -> bool {
+        (a.0[index] && b.1[index]) || (a.1[index] && b.0[index])
+    }
+}
+
+/// Distance given by ordered rules over the text of the tokens, e.g. two calls cost 1 and a jump
+/// aligned with a call costs 2. The first rule that matches two different tokens gives their
+/// cost, the pairs that match no rule, equal tokens and the gaps are scored by the base distance.
+///
+/// The rules are compiled once for the vocabulary of the encoder. Tokens that match the same
+/// patterns are in the same class, and the cost of every pair of classes is calculated
+/// beforehand
+pub struct RuleDistance {
+    pub base: STRACDistance,
+    /// Class of every token id, ids out of the vocabulary are in the class 0
+    classes: Vec<usize>,
+    class_count: usize,
+    /// Cost of every pair of classes, if a rule matches them
+    costs: Vec<Option<f64>>,
+}
+
+impl RuleDistance {
+    pub fn new(base: STRACDistance, rules: &[Rule], encoder: &ToMemoryParser) -> Self {
+        // The patterns of every rule matched by a token, the class 0 matches none
+        let mut patterns = vec![(vec![false; rules.len()], vec![false; rules.len()])];
+        let mut ids = HashMap::new();
+        ids.insert(patterns[0].clone(), 0);
+
+        let classes = encoder
+            .vocabulary()
+            .into_iter()
+            .map(|token| {
+                let matched = (
+                    rules.iter().map(|r| r.first.is_match(token)).collect(),
+                    rules.iter().map(|r| r.second.is_match(token)).collect(),
+                );
+                *ids.entry(matched).or_insert_with_key(|matched| {
+                    patterns.push(matched.clone());
+                    patterns.len() - 1
+                })
+            })
+            .collect();
+
+        let class_count = patterns.len();
+        let mut costs = vec![None; class_count * class_count];
+        for (a, first) in patterns.iter().enumerate() {
+            for (b, second) in patterns.iter().enumerate() {
+                costs[a * class_count + b] = rules
+                    .iter()
+                    .enumerate()
+                    .find(|(index, rule)| rule.matches(first, second, *index))
+                    .map(|(_, rule)| rule.cost);
+            }
+        }
+
+        RuleDistance {
+            base,
+            classes,
+            class_count,
+            costs,
+        }
+    }
+
+    /// Number of classes of the vocabulary, including the class of the tokens out of it
+    pub fn class_count(&self) -> usize {
+        self.class_count
+    }
+
+    /// Loads a rules file, see `parse_rules`
+    pub fn load(path: &Path, encoder: &ToMemoryParser, base: STRACDistance) -> Result<Self> {
+        let f = std::fs::File::open(path)?;
+        let rules = Self::parse_rules(std::io::BufReader::new(f))?;
+        Ok(Self::new(base, &rules, encoder))
+    }
+
+    /// Reads the rules, one `pattern1,pattern2,cost` line per rule in the same format as the cost
+    /// matrices, see `MatrixDistance::from_csv`. Patterns are regular expressions, a rule
+    /// matches two tokens if one matches the first pattern and the other the second one.
+    /// Rules are tried in the order of the file
+    pub fn parse_rules<R: BufRead>(reader: R) -> Result<Vec<Rule>> {
+        let mut rules = vec![];
+
+        for (line, row) in reader.lines().enumerate() {
+            let row = row?;
+            let line = line + 1;
+            let invalid = |reason: String| DtwError::Rules { line, reason };
+            if row.trim().is_empty() || row.trim_start().starts_with('#') {
+                continue;
+            }
+
+            let fields = split_csv(&row).map_err(invalid)?;
+            let [first, second, cost] = &fields[..] else {
+                return Err(invalid(format!(
+                    "expected 3 fields but found {}",
+                    fields.len()
+                )));
+            };
+            let pattern = |pattern: &str| {
+                Regex::new(pattern)
+                    .map_err(|e| invalid(format!("invalid pattern {:?}: {}", pattern, e)))
+            };
+            let cost = cost
+                .parse::<f64>()
+                .map_err(|_| invalid(format!("invalid cost {:?}", cost)))?;
+            if !cost.is_finite() || cost < 0.0 {
+                return Err(invalid(format!(
+                    "the cost must be finite and not negative, found {}",
+                    cost
+                )));
+            }
+
+            rules.push(Rule {
+                first: pattern(first)?,
+                second: pattern(second)?,
+                cost,
+            });
+        }
+        Ok(rules)
+    }
+
+    #[inline]
+    fn class(&self, id: TokenID) -> usize {
+        self.classes.get(id).copied().unwrap_or(0)
+    }
+}
+
+impl Distance for RuleDistance {
+    #[inline]
+    fn distance(&self, a: TokenID, b: TokenID) -> f64 {
+        if a == b {
+            return self.base.match_cost;
+        }
+        match self.costs[self.class(a) * self.class_count + self.class(b)] {
+            Some(cost) => cost,
+            None => self.base.distance(a, b),
+        }
+    }
+
+    base_gaps!();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dtw::{FixedDTW, StandardDTW, WavefrontDTW, DTW};
+    use crate::parsing::TraceEncoder;
+
+    fn encoder(tokens: &[&str]) -> ToMemoryParser {
+        let mut encoder = ToMemoryParser::default();
+        for token in tokens {
+            encoder.token_to_id(token);
+        }
+        encoder
+    }
+
+    #[test]
+    fn test_split_csv() {
+        assert_eq!(split_csv("a,b,1").unwrap(), ["a", "b", "1"]);
+        assert_eq!(
+            split_csv(" call malloc , call calloc,0.5 ").unwrap(),
+            ["call malloc", "call calloc", "0.5"]
+        );
+        assert_eq!(
+            split_csv(r#""add 2,2", "say ""hi""" ,"#).unwrap(),
+            ["add 2,2", r#"say "hi""#, ""]
+        );
+        assert_eq!(split_csv(r#"" a ",b,1"#).unwrap(), [" a ", "b", "1"]);
+        assert!(split_csv(r#""a,b,1"#).is_err());
+        assert!(split_csv(r#""a"x,b,1"#).is_err());
+    }
+
+    #[test]
+    fn test_matrix_costs() {
+        let encoder = encoder(&["call malloc", "call calloc", "call free", "ret", "add 2,2"]);
+        let matrix = "\
+# Allocations are alike
+call malloc,call calloc,0.5
+
+call free,ret,10
+ret,call free,8
+\"add 2,2\",ret,2
+unknown,ret,0
+";
+        let distance =
+            MatrixDistance::from_csv(matrix.as_bytes(), &encoder, STRACDistance::default())
+                .unwrap();
+        let id = |token| encoder.lookup(token).unwrap();
+
+        assert_eq!(distance.len(), 6);
+        assert_eq!(distance.distance(id("call malloc"), id("call calloc")), 0.5);
+        assert_eq!(distance.distance(id("call calloc"), id("call malloc")), 0.5);
+        // The reversed pair has its own line
+        assert_eq!(distance.distance(id("call free"), id("ret")), 10.0);
+        assert_eq!(distance.distance(id("ret"), id("call free")), 8.0);
+        assert_eq!(distance.distance(id("ret"), id("add 2,2")), 2.0);
+        let mut transposed =
+            MatrixDistance::from_csv(matrix.as_bytes(), &encoder, STRACDistance::default())
+                .unwrap();
+        transposed.transpose();
+        assert_eq!(transposed.distance(id("call free"), id("ret")), 8.0);
+        assert_eq!(transposed.distance(id("ret"), id("call free")), 10.0);
+        // Pairs that are not in the matrix use the base distance
+        assert_eq!(distance.distance(id("ret"), id("ret")), 0.0);
+        assert_eq!(distance.distance(id("ret"), id("call malloc")), 3.0);
+        assert_eq!(distance.gap_cost(), 1.0);
+        assert!(encoder.lookup("unknown").is_none());
+    }
+
+    #[test]
+    fn test_invalid_matrix() {
+        let encoder = encoder(&["a", "b"]);
+        let line = |matrix: &str| match MatrixDistance::from_csv(
+            matrix.as_bytes(),
+            &encoder,
+            STRACDistance::default(),
+        ) {
+            Err(DtwError::CostMatrix { line, .. }) => line,
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("{:?} is not a valid matrix", matrix),
+        };
+
+        assert_eq!(line("a,b"), 1);
+        assert_eq!(line("a,b,1,2"), 1);
+        assert_eq!(line("# comment\na,b,cheap"), 2);
+        assert_eq!(line("a,b,-1"), 1);
+        assert_eq!(line("a,b,inf"), 1);
+        assert_eq!(line("a,b,1\nb,a,1\n\na,b,2"), 4);
+    }
+
+    #[test]
+    fn test_matrix_alignment() {
+        let mut encoder = encoder(&[]);
+        let chain1: Vec<TokenID> = ["push", "call malloc", "ret"]
+            .iter()
+            .map(|t| encoder.token_to_id(t))
+            .collect();
+        let chain2: Vec<TokenID> = ["push", "call calloc", "ret"]
+            .iter()
+            .map(|t| encoder.token_to_id(t))
+            .collect();
+
+        let base = STRACDistance::new(1.0, 3.0, 0.0);
+        let plain = StandardDTW::new(&base).calculate(&chain1, &chain2).0;
+        // A mismatch costs more than deleting and inserting the token
+        assert_eq!(plain, 2.0);
+
+        let distance = MatrixDistance::from_csv(
+            "call calloc,call malloc,0.5".as_bytes(),
+            &encoder,
+            STRACDistance::new(1.0, 3.0, 0.0),
+        )
+        .unwrap();
+        let (cost, path) = StandardDTW::new(&distance).calculate(&chain1, &chain2);
+        assert_eq!(cost, 0.5);
+        assert_eq!(path.unwrap().0, [(2, 2), (1, 1), (0, 0)]);
+        assert_eq!(FixedDTW::new(&distance).calculate(&chain1, &chain2).0, 0.5);
+    }
+
+    #[test]
+    fn test_similarities() {
+        assert_eq!(levenshtein(&['a', 'b', 'c'], &[]), 3);
+        let chars = |s: &str| s.chars().collect::<Vec<_>>();
+        assert_eq!(levenshtein(&chars("kitten"), &chars("sitting")), 3);
+        assert_eq!(levenshtein(&chars("flaw"), &chars("lawn")), 2);
+
+        let levenshtein = Similarity::Levenshtein;
+        assert_eq!(levenshtein.dissimilarity("mov rax", "mov rax"), 0.0);
+        assert_eq!(levenshtein.dissimilarity("mov rax", "mov rbx"), 1.0 / 7.0);
+        assert_eq!(levenshtein.dissimilarity("", "ret"), 1.0);
+
+        let jaccard = Similarity::Jaccard;
+        assert_eq!(jaccard.dissimilarity("mov rax, rbx", "mov rax, rcx"), 0.5);
+        assert_eq!(jaccard.dissimilarity("mov rax,rbx", "mov  rax, rbx"), 0.0);
+        assert_eq!(jaccard.dissimilarity("push", "ret"), 1.0);
+
+        let numeric = Similarity::Numeric;
+        assert_eq!(numeric.dissimilarity("jmp 0x400", "jmp 0x200"), 0.25);
+        assert_eq!(numeric.dissimilarity("add 2,-2", "add 2,2"), 1.0 / 3.0);
+        assert_eq!(numeric.dissimilarity("load 1.5", "store 1.5"), 0.5);
+        assert_eq!(numeric.dissimilarity("jmp 0x400", "jmp"), 1.0);
+        assert_eq!(numeric.dissimilarity("call foo", "call bar"), 0.5);
+    }
+
+    #[test]
+    fn test_content_distance() {
+        let mut encoder = encoder(&["call 0x1000", "call 0x1010", "call foo"]);
+        let base = STRACDistance::new(1.0, 3.0, 0.0);
+
+        let distance = ContentDistance::new(base, Similarity::Numeric, &encoder);
+        assert!(distance.table.is_some());
+        assert_eq!(distance.distance(0, 0), 0.0);
+        assert_eq!(distance.distance(0, 1), 3.0 * 0.5 * (16.0 / 4112.0));
+        assert_eq!(distance.distance(1, 2), 1.5);
+        assert_eq!(distance.distance(2, 1), 1.5);
+        // Ids out of the vocabulary
+        assert_eq!(distance.distance(0, 100), 3.0);
+        assert_eq!(distance.distance(100, 100), 0.0);
+
+        // Large vocabularies are cached instead
+        for i in 0..PRECOMPUTE_LIMIT {
+            encoder.token_to_id(&format!("tok{}", i));
+        }
+        let large = ContentDistance::new(
+            STRACDistance::new(1.0, 3.0, 0.0),
+            Similarity::Numeric,
+            &encoder,
+        );
+        assert!(large.table.is_none());
+        for _ in 0..2 {
+            assert_eq!(large.distance(0, 1), distance.distance(0, 1));
+            assert_eq!(large.distance(2, 1), 1.5);
+        }
+        let cached = large.cache.iter().map(|shard| shard.read().unwrap().len());
+        assert_eq!(cached.sum::<usize>(), 2);
+
+        // The threads of the wavefront share the cache
+        let chain1 = (0..300).map(|i| i * 7 % 500).collect::<Vec<_>>();
+        let chain2 = (0..200).map(|i| i * 11 % 500).collect::<Vec<_>>();
+        let wavefront = WavefrontDTW::new(&large).with_chunk(8);
+        assert_eq!(
+            wavefront.calculate(&chain1, &chain2).0,
+            StandardDTW::new(&large).calculate(&chain1, &chain2).0
+        );
+    }
+
+    #[test]
+    fn test_content_alignment() {
+        let mut encoder = encoder(&[]);
+        let mut encode = |trace: &[&str]| {
+            trace
+                .iter()
+                .map(|t| encoder.token_to_id(t))
+                .collect::<Vec<_>>()
+        };
+        let chain1 = encode(&["push rbp", "mov rbp, rsp", "call 0x401000", "ret"]);
+        let chain2 = encode(&["push rbp", "mov rbp, rsp", "call 0x401008", "ret"]);
+
+        let base = STRACDistance::new(1.0, 3.0, 0.0);
+        assert_eq!(StandardDTW::new(&base).calculate(&chain1, &chain2).0, 2.0);
+
+        let distance = ContentDistance::new(base, Similarity::Numeric, &encoder);
+        let (cost, path) = StandardDTW::new(&distance).calculate(&chain1, &chain2);
+        assert!(cost > 0.0 && cost < 0.001);
+        assert_eq!(path.unwrap().0, [(3, 3), (2, 2), (1, 1), (0, 0)]);
+    }
+
+    #[test]
+    fn test_rules() {
+        let rules = "\
+# Calls are alike
+^call ,^call ,1
+^jmp,^call ,2
+\"^(add|sub) \",\"^(add|sub) \",0.5
+.,.,4
+";
+        let rules = RuleDistance::parse_rules(rules.as_bytes()).unwrap();
+        assert_eq!(rules.len(), 4);
+
+        let encoder = encoder(&[
+            "call foo", "call bar", "jmp 0x10", "add 1,2", "sub 1,2", "ret",
+        ]);
+        let distance = RuleDistance::new(STRACDistance::default(), &rules[..3], &encoder);
+        // The calls, the jump, add and sub, and the empty class of ret
+        assert_eq!(distance.class_count(), 4);
+        assert_eq!(distance.distance(0, 1), 1.0);
+        assert_eq!(distance.distance(2, 1), 2.0);
+        assert_eq!(distance.distance(0, 2), 2.0);
+        assert_eq!(distance.distance(3, 4), 0.5);
+        // No rule matches
+        assert_eq!(distance.distance(5, 0), 3.0);
+        assert_eq!(distance.distance(2, 3), 3.0);
+        assert_eq!(distance.distance(100, 0), 3.0);
+        // Equal tokens are a match
+        assert_eq!(distance.distance(0, 0), 0.0);
+
+        // The first rule wins
+        let distance = RuleDistance::new(STRACDistance::default(), &rules, &encoder);
+        assert_eq!(distance.distance(0, 1), 1.0);
+        assert_eq!(distance.distance(5, 0), 4.0);
+        assert_eq!(distance.distance(1, 1), 0.0);
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        let line = |rules: &str| match RuleDistance::parse_rules(rules.as_bytes()) {
+            Err(DtwError::Rules { line, .. }) => line,
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("{:?} are not valid rules", rules),
+        };
+
+        assert_eq!(line("^call,^call"), 1);
+        assert_eq!(line("\n^call(,^call,1"), 2);
+        assert_eq!(line("^call,^call,-1"), 1);
+        assert_eq!(line("^call,^call,x"), 1);
+    }
+}
@@ -2,6 +2,12 @@
 //!
 //!
 
+use crate::error::Result;
+use crate::matrix::{Matrix, Move, Rows, Storage, Traceback};
+use crate::score::Score;
+use crate::simd::Kernel;
+use rayon::prelude::*;
+
 /// Type for the trace token
 pub type TokenID = usize;
 /// Operation type in the warp path
@@ -12,6 +18,65 @@ pub type DTWResult = (f64, Option<(Vec<OP>, usize, usize)>);
 pub trait Distance {
     fn distance(&self, a: TokenID, b: TokenID) -> f64;
     fn gap_cost(&self) -> f64;
+
+    /// Paid once for every run of consecutive gaps in the same trace, on top of the cost of each
+    /// gap. A run of `k` insertions costs `gap_open + k * insertion_cost`. Defaults to 0, linear
+    /// gaps
+    fn gap_open(&self) -> f64 {
+        0.0
+    }
+
+    /// Paid for every gap of a run. Defaults to `gap_cost`
+    fn gap_extend(&self) -> f64 {
+        self.gap_cost()
+    }
+
+    /// Paid for every token that is only in the second trace. Defaults to `gap_extend`
+    fn insertion_cost(&self) -> f64 {
+        self.gap_extend()
+    }
+
+    /// Paid for every token that is only in the first trace. Defaults to `gap_extend`
+    fn deletion_cost(&self) -> f64 {
+        self.gap_extend()
+    }
+}
+
+/// Cost of the best path to a cell that ends with a gap, Gotoh's recurrence.
+/// `total` is the best cost of the previous cell and `run` the best one of the previous cell
+/// ending with a gap of the same kind, which does not have to be opened again
+#[inline]
+fn gap<S: Score>(total: S, run: S, open: S, extend: S) -> S {
+    total.add(open).min(run).add(extend)
+}
+
+/// Matrix of `rows` by `columns` cells in `storage`, in memory when the file can not be mapped
+fn allocate<S: Score>(rows: usize, columns: usize, value: S, storage: &Storage) -> Matrix<S> {
+    Matrix::with_storage(rows, columns, value, storage).unwrap_or_else(|e| {
+        log::warn!(
+            "The matrix could not be mapped, keeping it in memory: {}",
+            e
+        );
+        Matrix::new(rows, columns, value)
+    })
+}
+
+/// Moves of a matrix of `rows` by `columns` cells in `storage`, see `allocate`
+fn allocate_moves(rows: usize, columns: usize, affine: bool, storage: &Storage) -> Traceback {
+    Traceback::with_storage(rows, columns, affine, storage).unwrap_or_else(|e| {
+        log::warn!(
+            "The moves could not be mapped, keeping them in memory: {}",
+            e
+        );
+        Traceback::with_storage(rows, columns, affine, &Storage::Memory)
+            .expect("memory storage does not fail")
+    })
+}
+
+/// The run of gaps goes on in `gap`, instead of starting after `total`
+#[inline]
+fn runs<S: Score>(total: S, run: S, open: S) -> bool {
+    run < total.add(open)
 }
 
 /// A trait for accessing the data to be compared
@@ -23,111 +88,325 @@ pub trait Accesor {
 
     fn size(&self) -> usize;
 
-    fn get_half(&self) -> Box<dyn Accesor>;
+    /// Creates a new accesor with every other element, used by the coarse levels of FastDTW
+    fn get_half(&self) -> Result<Box<dyn Accesor>>;
 }
 
 pub trait DTW {
-    fn calculate(&self, chain1: Box<dyn Accesor>, chain2: Box<dyn Accesor>) -> DTWResult;
+    /// Aligns the two traces. The traces are borrowed, so they can be reused across comparisons.
+    /// Concrete accesors, e.g. `Vec<TokenID>`, let the compiler inline `get` in the inner loops,
+    /// `dyn Accesor` can be used when the kind of trace is only known at runtime
+    fn calculate<A, B>(&self, chain1: &A, chain2: &B) -> DTWResult
+    where
+        A: Accesor + ?Sized,
+        B: Accesor + ?Sized;
 
     fn can_provide_alignment(&self) -> bool {
         false
     }
+}
 
-    fn get_warp_path(
-        &self,
-        map: &[Vec<f64>],
-        window: Option<DynamicWindow>,
-    ) -> (Vec<OP>, usize, usize) {
-        // We always start in the end of the alignment
-        let mut i = map.len() - 1;
-        let mut j = map[0].len() - 1;
-        let mut r = vec![];
+/// Costs of the moves into a cell of the matrix, with linear gaps
+struct Steps<S, F: Fn(usize, usize) -> S> {
+    /// Cost of the tokens of the cell, `(i, j)` aligns `chain1[i - 1]` with `chain2[j - 1]`
+    distance: F,
+    delete: S,
+    insert: S,
+}
 
-        let mut mini = map.len() - 1;
-        let mut minj = map[0].len() - 1;
+/// Costs of the moves with `distance`, converted to scores as the engines do
+fn steps<'a, S, A, B>(
+    distance: &'a dyn Distance,
+    chain1: &'a A,
+    chain2: &'a B,
+) -> Steps<S, impl Fn(usize, usize) -> S + 'a>
+where
+    S: Score,
+    A: Accesor + ?Sized,
+    B: Accesor + ?Sized,
+{
+    // The engines only trace back matrices whose costs were all scores
+    let score = |cost: f64| S::from_cost(cost).unwrap_or(S::INFINITY);
+    Steps {
+        distance: move |i: usize, j: usize| {
+            score(distance.distance(chain1.get(i - 1), chain2.get(j - 1)))
+        },
+        delete: score(distance.deletion_cost()),
+        insert: score(distance.insertion_cost()),
+    }
+}
 
-        while i > 0 || j > 0 {
-            let mut diagcost = std::f64::INFINITY;
-            let mut leftcost = std::f64::INFINITY;
-            let mut rightcost = std::f64::INFINITY;
-
-            if i > 0 && j > 0 {
-                match &window {
-                    Some(window) => {
-                        if window.is_in_range(i - 1, j - 1) {
-                            diagcost = map[i - 1][j - 1];
-                        }
-                    }
-                    None => {
-                        diagcost = map[i - 1][j - 1];
-                    }
-                }
+/// Warping path from `(i, j)`, excluded, back to `(0, 0)`. Each step is a move that gives the
+/// cost of its cell with `steps`, the one that `tie` prefers when several of them do, so the
+/// cost of the path is the one of `(i, j)`, see `path_cost`. Only for linear gaps, the runs of
+/// affine gaps need the moves of `trace_moves`.
+/// With free gaps at the start the path ends as soon as it reaches the first row or column
+fn trace_back<S: Score, F: Fn(usize, usize) -> S>(
+    map: &Matrix<S>,
+    window: Option<&DynamicWindow>,
+    (mut i, mut j): OP,
+    free: FreeGaps,
+    tie: TieBreak,
+    steps: Steps<S, F>,
+) -> (Vec<OP>, usize, usize) {
+    let mut r = vec![];
+    let in_window = |i: usize, j: usize| window.is_none_or(|window| window.is_in_range(i, j));
+
+    while !free.is_start((i, j)) {
+        // The same sums as the forward pass, so the costs are exactly the same
+        let cost = map[i][j];
+        let gives = |step: &Move| match step {
+            Move::Diagonal => {
+                i > 0
+                    && j > 0
+                    && in_window(i - 1, j - 1)
+                    && (steps.distance)(i, j).add(map[i - 1][j - 1]) == cost
             }
-
-            if i > 0 {
-                match &window {
-                    Some(window) => {
-                        if window.is_in_range(i - 1, j) {
-                            leftcost = map[i - 1][j];
-                        }
-                    }
-                    None => {
-                        leftcost = map[i - 1][j];
-                    }
-                }
+            Move::Up => {
+                i > 0
+                    && in_window(i - 1, j)
+                    && gap(map[i - 1][j], S::INFINITY, S::ZERO, steps.delete) == cost
             }
-
-            if j > 0 {
-                match &window {
-                    Some(window) => {
-                        if window.is_in_range(i, j - 1) {
-                            rightcost = map[i][j - 1];
-                        }
-                    }
-                    None => {
-                        rightcost = map[i][j - 1];
-                    }
-                }
+            Move::Left => {
+                j > 0
+                    && in_window(i, j - 1)
+                    && gap(map[i][j - 1], S::INFINITY, S::ZERO, steps.insert) == cost
             }
+            Move::Start => false,
+        };
 
-            if i > 0 && j > 0 && diagcost <= leftcost && diagcost <= rightcost {
-                // The diagonal is better
+        match tie.order().into_iter().find(gives) {
+            Some(Move::Diagonal) => {
                 i -= 1;
                 j -= 1;
-            } else if i > 0 && leftcost <= diagcost && leftcost <= rightcost {
+            }
+            Some(Move::Up) => i -= 1,
+            Some(Move::Left) => j -= 1,
+            // No alignment reaches the cell
+            _ => break,
+        }
+        r.push((i, j));
+    }
+
+    (r, i, j)
+}
+
+/// Warping path from `(i, j)`, excluded, back to the start following the recorded moves, see
+/// `trace_back`. The ties were broken when the moves were recorded
+fn trace_moves(moves: &Traceback, (mut i, mut j): OP, free: FreeGaps) -> (Vec<OP>, usize, usize) {
+    let mut r = vec![];
+    // Inside of a run of gaps the cells are left with the move of the run, not their own one
+    let mut run = None;
+
+    while !free.is_start((i, j)) {
+        let step = run.unwrap_or_else(|| moves.get((i, j)));
+        run = Some(step).filter(|step| moves.runs((i, j), *step));
+        match step {
+            Move::Diagonal => {
                 i -= 1;
-            } else if j > 0 && rightcost <= diagcost && rightcost <= leftcost {
-                j -= 1;
-            } else if j > 0 && i <= j {
                 j -= 1;
-            } else if i > 0 {
-                i -= 1;
-            } else {
-                break;
             }
+            Move::Up => i -= 1,
+            Move::Left => j -= 1,
+            // No alignment reaches the cell, e.g. outside of a window
+            Move::Start => break,
+        }
+        r.push((i, j));
+    }
 
-            if i < mini {
-                mini = i;
-            }
+    (r, i, j)
+}
+
+/// Gaps at the ends of the traces that are not paid, e.g. with `start2` the tokens of the second
+/// trace before the alignment are ignored. All of them are paid by default.
+/// At most the tokens of one of the traces are skipped at each end, the alignment still starts in
+/// the first row or column of the matrix and ends in the last row or column
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FreeGaps {
+    /// Tokens of the first trace before the alignment
+    pub start1: bool,
+    /// Tokens of the first trace after the alignment
+    pub end1: bool,
+    /// Tokens of the second trace before the alignment
+    pub start2: bool,
+    /// Tokens of the second trace after the alignment
+    pub end2: bool,
+}
 
-            if j < minj {
-                minj = j;
+impl FreeGaps {
+    /// The first trace is searched in the second one, the second trace is free on both sides
+    pub fn subsequence() -> Self {
+        FreeGaps {
+            start2: true,
+            end2: true,
+            ..Default::default()
+        }
+    }
+
+    /// The free gaps of the traces in the other order
+    pub fn transposed(self) -> Self {
+        FreeGaps {
+            start1: self.start2,
+            end1: self.end2,
+            start2: self.start1,
+            end2: self.end1,
+        }
+    }
+
+    /// The cell can be the first one of an alignment
+    #[inline]
+    fn is_start(&self, (i, j): OP) -> bool {
+        (i == 0 || (self.start1 && j == 0)) && (j == 0 || (self.start2 && i == 0))
+    }
+
+    /// Keeps the best last cell of an alignment in row `i`, of the `n + 1` rows of the matrix.
+    /// It has to start with the last cell of the matrix and an infinite cost
+    fn keep_end<S: Score>(&self, best: &mut (S, OP), i: usize, n: usize, row: &[S]) {
+        let m = row.len() - 1;
+        if i == n && self.end2 {
+            for (j, cost) in row.iter().enumerate() {
+                keep_best(best, *cost, (i, j));
             }
-            // Push the operation
-            r.push((i, j));
+        } else if i == n || self.end1 {
+            keep_best(best, row[m], (i, m));
         }
+    }
+}
 
-        (r, mini, minj)
+/// Keeps the cheapest cell, ties go to the cell with the fewest tokens and then with the fewest
+/// tokens of the second trace, so every engine picks the same one
+#[inline]
+fn keep_best<S: Score>(best: &mut (S, OP), cost: S, cell: OP) {
+    let key = |(i, j): OP| (i + j, j);
+    if cost < best.0 || (cost == best.0 && key(cell) < key(best.1)) {
+        *best = (cost, cell);
     }
 }
 
-pub struct StandardDTW<'a> {
+/// Move of the warping path when several of them give the cost of a cell. The path and its cost
+/// do not change with the costs of the traces, only with the policy
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TieBreak {
+    /// Aligns the tokens of both traces, then a gap in the second trace, then one in the first
+    #[default]
+    Match,
+    /// A token of the second trace against a gap in the first one, then a match
+    GapIn1,
+    /// A token of the first trace against a gap in the second one, then a match
+    GapIn2,
+}
+
+impl TieBreak {
+    /// The moves from the preferred one
+    fn order(self) -> [Move; 3] {
+        match self {
+            TieBreak::Match => [Move::Diagonal, Move::Up, Move::Left],
+            TieBreak::GapIn1 => [Move::Left, Move::Diagonal, Move::Up],
+            TieBreak::GapIn2 => [Move::Up, Move::Diagonal, Move::Left],
+        }
+    }
+
+    /// The preferred move of the ones of cost `diagonal.min(up).min(left)`
+    #[inline]
+    fn best_move<S: Score>(self, diagonal: S, up: S, left: S) -> Move {
+        preferred(self.order(), diagonal, up, left)
+    }
+}
+
+/// The first move of `order` of the ones of cost `diagonal.min(up).min(left)`
+#[inline]
+fn preferred<S: Score>(order: [Move; 3], diagonal: S, up: S, left: S) -> Move {
+    let min = diagonal.min(up).min(left);
+    let cost = |step: &Move| match step {
+        Move::Diagonal => diagonal,
+        Move::Up => up,
+        _ => left,
+    };
+    *order
+        .iter()
+        .find(|step| cost(step) == min)
+        .unwrap_or(&order[0])
+}
+
+/// The same order of the moves with the traces in the other order, where the gaps in one trace
+/// are gaps in the other one
+fn swap_gaps(order: [Move; 3]) -> [Move; 3] {
+    order.map(|step| match step {
+        Move::Up => Move::Left,
+        Move::Left => Move::Up,
+        step => step,
+    })
+}
+
+/// Cost of a warping path as the engines return it, from `end`, excluded, back to its last cell.
+/// The moves are added from the start of the path, like the forward pass does, so the cost of
+/// the paths of the engines is the one they report. Consecutive gaps of the same kind are a
+/// single run. `None` if a step is not a move of the matrix
+pub fn path_cost<A, B>(
+    distance: &dyn Distance,
+    chain1: &A,
+    chain2: &B,
+    end: OP,
+    path: &[OP],
+) -> Option<f64>
+where
+    A: Accesor + ?Sized,
+    B: Accesor + ?Sized,
+{
+    if end.0 > chain1.size() || end.1 > chain2.size() {
+        return None;
+    }
+
+    // The end is the first cell of the alignment
+    let Some(&start) = path.last() else {
+        return Some(0.0);
+    };
+
+    let mut cost = 0.0;
+    let mut prev = start;
+    let mut prev_step = None;
+    for &(i, j) in path.iter().rev().skip(1).chain([&end]) {
+        let step = (i.checked_sub(prev.0)?, j.checked_sub(prev.1)?);
+        cost = match step {
+            (1, 1) => distance.distance(chain1.get(i - 1), chain2.get(j - 1)) + cost,
+            (1, 0) if prev_step == Some(step) => cost + distance.deletion_cost(),
+            (1, 0) => cost + distance.gap_open() + distance.deletion_cost(),
+            (0, 1) if prev_step == Some(step) => cost + distance.insertion_cost(),
+            (0, 1) => cost + distance.gap_open() + distance.insertion_cost(),
+            _ => return None,
+        };
+        prev = (i, j);
+        prev_step = Some(step);
+    }
+    Some(cost)
+}
+
+/// Best occurrence of a query trace inside a reference trace, see `StandardDTW::search`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Match {
+    /// First token of the reference in the match
+    pub start: usize,
+    /// Token after the last one of the match
+    pub end: usize,
+    pub cost: f64,
+    /// Warping path from `(query.size(), end)`, excluded, back to `(0, start)`
+    pub path: Option<(Vec<OP>, usize, usize)>,
+}
+
+/// Exact DTW over the whole matrix, of `S` scores, see `with_score`
+pub struct StandardDTW<'a, S: Score = f64> {
     pub distance: &'a dyn Distance,
+    gotoh: Gotoh<S>,
 }
 
 pub struct STRACDistance {
     // Default to 1
     pub gap_cost: f64,
+    // Default to 0
+    pub gap_open: f64,
+    // Default to the gap cost
+    pub insertion_cost: Option<f64>,
+    pub deletion_cost: Option<f64>,
     pub match_cost: f64,
     pub mismatch_cost: f64,
 }
@@ -136,6 +415,9 @@ impl Default for STRACDistance {
     fn default() -> Self {
         STRACDistance {
             gap_cost: 1.0,
+            gap_open: 0.0,
+            insertion_cost: None,
+            deletion_cost: None,
             match_cost: 0.0,
             mismatch_cost: 3.0,
         }
@@ -156,188 +438,1450 @@ impl Distance for STRACDistance {
     fn gap_cost(&self) -> f64 {
         self.gap_cost
     }
+
+    #[inline]
+    fn gap_open(&self) -> f64 {
+        self.gap_open
+    }
+
+    #[inline]
+    fn insertion_cost(&self) -> f64 {
+        self.insertion_cost.unwrap_or(self.gap_cost)
+    }
+
+    #[inline]
+    fn deletion_cost(&self) -> f64 {
+        self.deletion_cost.unwrap_or(self.gap_cost)
+    }
 }
 
 impl STRACDistance {
     pub fn new(gap_cost: f64, mismatch_cost: f64, match_cost: f64) -> Self {
         STRACDistance {
             gap_cost,
+            gap_open: 0.0,
+            insertion_cost: None,
+            deletion_cost: None,
             match_cost,
             mismatch_cost,
         }
     }
-}
+}
+
+impl<'a> StandardDTW<'a> {
+    pub fn new(distance: &'a dyn Distance) -> StandardDTW<'a> {
+        StandardDTW {
+            distance,
+            gotoh: Gotoh::new(None),
+        }
+    }
+}
+
+impl<'a, S: Score> StandardDTW<'a, S> {
+    /// `calculate` aligns the first trace with the best part of the second one, see `search`
+    pub fn subsequence(self) -> Self {
+        self.with_free_gaps(FreeGaps::subsequence())
+    }
+
+    /// Finds the part of `reference` that aligns best with the whole `query`. The tokens of the
+    /// reference before and after the match are free. Ties go to the match that ends first
+    pub fn search<A, B>(&self, query: &A, reference: &B) -> Match
+    where
+        A: Accesor + ?Sized,
+        B: Accesor + ?Sized,
+    {
+        let free = FreeGaps::subsequence();
+        let Some((cost, (_, end), path)) = self.gotoh.align(self.distance, query, reference, free)
+        else {
+            let exact = StandardDTW {
+                distance: self.distance,
+                gotoh: self.gotoh.exact(),
+            };
+            return exact.search(query, reference);
+        };
+        Match {
+            start: path.2,
+            end,
+            cost: cost.to_cost(),
+            path: Some(path),
+        }
+    }
+}
+
+// Implement one based on memory file mapping
+
+impl Accesor for [TokenID] {
+    #[inline]
+    fn get(&self, idx: usize) -> TokenID {
+        self[idx]
+    }
+
+    #[inline]
+    fn size(&self) -> usize {
+        self.len()
+    }
+
+    fn get_half(&self) -> Result<Box<dyn Accesor>> {
+        let mut v = Vec::with_capacity(self.len() / 2);
+
+        // We take the first of the consecutive pairs
+        for (i, t) in self.iter().enumerate() {
+            if i % 2 == 0 {
+                v.push(*t);
+            }
+        }
+
+        Ok(Box::new(v))
+    }
+}
+
+impl Accesor for Vec<TokenID> {
+    #[inline]
+    fn get(&self, idx: usize) -> TokenID {
+        self[idx]
+    }
+
+    #[inline]
+    fn size(&self) -> usize {
+        self.len()
+    }
+
+    fn get_half(&self) -> Result<Box<dyn Accesor>> {
+        self.as_slice().get_half()
+    }
+}
+
+pub struct UnsafeDTW<'a, S: Score = f64> {
+    distance: &'a dyn Distance,
+    gotoh: Gotoh<S>,
+}
+
+impl<'a> UnsafeDTW<'a> {
+    pub fn new(distance: &'a dyn Distance) -> UnsafeDTW<'a> {
+        UnsafeDTW {
+            distance,
+            gotoh: Gotoh::new(None),
+        }
+    }
+}
+
+/// Builders and `DTW` of the engines that wrap a `Gotoh` matrix
+macro_rules! gotoh_engine {
+    ($engine:ident) => {
+        impl<'a, S: Score> $engine<'a, S> {
+            /// The matrix holds `T` scores instead, e.g. `u16` for small integer costs takes a
+            /// quarter of the memory of `f64`. The costs are converted as `Score::from_cost`
+            /// does, distances with a cost that `T` cannot hold, e.g. a fractional one for
+            /// integer scores, are aligned with `f64` scores instead. So are the alignments
+            /// whose cost saturates
+            pub fn with_score<T: Score>(self) -> $engine<'a, T> {
+                $engine {
+                    distance: self.distance,
+                    gotoh: self.gotoh.with_score(),
+                }
+            }
+
+            /// The gaps at the ends of the traces that `calculate` does not pay
+            pub fn with_free_gaps(mut self, free: FreeGaps) -> Self {
+                self.gotoh.free = free;
+                self
+            }
+
+            /// Keeps the matrix in `storage`, `Storage::Disk` lets the system write to disk the
+            /// parts that do not fit in RAM. The matrix stays in memory if the file can not be
+            /// mapped
+            pub fn with_storage(mut self, storage: Storage) -> Self {
+                self.gotoh.storage = storage;
+                self
+            }
+
+            /// Records the move that gives the cost of each cell, in 2 bits, and follows them
+            /// back to get the path. Only two rows of scores are kept, so the alignment takes 32
+            /// times less memory than the `f64` matrix, 16 with affine gaps. The scores do not
+            /// tell where the runs of affine gaps start, so the moves are always recorded with
+            /// them
+            pub fn with_traceback(mut self) -> Self {
+                self.gotoh.traceback = true;
+                self
+            }
+
+            /// The move of the path when several moves give the cost of a cell,
+            /// `TieBreak::Match` by default. Either way the cost of the path is the cost of the
+            /// alignment, see `path_cost`
+            pub fn with_tie_break(mut self, tie: TieBreak) -> Self {
+                self.gotoh.tie = tie;
+                self
+            }
+        }
+
+        impl<S: Score> DTW for $engine<'_, S> {
+            fn calculate<A, B>(&self, chain1: &A, chain2: &B) -> DTWResult
+            where
+                A: Accesor + ?Sized,
+                B: Accesor + ?Sized,
+            {
+                self.gotoh.calculate(self.distance, chain1, chain2)
+            }
+        }
+    };
+}
+
+gotoh_engine!(StandardDTW);
+gotoh_engine!(UnsafeDTW);
+
+/// Gotoh's recurrences over the whole matrix, or only over the cells of a window, with `S`
+/// scores. The full-matrix engines only differ in the window
+struct Gotoh<S: Score> {
+    window: Option<DynamicWindow>,
+    free: FreeGaps,
+    storage: Storage,
+    // The path comes from the moves of the cells instead of the matrix, see `with_traceback`
+    traceback: bool,
+    tie: TieBreak,
+    score: std::marker::PhantomData<S>,
+}
+
+impl Gotoh<f64> {
+    fn new(window: Option<DynamicWindow>) -> Self {
+        Gotoh {
+            window,
+            free: FreeGaps::default(),
+            storage: Storage::default(),
+            traceback: false,
+            tie: TieBreak::default(),
+            score: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<S: Score> Gotoh<S> {
+    fn with_score<T: Score>(self) -> Gotoh<T> {
+        Gotoh {
+            window: self.window,
+            free: self.free,
+            storage: self.storage,
+            traceback: self.traceback,
+            tie: self.tie,
+            score: std::marker::PhantomData,
+        }
+    }
+
+    /// The same matrix with `f64` scores, for the distances whose costs are not `S` scores
+    fn exact(&self) -> Gotoh<f64> {
+        log::warn!(
+            "The costs do not fit in {} scores, using f64",
+            std::any::type_name::<S>()
+        );
+        Gotoh {
+            window: self.window.clone(),
+            free: self.free,
+            storage: self.storage.clone(),
+            traceback: self.traceback,
+            tie: self.tie,
+            score: std::marker::PhantomData,
+        }
+    }
+
+    /// First and last columns of the row that are calculated, `None` if the row is out of the
+    /// window
+    #[inline]
+    fn limits(&self, i: usize, columns: usize) -> Option<(usize, usize)> {
+        match &self.window {
+            None => Some((0, columns - 1)),
+            Some(window) => match window.get_limits(i) {
+                (Some(min), Some(max)) => Some((min, max.min(columns - 1))),
+                _ => None,
+            },
+        }
+    }
+
+    fn calculate<A, B>(&self, distance: &dyn Distance, chain1: &A, chain2: &B) -> DTWResult
+    where
+        A: Accesor + ?Sized,
+        B: Accesor + ?Sized,
+    {
+        match self.align(distance, chain1, chain2, self.free) {
+            Some((cost, _, path)) => (cost.to_cost(), Some(path)),
+            None => self.exact().calculate(distance, chain1, chain2),
+        }
+    }
+
+    /// Cost, last cell and warping path of the best alignment, from the full matrix or from the
+    /// moves with `with_traceback`. `None` if a cost is not an `S` score
+    #[allow(clippy::type_complexity)]
+    fn align<A, B>(
+        &self,
+        distance: &dyn Distance,
+        chain1: &A,
+        chain2: &B,
+        free: FreeGaps,
+    ) -> Option<(S, OP, (Vec<OP>, usize, usize))>
+    where
+        A: Accesor + ?Sized,
+        B: Accesor + ?Sized,
+    {
+        let (rows, columns) = (chain1.size() + 1, chain2.size() + 1);
+        // The scores do not tell where the runs of affine gaps start, the moves do
+        let affine = distance.gap_open() != 0.0;
+        if self.traceback || affine {
+            let mut moves = allocate_moves(rows, columns, affine, &self.storage);
+            let mut dtw = Rows::new(columns, S::INFINITY);
+            let (cost, end) =
+                self.fill(distance, chain1, chain2, free, &mut dtw, Some(&mut moves))?;
+            return Some((cost, end, trace_moves(&moves, end, free)));
+        }
+
+        // Cells outside of the window keep the infinite cost, so no path goes through them
+        let mut dtw = allocate(rows, columns, S::INFINITY, &self.storage);
+        let (cost, end) = self.fill(distance, chain1, chain2, free, &mut dtw, None)?;
+        let steps = steps(distance, chain1, chain2);
+        let window = self.window.as_ref();
+        Some((
+            cost,
+            end,
+            trace_back(&dtw, window, end, free, self.tie, steps),
+        ))
+    }
+
+    /// Fills the cells of the window, the first row and column are 0 where the gaps are free.
+    /// `dtw` is the whole matrix, whose other cells have to be infinite, or its last two rows,
+    /// then the path needs the `moves` of the cells.
+    /// Returns the cost and the last cell of the best alignment, `None` as soon as a cost is not
+    /// an `S` score or if the best cost saturates
+    fn fill<A, B, M>(
+        &self,
+        distance: &dyn Distance,
+        chain1: &A,
+        chain2: &B,
+        free: FreeGaps,
+        dtw: &mut M,
+        mut moves: Option<&mut Traceback>,
+    ) -> Option<(S, OP)>
+    where
+        A: Accesor + ?Sized,
+        B: Accesor + ?Sized,
+        M: std::ops::IndexMut<usize, Output = [S]>,
+    {
+        let open = S::from_cost(distance.gap_open())?;
+        // Moving down skips a token of the first trace, moving right one of the second
+        let (delete, insert) = (
+            S::from_cost(distance.deletion_cost())?,
+            S::from_cost(distance.insertion_cost())?,
+        );
+        let columns = chain2.size() + 1;
+        // Best costs ending with a gap coming from the previous row, and from the previous column.
+        // The previous row is only valid inside of its window
+        let mut up = vec![S::INFINITY; columns];
+        let mut left;
+        let mut best = (S::INFINITY, (chain1.size(), chain2.size()));
+
+        for i in 0..=chain1.size() {
+            // With the moves the scores are in `Rows`, the row is the one of `i - 2`, whose window
+            // is not the same
+            if moves.is_some() && self.window.is_some() && i >= 2 {
+                if let Some((min, max)) = self.limits(i - 2, columns) {
+                    dtw[i][min..=max].fill(S::INFINITY);
+                }
+            }
+
+            if let Some((min, max)) = self.limits(i, columns) {
+                left = S::INFINITY;
+                for j in min..=max {
+                    let in_window = |i: usize, j: usize| {
+                        self.window
+                            .as_ref()
+                            .is_none_or(|window| window.is_in_range(i, j))
+                    };
+                    let prev_up = if i > 0 && in_window(i - 1, j) {
+                        up[j]
+                    } else {
+                        S::INFINITY
+                    };
+
+                    dtw[i][j] = match (i, j) {
+                        (0, 0) => S::ZERO,
+                        // First row
+                        (0, _) if free.start2 => S::ZERO,
+                        (0, _) => {
+                            left = gap(dtw[0][j - 1], left, open, insert);
+                            if let Some(moves) = moves.as_deref_mut() {
+                                moves.set((0, j), Move::Left, false, false);
+                            }
+                            left
+                        }
+                        // First column
+                        (_, 0) if free.start1 => S::ZERO,
+                        (i, 0) => {
+                            up[0] = gap(dtw[i - 1][0], prev_up, open, delete);
+                            if let Some(moves) = moves.as_deref_mut() {
+                                moves.set((i, 0), Move::Up, false, false);
+                            }
+                            up[0]
+                        }
+                        _ => {
+                            let a = chain1.get(i - 1);
+                            let b = chain2.get(j - 1);
+
+                            let diagcost =
+                                S::from_cost(distance.distance(a, b))?.add(dtw[i - 1][j - 1]);
+                            let left_run = left;
+                            up[j] = gap(dtw[i - 1][j], prev_up, open, delete);
+                            left = gap(dtw[i][j - 1], left_run, open, insert);
+
+                            if let Some(moves) = moves.as_deref_mut() {
+                                let runs = (
+                                    runs(dtw[i - 1][j], prev_up, open),
+                                    runs(dtw[i][j - 1], left_run, open),
+                                );
+                                let step = self.tie.best_move(diagcost, up[j], left);
+                                moves.set((i, j), step, runs.0, runs.1);
+                            }
+                            diagcost.min(up[j]).min(left)
+                        }
+                    };
+                }
+            }
+            free.keep_end(&mut best, i, chain1.size(), &dtw[i]);
+        }
+
+        // The alignment can be unreachable, but its cost can also have saturated
+        if S::SATURATES && best.0 == S::INFINITY {
+            return None;
+        }
+        Some(best)
+    }
+}
+
+pub struct FixedDTW<'a> {
+    distance: &'a dyn Distance,
+    // If set, the warping path is calculated as well, see `with_alignment`
+    alignment: bool,
+    free: FreeGaps,
+    // Costs above it are not calculated, see `with_threshold`
+    threshold: Option<f64>,
+    kernel: Option<Kernel>,
+    // The moves of `TieBreak::order`, swapped with the traces, see `with_tie_break`
+    tie: [Move; 3],
+}
+
+impl<'a> FixedDTW<'a> {
+    pub fn new(distance: &'a dyn Distance) -> FixedDTW<'a> {
+        FixedDTW {
+            distance,
+            alignment: false,
+            free: FreeGaps::default(),
+            threshold: None,
+            kernel: None,
+            tie: TieBreak::default().order(),
+        }
+    }
+
+    /// Also returns the warping path, still in linear memory. The matrix is never allocated, the
+    /// path is found by divide and conquer (Hirschberg), which takes about twice the time
+    pub fn with_alignment(distance: &'a dyn Distance) -> FixedDTW<'a> {
+        FixedDTW {
+            distance,
+            alignment: true,
+            free: FreeGaps::default(),
+            threshold: None,
+            kernel: None,
+            tie: TieBreak::default().order(),
+        }
+    }
+
+    /// The gaps at the ends of the traces that `calculate` does not pay. Finding where the path
+    /// starts takes a second pass backwards from its end
+    pub fn with_free_gaps(mut self, free: FreeGaps) -> Self {
+        self.free = free;
+        self
+    }
+
+    /// `calculate` aligns the first trace with the best part of the second one, see `search`
+    pub fn subsequence(self) -> Self {
+        self.with_free_gaps(FreeGaps::subsequence())
+    }
+
+    /// The move of the path when several moves give the cost of a cell, `TieBreak::Match` by
+    /// default. The path of `with_alignment` is the one of `StandardDTW` with the same policy
+    pub fn with_tie_break(mut self, tie: TieBreak) -> Self {
+        self.tie = tie.order();
+        self
+    }
+
+    /// `calculate` returns an infinite cost, and no path, as soon as the cost is known to be
+    /// above `threshold`: when `lower_bound` is already above it or when every cell of a row is,
+    /// of two consecutive anti-diagonals with `with_kernel`.
+    /// Comparing a trace with many others, the threshold is the cost of the best one so far.
+    /// With `with_alignment` the path is only searched for once the cost is known.
+    /// Negative costs can make a later row cheaper, the threshold is then ignored
+    pub fn with_threshold(mut self, threshold: f64) -> Self {
+        self.threshold = Some(threshold);
+        self
+    }
+
+    /// Without `with_alignment`, `calculate` fills the matrix by anti-diagonals with `kernel`
+    /// scores, eight cells at once if the CPU has AVX2. The costs are the same for integer
+    /// costs, distances that the scores cannot hold exactly and traces with too many different
+    /// tokens use the `f64` rows
+    pub fn with_kernel(mut self, kernel: Kernel) -> Self {
+        self.kernel = Some(kernel);
+        self
+    }
+
+    /// A lower bound of the cost of `calculate`, which only takes a pass over each trace. It is
+    /// the largest of the cost of the gaps that the difference of lengths forces and the cost of
+    /// pairing the tokens of each trace, where only as many tokens as the other trace has can be
+    /// paired with an equal one. Free gaps can skip any token, the bound is then 0.
+    /// Pairing takes a distance for every two different tokens of the traces, above
+    /// `PAIRING_LIMIT` only the lengths are bounded. With a negative cost there is no bound, it
+    /// is minus infinity
+    pub fn lower_bound<A, B>(&self, chain1: &A, chain2: &B) -> f64
+    where
+        A: Accesor + ?Sized,
+        B: Accesor + ?Sized,
+    {
+        let distance = self.distance;
+        let gaps = [
+            distance.gap_open(),
+            distance.insertion_cost(),
+            distance.deletion_cost(),
+        ];
+        if gaps.iter().any(|cost| *cost < 0.0) {
+            return f64::NEG_INFINITY;
+        }
+        if self.free != FreeGaps::default() {
+            return 0.0;
+        }
+
+        let (n, m) = (chain1.size(), chain2.size());
+        let length = match n.cmp(&m) {
+            std::cmp::Ordering::Greater => {
+                distance.gap_open() + (n - m) as f64 * distance.deletion_cost()
+            }
+            std::cmp::Ordering::Less => {
+                distance.gap_open() + (m - n) as f64 * distance.insertion_cost()
+            }
+            std::cmp::Ordering::Equal => 0.0,
+        };
+
+        let (histogram1, histogram2) = (histogram(chain1), histogram(chain2));
+        if histogram1.len() * histogram2.len() > PAIRING_LIMIT {
+            return length;
+        }
+        let pairs1 = pairing_cost(
+            &histogram1,
+            &histogram2,
+            distance.deletion_cost(),
+            |a, b| distance.distance(a, b),
+        );
+        let pairs2 = pairing_cost(
+            &histogram2,
+            &histogram1,
+            distance.insertion_cost(),
+            |a, b| distance.distance(b, a),
+        );
+        if pairs1.min(pairs2) == f64::NEG_INFINITY {
+            return f64::NEG_INFINITY;
+        }
+        length.max(pairs1).max(pairs2)
+    }
+
+    /// Finds the part of `reference` that aligns best with the whole `query`, as
+    /// `StandardDTW::search` does. The memory is linear in the size of the query.
+    /// A first pass finds the end of the match, a second one backwards from the end finds the
+    /// start, the shortest match if there are ties. The path is only calculated with
+    /// `with_alignment`
+    pub fn search<A, B>(&self, query: &A, reference: &B) -> Match
+    where
+        A: Accesor + ?Sized,
+        B: Accesor + ?Sized,
+    {
+        let dtw = FixedDTW {
+            distance: self.distance,
+            alignment: self.alignment,
+            free: FreeGaps::subsequence(),
+            threshold: None,
+            kernel: None,
+            tie: self.tie,
+        };
+        let (cost, end) = dtw.end(query, reference);
+        let start = dtw.start(query, reference, end);
+        let path = self
+            .alignment
+            .then(|| (dtw.hirschberg(query, reference, start, end).1, 0, start.1));
+
+        Match {
+            start: start.1,
+            end: end.1,
+            cost,
+            path,
+        }
+    }
+
+    /// Finds every occurrence of `query` in `reference` that costs at most `threshold`, without
+    /// overlaps and in order, in a single pass over the reference (SPRING).
+    /// Every cell keeps the start of its best path. A match is reported once no path through a
+    /// cell that overlaps it can still end cheaper, then the paths that overlap it are dropped.
+    /// Matches must have at least one token of the reference. With `with_alignment` the path of
+    /// every match is calculated, and its cost is the one of the best alignment of its tokens
+    pub fn occurrences<A, B>(&self, query: &A, reference: &B, threshold: f64) -> Vec<Match>
+    where
+        A: Accesor + ?Sized,
+        B: Accesor + ?Sized,
+    {
+        let open = self.distance.gap_open();
+        // The rows are the reference, moving down inserts and moving right deletes
+        let (insert, delete) = (
+            self.distance.insertion_cost(),
+            self.distance.deletion_cost(),
+        );
+        let len = query.size();
+        let infinite = || vec![(f64::INFINITY, 0); len + 1];
+        let via = |(cost, start): (f64, usize), extra: f64| (cost + extra, start);
+        let min = |a: (f64, usize), b: (f64, usize)| if b.0 < a.0 { b } else { a };
+        // Cost and first row of the best paths to every cell, by the last move
+        let (mut total, mut up, mut left) = (infinite(), infinite(), infinite());
+
+        let mut found = vec![];
+        // Best match that can not be reported yet, as (cost, start, end)
+        let mut candidate: Option<(f64, usize, usize)> = None;
+
+        for i in 0..=reference.size() {
+            // The total of the previous row, before being overwritten
+            let mut diag = total[0];
+            // Tokens of the reference before the match are free
+            total[0] = (0.0, i);
+            up[0] = (f64::INFINITY, i);
+            for j in 1..=len {
+                left[j] = min(via(total[j - 1], open), left[j - 1]);
+                left[j].0 += delete;
+                if i == 0 {
+                    total[j] = left[j];
+                    continue;
+                }
+
+                let a = query.get(j - 1);
+                let b = reference.get(i - 1);
+                let cost = via(diag, self.distance.distance(a, b));
+                diag = total[j];
+                up[j] = min(via(total[j], open), up[j]);
+                up[j].0 += insert;
+
+                total[j] = min(min(cost, up[j]), left[j]);
+            }
+
+            // No path through the row can end in a cheaper match that overlaps the candidate
+            if let Some((best, start, end)) = candidate {
+                let settled = |cells: &[(f64, usize)]| {
+                    cells
+                        .iter()
+                        .all(|&(cost, first)| cost >= best || first >= end)
+                };
+                if settled(&total) && settled(&up) && settled(&left) {
+                    found.push((start, end, best));
+                    candidate = None;
+
+                    for cells in [&mut total, &mut up, &mut left] {
+                        for cell in cells.iter_mut().filter(|cell| cell.1 < end) {
+                            cell.0 = f64::INFINITY;
+                        }
+                    }
+                    for j in 0..=len {
+                        total[j] = min(min(total[j], up[j]), left[j]);
+                    }
+                }
+            }
+
+            let (cost, start) = total[len];
+            let better = candidate.is_none_or(|(best, _, _)| cost < best);
+            if cost <= threshold && start < i && better {
+                candidate = Some((cost, start, i));
+            }
+        }
+        if let Some((best, start, end)) = candidate {
+            found.push((start, end, best));
+        }
+
+        found
+            .into_iter()
+            .map(|(start, end, cost)| {
+                let mut found = Match {
+                    start,
+                    end,
+                    cost,
+                    path: None,
+                };
+                if self.alignment {
+                    let dtw = FixedDTW::new(self.distance);
+                    let (cost, path) = dtw.hirschberg(query, reference, (0, start), (len, end));
+                    found.cost = cost;
+                    found.path = Some((path, 0, start));
+                }
+                found
+            })
+            .collect()
+    }
+}
+
+impl DTW for FixedDTW<'_> {
+    fn calculate<A, B>(&self, chain1: &A, chain2: &B) -> DTWResult
+    where
+        A: Accesor + ?Sized,
+        B: Accesor + ?Sized,
+    {
+        // Swap the chains if the first one is smaller, the distance and the free gaps are swapped
+        // as well so the insertions and deletions keep their costs
+        let swapped = chain1.size() > chain2.size();
+        let transposed = Transposed(self.distance);
+        let dtw = FixedDTW {
+            distance: if swapped { &transposed } else { self.distance },
+            alignment: self.alignment,
+            free: if swapped {
+                self.free.transposed()
+            } else {
+                self.free
+            },
+            threshold: self.threshold,
+            kernel: self.kernel,
+            tie: if swapped {
+                swap_gaps(self.tie)
+            } else {
+                self.tie
+            },
+        };
+
+        if !swapped {
+            return dtw.align(chain1, chain2);
+        }
+        let (cost, path) = dtw.align(chain2, chain1);
+        let path = path.map(|(mut path, minj, mini)| {
+            path.iter_mut().for_each(|(i, j)| std::mem::swap(i, j));
+            (path, mini, minj)
+        });
+        (cost, path)
+    }
+
+    fn can_provide_alignment(&self) -> bool {
+        self.alignment
+    }
+}
+
+/// Most pairs of different tokens whose distance `FixedDTW::lower_bound` takes
+const PAIRING_LIMIT: usize = 1 << 20;
+
+/// Number of times that each token appears in the trace
+fn histogram<A>(chain: &A) -> std::collections::BTreeMap<TokenID, usize>
+where
+    A: Accesor + ?Sized,
+{
+    let mut histogram = std::collections::BTreeMap::new();
+    for i in 0..chain.size() {
+        *histogram.entry(chain.get(i)).or_insert(0) += 1;
+    }
+    histogram
+}
+
+/// Least cost of the tokens of a trace, given by its histogram `own`, when each one is either a
+/// gap or paired with a token of the other trace. Only as many tokens as `other` has can be
+/// paired with an equal one, the rest are paired with the cheapest different one. Minus
+/// infinity if a distance is negative
+fn pairing_cost<F>(
+    own: &std::collections::BTreeMap<TokenID, usize>,
+    other: &std::collections::BTreeMap<TokenID, usize>,
+    gap: f64,
+    distance: F,
+) -> f64
+where
+    F: Fn(TokenID, TokenID) -> f64,
+{
+    own.iter()
+        .map(|(&a, &count)| {
+            let (mut equal, mut different) = (gap, gap);
+            for &b in other.keys() {
+                if distance(a, b) < 0.0 {
+                    return f64::NEG_INFINITY;
+                }
+                if a == b {
+                    equal = equal.min(distance(a, b));
+                } else {
+                    different = different.min(distance(a, b));
+                }
+            }
+            let paired = count.min(other.get(&a).copied().unwrap_or(0));
+            paired as f64 * equal.min(different) + (count - paired) as f64 * different
+        })
+        .sum()
+}
+
+/// Exact DTW that fills the matrix by anti-diagonals. The cells of an anti-diagonal only depend
+/// on the two previous ones, so long anti-diagonals are split in chunks filled in parallel, by
+/// the rayon thread pool.
+/// Only three anti-diagonals are kept, in contiguous buffers, so the memory is linear and there
+/// is no warping path. The costs are the ones of `StandardDTW`, it pays off for traces of many
+/// thousands of tokens. The distance is shared by the threads, so it has to be `Sync`
+pub struct WavefrontDTW<'a> {
+    distance: &'a (dyn Distance + Sync),
+    free: FreeGaps,
+    // Cells of an anti-diagonal filled by each task
+    chunk: usize,
+}
+
+impl<'a> WavefrontDTW<'a> {
+    pub fn new(distance: &'a (dyn Distance + Sync)) -> Self {
+        WavefrontDTW {
+            distance,
+            free: FreeGaps::default(),
+            chunk: 4096,
+        }
+    }
+
+    /// The gaps at the ends of the traces that `calculate` does not pay
+    pub fn with_free_gaps(mut self, free: FreeGaps) -> Self {
+        self.free = free;
+        self
+    }
+
+    /// Cells of an anti-diagonal filled by each task, 4096 by default. Anti-diagonals shorter
+    /// than two chunks are filled by the calling thread, the synchronization would cost more
+    pub fn with_chunk(mut self, chunk: usize) -> Self {
+        self.chunk = chunk.max(1);
+        self
+    }
+
+    /// Fills the cells of the anti-diagonal `d` from the row `first`, `total`, `up` and `left`
+    /// start at that row. `prev` is the anti-diagonal `d - 1` and `prev2` the totals of `d - 2`,
+    /// all of them indexed by row. `reversed2` is the second trace backwards, so the tokens of
+    /// both traces are read in order along the anti-diagonal
+    #[allow(clippy::too_many_arguments)]
+    fn fill(
+        &self,
+        chain1: &[TokenID],
+        reversed2: &[TokenID],
+        d: usize,
+        first: usize,
+        prev: &Row,
+        prev2: &[f64],
+        (total, up, left): (&mut [f64], &mut [f64], &mut [f64]),
+    ) {
+        let free = self.free;
+        let open = self.distance.gap_open();
+        // Moving down skips a token of the first trace, moving right one of the second
+        let (delete, insert) = (
+            self.distance.deletion_cost(),
+            self.distance.insertion_cost(),
+        );
+        let m = reversed2.len();
+
+        for k in 0..total.len() {
+            let i = first + k;
+            let cell = match (i, d - i) {
+                (0, 0) => (0.0, f64::INFINITY, f64::INFINITY),
+                // First row
+                (0, _) if free.start2 => (0.0, f64::INFINITY, f64::INFINITY),
+                (0, _) => {
+                    let left = gap(prev.total[0], prev.left[0], open, insert);
+                    (left, f64::INFINITY, left)
+                }
+                // First column
+                (_, 0) if free.start1 => (0.0, f64::INFINITY, f64::INFINITY),
+                (_, 0) => {
+                    let up = gap(prev.total[i - 1], prev.up[i - 1], open, delete);
+                    (up, up, f64::INFINITY)
+                }
+                _ => {
+                    let (a, b) = (chain1[i - 1], reversed2[m + i - d]);
+                    let diagcost = self.distance.distance(a, b) + prev2[i - 1];
+                    let up = gap(prev.total[i - 1], prev.up[i - 1], open, delete);
+                    let left = gap(prev.total[i], prev.left[i], open, insert);
+                    (diagcost.min(up).min(left), up, left)
+                }
+            };
+            (total[k], up[k], left[k]) = cell;
+        }
+    }
+}
+
+impl DTW for WavefrontDTW<'_> {
+    fn calculate<A, B>(&self, chain1: &A, chain2: &B) -> DTWResult
+    where
+        A: Accesor + ?Sized,
+        B: Accesor + ?Sized,
+    {
+        // The accesors do not have to be shared between threads, the tokens are copied once
+        let (n, m) = (chain1.size(), chain2.size());
+        let chain1 = (0..n).map(|i| chain1.get(i)).collect::<Vec<_>>();
+        let reversed2 = (0..m).rev().map(|j| chain2.get(j)).collect::<Vec<_>>();
+
+        let diagonal = || Row {
+            total: vec![f64::INFINITY; n + 1],
+            up: vec![f64::INFINITY; n + 1],
+            left: vec![f64::INFINITY; n + 1],
+        };
+        let (mut prev, mut cur) = (diagonal(), diagonal());
+        let mut prev2 = vec![f64::INFINITY; n + 1];
+
+        let mut best = (f64::INFINITY, (n, m));
+        for d in 0..=n + m {
+            // Rows of the cells of the anti-diagonal
+            let (first, last) = (d.saturating_sub(m), d.min(n));
+            let cells = (
+                &mut cur.total[first..=last],
+                &mut cur.up[first..=last],
+                &mut cur.left[first..=last],
+            );
+
+            if last + 1 - first < 2 * self.chunk {
+                self.fill(&chain1, &reversed2, d, first, &prev, &prev2, cells);
+            } else {
+                let (total, up, left) = cells;
+                total
+                    .par_chunks_mut(self.chunk)
+                    .zip(up.par_chunks_mut(self.chunk))
+                    .zip(left.par_chunks_mut(self.chunk))
+                    .enumerate()
+                    .for_each(|(k, ((total, up), left))| {
+                        let first = first + k * self.chunk;
+                        let cells = (total, up, left);
+                        self.fill(&chain1, &reversed2, d, first, &prev, &prev2, cells);
+                    });
+            }
+
+            // The ends of the alignment that the free gaps allow, see `FreeGaps::keep_end`
+            if self.free.end2 && last == n {
+                keep_best(&mut best, cur.total[n], (n, d - n));
+            }
+            if self.free.end1 && d >= m {
+                keep_best(&mut best, cur.total[first], (first, m));
+            }
+            if d == n + m {
+                keep_best(&mut best, cur.total[n], (n, m));
+            }
+
+            std::mem::swap(&mut prev2, &mut prev.total);
+            std::mem::swap(&mut prev, &mut cur);
+        }
+
+        (best.0, None)
+    }
+}
+
+/// The distance of the traces in the other order, the insertions become deletions
+struct Transposed<'a>(&'a dyn Distance);
+
+impl Distance for Transposed<'_> {
+    #[inline]
+    fn distance(&self, a: TokenID, b: TokenID) -> f64 {
+        self.0.distance(b, a)
+    }
+
+    fn gap_cost(&self) -> f64 {
+        self.0.gap_cost()
+    }
+
+    fn gap_open(&self) -> f64 {
+        self.0.gap_open()
+    }
+
+    fn gap_extend(&self) -> f64 {
+        self.0.gap_extend()
+    }
+
+    fn insertion_cost(&self) -> f64 {
+        self.0.deletion_cost()
+    }
 
-impl<'a> StandardDTW<'a> {
-    pub fn new(distance: &'a dyn Distance) -> StandardDTW {
-        StandardDTW { distance }
+    fn deletion_cost(&self) -> f64 {
+        self.0.insertion_cost()
     }
 }
 
-impl DTW for StandardDTW<'_> {
-    fn calculate(&self, chain1: Box<dyn Accesor>, chain2: Box<dyn Accesor>) -> DTWResult {
-        // Do slices
-        // We do it with the max MEM possible
-        let mut dtw = vec![vec![0.0; chain2.size() + 1]; chain1.size() + 1];
-        let dtw = dtw.as_mut_slice();
+/// How a path starts or ends, used to join the halves of the divide and conquer in the middle of
+/// a gap run, which is paid only once with affine gaps
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Run {
+    /// Any move
+    Any,
+    /// A gap from the previous row
+    Up,
+    /// A gap from the previous column
+    Left,
+}
 
-        for i in 0..=chain1.size() {
-            for j in 0..=chain2.size() {
-                match (i, j) {
-                    (0, 0) => dtw[0][0] = 0.0,
-                    // First column
-                    (0, _) => dtw[0][j] = self.distance.gap_cost() * j as f64,
-                    // First row
-                    (i, 0) => dtw[i][0] = self.distance.gap_cost() * i as f64,
-                    _ => {
-                        let a = chain1.get(i - 1);
-                        let b = chain2.get(j - 1);
-
-                        let diagcost = self.distance.distance(a, b) + dtw[i - 1][j - 1];
-                        let leftcost = self.distance.gap_cost() + dtw[i - 1][j];
-                        let rightcost = self.distance.gap_cost() + dtw[i][j - 1];
-
-                        let min = diagcost.min(leftcost).min(rightcost);
-
-                        dtw[i][j] = min;
-                    }
+/// Best costs of the cells of a row of the matrix, by the last move. `WavefrontDTW` keeps
+/// anti-diagonals, indexed by row
+struct Row {
+    total: Vec<f64>,
+    up: Vec<f64>,
+    left: Vec<f64>,
+}
+
+impl FixedDTW<'_> {
+    /// Cost and path of `chain1`, the shortest trace, against `chain2`
+    fn align<A, B>(&self, chain1: &A, chain2: &B) -> DTWResult
+    where
+        A: Accesor + ?Sized,
+        B: Accesor + ?Sized,
+    {
+        if let Some(threshold) = self.threshold {
+            match self.lower_bound(chain1, chain2) {
+                // The rows can get cheaper, nothing can be pruned
+                f64::NEG_INFINITY => {
+                    log::warn!("Some costs are negative, the threshold is ignored");
+                    let dtw = FixedDTW {
+                        threshold: None,
+                        ..*self
+                    };
+                    return dtw.align(chain1, chain2);
                 }
+                bound if bound > threshold => return (f64::INFINITY, None),
+                _ => {}
             }
         }
+        if !self.alignment {
+            let vectorized = self.kernel.and_then(|kernel| {
+                let (distance, free) = (self.distance, self.free);
+                crate::simd::calculate(kernel, distance, chain1, chain2, free, self.threshold)
+            });
+            let cost = match vectorized {
+                Some(cost) if self.threshold.is_some_and(|threshold| cost > threshold) => {
+                    f64::INFINITY
+                }
+                Some(cost) => cost,
+                None => self.end(chain1, chain2).0,
+            };
+            return (cost, None);
+        }
 
-        let cost = dtw[chain1.size()][chain2.size()];
-        let path = self.get_warp_path(&dtw, None);
-
-        (cost, Some(path))
+        let no_gaps = self.free == FreeGaps::default();
+        let end = if no_gaps && self.threshold.is_none() {
+            (chain1.size(), chain2.size())
+        } else {
+            // The first pass finds the end, and whether the cost is above the threshold
+            match self.end(chain1, chain2) {
+                (cost, _) if cost == f64::INFINITY => return (cost, None),
+                (_, end) => end,
+            }
+        };
+        let start = if no_gaps {
+            (0, 0)
+        } else {
+            self.start(chain1, chain2, end)
+        };
+        let (cost, path) = self.hirschberg(chain1, chain2, start, end);
+        (cost, Some((path, start.0, start.1)))
     }
-}
 
-// Implement one based on memory file mapping
-
-impl Accesor for Vec<TokenID> {
-    #[inline]
-    fn get(&self, idx: usize) -> TokenID {
-        self[idx]
+    /// Cost and last cell of the best alignment, as `(chain1, chain2)` indexes. The cost is
+    /// infinite if it is above the threshold
+    fn end<A, B>(&self, chain1: &A, chain2: &B) -> (f64, OP)
+    where
+        A: Accesor + ?Sized,
+        B: Accesor + ?Sized,
+    {
+        let (cols, rows) = (0..chain1.size(), 0..chain2.size());
+        match self.best_cell(chain1, chain2, cols, rows, false, self.free) {
+            (cost, end) if self.threshold.is_some_and(|threshold| cost > threshold) => {
+                (f64::INFINITY, end)
+            }
+            best => best,
+        }
     }
 
-    #[inline]
-    fn size(&self) -> usize {
-        self.len()
+    /// First cell of the best alignment that ends in `end`. The matrix is filled backwards from
+    /// the end, where the free gaps of the start are free gaps of the end
+    fn start<A, B>(&self, chain1: &A, chain2: &B, end: OP) -> OP
+    where
+        A: Accesor + ?Sized,
+        B: Accesor + ?Sized,
+    {
+        let free = FreeGaps {
+            end1: self.free.start1,
+            end2: self.free.start2,
+            ..Default::default()
+        };
+        let (_, (cols, rows)) = self.best_cell(chain1, chain2, 0..end.0, 0..end.1, true, free);
+        (end.0 - cols, end.1 - rows)
     }
 
-    fn get_half(&self) -> Box<dyn Accesor> {
-        let mut v = Vec::with_capacity(self.len() / 2);
+    /// Cost of the best alignment of `chain1[cols]` against `chain2[rows]` with the free gaps,
+    /// and the number of tokens of each slice in it. With `reverse` both slices are aligned from
+    /// their ends
+    fn best_cell<A, B>(
+        &self,
+        chain1: &A,
+        chain2: &B,
+        cols: std::ops::Range<usize>,
+        rows: std::ops::Range<usize>,
+        reverse: bool,
+        free: FreeGaps,
+    ) -> (f64, OP)
+    where
+        A: Accesor + ?Sized,
+        B: Accesor + ?Sized,
+    {
+        let open = self.distance.gap_open();
+        // The rows are the second trace, moving down inserts and moving right deletes
+        let (insert, delete) = (
+            self.distance.insertion_cost(),
+            self.distance.deletion_cost(),
+        );
+        let width = cols.len();
+        let mut row = Row {
+            total: vec![f64::INFINITY; width + 1],
+            up: vec![f64::INFINITY; width + 1],
+            left: vec![f64::INFINITY; width + 1],
+        };
 
-        // We take the first of the consecutive pairs
-        for i in 0..self.len() {
-            if i % 2 == 0 {
-                v.push(self[i]);
+        row.total[0] = 0.0;
+        for j in 1..=width {
+            if free.start1 {
+                row.total[j] = 0.0;
+            } else {
+                row.left[j] = gap(row.total[j - 1], row.left[j - 1], open, delete);
+                row.total[j] = row.left[j];
             }
         }
 
-        Box::new(v)
-    }
-}
+        let mut best = (f64::INFINITY, (width, rows.len()));
+        if free.end2 {
+            keep_best(&mut best, row.total[width], (width, 0));
+        }
+        // The backward pass looks for the start of an alignment that is below it
+        let mut threshold = self.threshold.filter(|_| !reverse);
 
-pub struct UnsafeDTW<'a> {
-    distance: &'a dyn Distance,
-}
+        for i in 1..=rows.len() {
+            let b = if reverse {
+                chain2.get(rows.end - i)
+            } else {
+                chain2.get(rows.start + i - 1)
+            };
 
-impl<'a> UnsafeDTW<'a> {
-    pub fn new(distance: &'a dyn Distance) -> UnsafeDTW {
-        UnsafeDTW { distance }
-    }
-}
+            // The total of the previous row, before being overwritten
+            let mut diag = row.total[0];
+            if free.start2 {
+                row.total[0] = 0.0;
+            } else {
+                row.up[0] = gap(row.total[0], row.up[0], open, insert);
+                row.total[0] = row.up[0];
+            }
+            row.left[0] = f64::INFINITY;
+            let mut lowest = row.total[0];
+
+            for j in 1..=width {
+                let a = if reverse {
+                    chain1.get(cols.end - j)
+                } else {
+                    chain1.get(cols.start + j - 1)
+                };
+
+                let cost = self.distance.distance(a, b);
+                // Too many tokens for `lower_bound` to see every distance
+                if cost < 0.0 {
+                    threshold = None;
+                }
+                let cost = cost + diag;
+                diag = row.total[j];
+                row.up[j] = gap(row.total[j], row.up[j], open, insert);
+                row.left[j] = gap(row.total[j - 1], row.left[j - 1], open, delete);
 
-impl DTW for UnsafeDTW<'_> {
-    fn calculate(&self, chain1: Box<dyn Accesor>, chain2: Box<dyn Accesor>) -> DTWResult {
-        let mut dtw = vec![vec![0.0; chain2.size() + 1]; chain1.size() + 1];
+                row.total[j] = cost.min(row.up[j]).min(row.left[j]);
+                lowest = lowest.min(row.total[j]);
+            }
 
-            for i in 0..=chain1.size() {
-                for j in 0..=chain2.size() {
-                    // Unsafe should disable the bounds check
-                    match (i, j) {
-                        (0, 0) => dtw[0][0] = 0.0,
-                        // First column
-                        (0, _) => dtw[0][j] = self.distance.gap_cost() * j as f64,
-                        // First row
-                        (i, 0) => dtw[i][0] = self.distance.gap_cost() * i as f64,
-                        _ => {
-                            let a = chain1.get(i - 1);
-                            let b = chain2.get(j - 1);
+            if free.end2 {
+                keep_best(&mut best, row.total[width], (width, i));
+            }
+            // Every path still to end goes through this row and no cost is negative
+            if threshold.is_some_and(|threshold| lowest > threshold) {
+                return best;
+            }
+        }
+
+        if free.end1 {
+            for j in 0..=width {
+                keep_best(&mut best, row.total[j], (j, rows.len()));
+            }
+        }
+        keep_best(&mut best, row.total[width], (width, rows.len()));
+        best
+    }
 
-                            let cost = self.distance.distance(a, b) + dtw[i - 1][j - 1];
-                            let leftcost = self.distance.gap_cost() + dtw[i - 1][j];
-                            let rightcost = self.distance.gap_cost() + dtw[i][j - 1];
+    /// Cost of `chain1[cols]` against `chain2[rows]`, for paths starting and ending with the moves
+    /// of `runs`, and the column where the path leaves the row `mid` with the run of gaps that
+    /// goes on there. The path is the one that `block` follows back from the end, the same one
+    /// as the full matrix, so every cell below the middle row keeps where its own path crosses
+    /// it, as the moves that `block` takes out of the cell
+    fn crossing<A, B>(
+        &self,
+        chain1: &A,
+        chain2: &B,
+        cols: std::ops::Range<usize>,
+        rows: std::ops::Range<usize>,
+        mid: usize,
+        (start, end): (Run, Run),
+    ) -> (f64, usize, Run)
+    where
+        A: Accesor + ?Sized,
+        B: Accesor + ?Sized,
+    {
+        let open = self.distance.gap_open();
+        // The rows are the second trace, moving down inserts and moving right deletes
+        let (insert, delete) = (
+            self.distance.insertion_cost(),
+            self.distance.deletion_cost(),
+        );
+        let width = cols.len();
+        let mut row = Row {
+            total: vec![f64::INFINITY; width + 1],
+            up: vec![f64::INFINITY; width + 1],
+            left: vec![f64::INFINITY; width + 1],
+        };
+        // Where the paths of the cells cross the middle row, by the last move
+        let mut through = [(); 3].map(|_| vec![(0, Run::Any); width + 1]);
+
+        // A path that has to start with a gap is already in the run, it is opened by the move
+        match start {
+            Run::Any => row.total[0] = 0.0,
+            Run::Up => row.up[0] = open,
+            Run::Left => row.left[0] = open,
+        }
 
-                            let min = cost.min(leftcost).min(rightcost);
+        for j in 1..=width {
+            row.left[j] = gap(row.total[j - 1], row.left[j - 1], open, delete);
+            row.total[j] = row.left[j];
+        }
 
-                            dtw[i][j] = min;
-                        }
+        for i in 1..=rows.len() {
+            let b = chain2.get(rows.start + i - 1);
+            let below = rows.start + i > mid;
+            let [total, up, left] = &mut through;
+
+            // The total of the previous row, before being overwritten
+            let (mut diag, mut diag_through) = (row.total[0], total[0]);
+            if !runs(row.total[0], row.up[0], open) {
+                up[0] = total[0];
+            }
+            total[0] = up[0];
+            row.up[0] = gap(row.total[0], row.up[0], open, insert);
+            row.left[0] = f64::INFINITY;
+            row.total[0] = row.up[0];
+
+            for j in 1..=width {
+                let a = chain1.get(cols.start + j - 1);
+
+                let cost = self.distance.distance(a, b) + diag;
+                let up_runs = runs(row.total[j], row.up[j], open);
+                let left_runs = runs(row.total[j - 1], row.left[j - 1], open);
+                diag = row.total[j];
+                row.up[j] = gap(row.total[j], row.up[j], open, insert);
+                row.left[j] = gap(row.total[j - 1], row.left[j - 1], open, delete);
+                row.total[j] = cost.min(row.up[j]).min(row.left[j]);
+
+                if below {
+                    if !up_runs {
+                        up[j] = total[j];
                     }
+                    left[j] = if left_runs { left[j - 1] } else { total[j - 1] };
+                    let diagonal = std::mem::replace(&mut diag_through, total[j]);
+                    // Up in the full matrix is a token of `chain1` only, a move to the right here
+                    total[j] = match preferred(self.tie, cost, row.left[j], row.up[j]) {
+                        Move::Diagonal => diagonal,
+                        Move::Up => left[j],
+                        _ => up[j],
+                    };
                 }
             }
 
-            let cost = dtw[chain1.size()][chain2.size()];
-            let path = self.get_warp_path(&dtw, None);
+            if rows.start + i == mid {
+                for j in 0..=width {
+                    (total[j], up[j], left[j]) = ((j, Run::Any), (j, Run::Up), (j, Run::Left));
+                }
+            }
+        }
 
-            (cost, Some(path))
+        let [total, up, left] = through;
+        let (cost, (k, run)) = match end {
+            Run::Any => (row.total[width], total[width]),
+            Run::Up => (row.up[width], up[width]),
+            Run::Left => (row.left[width], left[width]),
+        };
+        (cost, k, run)
     }
-}
-
-pub struct FixedDTW<'a> {
-    distance: &'a dyn Distance,
-}
 
-impl<'a> FixedDTW<'a> {
-    pub fn new(distance: &'a dyn Distance) -> FixedDTW {
-        FixedDTW { distance }
+    /// Cost and warping path of `chain1`, the shortest trace, against `chain2` from the cell
+    /// `start` to `end`. The path has the same shape as the one of `trace_back`
+    fn hirschberg<A, B>(&self, chain1: &A, chain2: &B, start: OP, end: OP) -> (f64, Vec<OP>)
+    where
+        A: Accesor + ?Sized,
+        B: Accesor + ?Sized,
+    {
+        // Cells from the start to the end, as (chain1, chain2) indexes
+        let mut cells = vec![start];
+        let cost = self.split(
+            chain1,
+            chain2,
+            start.0..end.0,
+            start.1..end.1,
+            (Run::Any, Run::Any),
+            &mut cells,
+        );
+
+        // The path goes backwards and does not include the last cell
+        cells.pop();
+        cells.reverse();
+        (cost, cells)
     }
-}
-
-impl DTW for FixedDTW<'_> {
-    fn calculate(&self, chain1: Box<dyn Accesor>, chain2: Box<dyn Accesor>) -> DTWResult {
-        // Swap the chains if the first one is smaller
-        let (chain1, chain2) = if chain1.size() > chain2.size() {
-            (chain2, chain1)
-        } else {
-            (chain1, chain2)
-        };
-
-        let mut prev_row = vec![0.0; chain1.size() + 1];
-        let prev_row = prev_row.as_mut_slice();
 
-        for i in 0..prev_row.len() {
-            prev_row[i] = self.distance.gap_cost() * i as f64;
+    /// Pushes the cells of the best path from `(cols.start, rows.start)`, excluded, to
+    /// `(cols.end, rows.end)` and returns its cost. The path starts and ends with the moves of
+    /// `runs`.
+    /// The rows are halved until one of the sides has a single token, then the block is small
+    /// enough to be solved with the full matrix
+    fn split<A, B>(
+        &self,
+        chain1: &A,
+        chain2: &B,
+        cols: std::ops::Range<usize>,
+        rows: std::ops::Range<usize>,
+        runs: (Run, Run),
+        cells: &mut Vec<OP>,
+    ) -> f64
+    where
+        A: Accesor + ?Sized,
+        B: Accesor + ?Sized,
+    {
+        if rows.len() <= 1 || cols.len() <= 1 {
+            return self.block(chain1, chain2, cols, rows, runs, cells);
         }
 
-        // let mut progre = 0;
-
-        for i in 1..=chain2.size() {
-            let mut curr_row = vec![0.0; chain1.size() + 1];
-            // TODO Check if the following actually helps
-            let curr_row = curr_row.as_mut_slice();
-
-            curr_row[0] = self.distance.gap_cost() * i as f64;
+        // The path crosses the middle row where the one that `block` follows back from the end
+        // leaves it
+        let mid = rows.start + rows.len() / 2;
+        let (cost, k, run) = self.crossing(chain1, chain2, cols.clone(), rows.clone(), mid, runs);
+        let col = cols.start + k;
+
+        self.split(
+            chain1,
+            chain2,
+            cols.start..col,
+            rows.start..mid,
+            (runs.0, run),
+            cells,
+        );
+        self.split(
+            chain1,
+            chain2,
+            col..cols.end,
+            mid..rows.end,
+            (run, runs.1),
+            cells,
+        );
+
+        cost
+    }
 
-            for j in 1..curr_row.len() {
-                let a = chain1.get(j - 1);
-                let b = chain2.get(i - 1);
+    /// Solves the block with the full matrix, at most two rows or two columns
+    fn block<A, B>(
+        &self,
+        chain1: &A,
+        chain2: &B,
+        cols: std::ops::Range<usize>,
+        rows: std::ops::Range<usize>,
+        (start, end): (Run, Run),
+        cells: &mut Vec<OP>,
+    ) -> f64
+    where
+        A: Accesor + ?Sized,
+        B: Accesor + ?Sized,
+    {
+        let open = self.distance.gap_open();
+        let (insert, delete) = (
+            self.distance.insertion_cost(),
+            self.distance.deletion_cost(),
+        );
+        let distance = |i: usize, j: usize| {
+            let a = chain1.get(cols.start + j - 1);
+            let b = chain2.get(rows.start + i - 1);
+            self.distance.distance(a, b)
+        };
 
-                let cost = self.distance.distance(a, b) + prev_row[j - 1];
-                let leftcost = self.distance.gap_cost() + prev_row[j];
-                let rightcost = self.distance.gap_cost() + curr_row[j - 1];
+        let matrix = || vec![vec![f64::INFINITY; cols.len() + 1]; rows.len() + 1];
+        let (mut dtw, mut up, mut left) = (matrix(), matrix(), matrix());
+        match start {
+            Run::Any => dtw[0][0] = 0.0,
+            Run::Up => up[0][0] = open,
+            Run::Left => left[0][0] = open,
+        }
 
-                let min = cost.min(leftcost).min(rightcost);
+        for i in 0..=rows.len() {
+            for j in 0..=cols.len() {
+                if i == 0 && j == 0 {
+                    continue;
+                }
+                let mut cost = f64::INFINITY;
+                if i > 0 && j > 0 {
+                    cost = distance(i, j) + dtw[i - 1][j - 1];
+                }
+                if i > 0 {
+                    up[i][j] = gap(dtw[i - 1][j], up[i - 1][j], open, insert);
+                }
+                if j > 0 {
+                    left[i][j] = gap(dtw[i][j - 1], left[i][j - 1], open, delete);
+                }
+                dtw[i][j] = cost.min(up[i][j]).min(left[i][j]);
+            }
+        }
 
-                 curr_row[j] = min;
+        // Follow the moves that actually gave the cost of each cell. The rows are the tokens of
+        // the second trace, a gap from the previous row is a gap in the first one
+        let (mut i, mut j) = (rows.len(), cols.len());
+        let mut run = end;
+        let mut block = vec![];
+        while i > 0 || j > 0 {
+            block.push((cols.start + j, rows.start + i));
+
+            if run == Run::Any {
+                let diagonal = if i > 0 && j > 0 {
+                    distance(i, j) + dtw[i - 1][j - 1]
+                } else {
+                    f64::INFINITY
+                };
+                run = match preferred(self.tie, diagonal, left[i][j], up[i][j]) {
+                    Move::Diagonal => {
+                        // The diagonal is the only move that is not a run
+                        i -= 1;
+                        j -= 1;
+                        continue;
+                    }
+                    Move::Up => Run::Left,
+                    _ => Run::Up,
+                };
             }
 
-            // Copy the memory
-             prev_row.copy_from_slice(&curr_row) ;
+            // Stay in the run while it is cheaper than opening it here, as `runs` records it
+            if run == Run::Up {
+                run = if runs(dtw[i - 1][j], up[i - 1][j], open) {
+                    Run::Up
+                } else {
+                    Run::Any
+                };
+                i -= 1;
+            } else {
+                run = if runs(dtw[i][j - 1], left[i][j - 1], open) {
+                    Run::Left
+                } else {
+                    Run::Any
+                };
+                j -= 1;
+            }
         }
+        cells.extend(block.into_iter().rev());
 
-        (prev_row[chain1.size()], None)
+        match end {
+            Run::Any => dtw[rows.len()][cols.len()],
+            Run::Up => up[rows.len()][cols.len()],
+            Run::Left => left[rows.len()][cols.len()],
+        }
     }
 }
 
@@ -349,14 +1893,16 @@ pub struct DynamicWindow {
 }
 
 impl DynamicWindow {
+    /// Last column of the row, `None` if the row is empty or outside of the window
     #[inline]
     pub fn get_max(&self, row: usize) -> Option<usize> {
-        self.max_values[row]
+        self.max_values.get(row).copied().flatten()
     }
 
+    /// First column of the row, `None` if the row is empty or outside of the window
     #[inline]
     pub fn get_min(&self, row: usize) -> Option<usize> {
-        self.min_values[row]
+        self.min_values.get(row).copied().flatten()
     }
 
     #[inline]
@@ -366,7 +1912,7 @@ impl DynamicWindow {
 
     #[inline]
     pub fn is_in_range(&self, row: usize, col: usize) -> bool {
-        match (self.min_values[row], self.max_values[row]) {
+        match self.get_limits(row) {
             (Some(min), Some(max)) => min <= col && col <= max,
             _ => false,
         }
@@ -437,6 +1983,63 @@ impl DynamicWindow {
         }
     }
 
+    /// Window with the given inclusive column range for each row of the cost matrix.
+    /// Row `i` is the prefix of `i` tokens of the first trace, so a trace of `n` tokens needs
+    /// `n + 1` ranges. Columns larger than `width` are ignored
+    pub fn from_ranges(ranges: &[(usize, usize)], width: usize) -> Self {
+        let mut window = DynamicWindow::new(ranges.len(), width);
+        for (row, &(min, max)) in ranges.iter().enumerate() {
+            window.set_range(min, max.min(width), row);
+        }
+        window
+    }
+
+    /// Cells on the straight line from `(0, 0)` to `(len1, len2)`, connected row to row
+    fn diagonal(len1: usize, len2: usize) -> Vec<(usize, usize)> {
+        if len1 == 0 {
+            return vec![(0, len2)];
+        }
+        let center = |i: usize| i * len2 / len1;
+        (0..=len1)
+            .map(|i| (center(i.saturating_sub(1)), center(i)))
+            .collect()
+    }
+
+    /// Sakoe-Chiba band, the cells at most `radius` columns away from the diagonal.
+    /// The diagonal goes from corner to corner, so traces of different length are supported
+    pub fn sakoe_chiba(len1: usize, len2: usize, radius: usize) -> Self {
+        let ranges: Vec<_> = Self::diagonal(len1, len2)
+            .into_iter()
+            .map(|(min, max)| (min.saturating_sub(radius), max.saturating_add(radius)))
+            .collect();
+        Self::from_ranges(&ranges, len2)
+    }
+
+    /// Itakura parallelogram, the path can not be steeper than `slope` nor flatter than
+    /// `1 / slope`, relative to the diagonal. Slopes below 1 are taken as 1, which leaves the
+    /// diagonal only
+    pub fn itakura(len1: usize, len2: usize, slope: f64) -> Self {
+        let slope = slope.max(1.0);
+        let (n, m) = (len1.max(1) as f64, len2 as f64);
+
+        let ranges: Vec<_> = Self::diagonal(len1, len2)
+            .into_iter()
+            .enumerate()
+            .map(|(i, (min, max))| {
+                // Normalized position of the row, from 0 to 1
+                let x = i as f64 / n;
+                let lower = (m * x / slope).max(m * (1.0 - slope * (1.0 - x)));
+                let upper = (m * x * slope).min(m * (1.0 - (1.0 - x) / slope));
+
+                // The rounding can leave the diagonal out, it is always kept
+                let lower = (lower.max(0.0).ceil() as usize).min(min);
+                let upper = (upper.max(0.0).floor() as usize).max(max);
+                (lower, upper)
+            })
+            .collect();
+        Self::from_ranges(&ranges, len2)
+    }
+
     pub fn replicate_prev(&mut self, i: usize) -> &mut Self {
         self.min_values[i] = self.min_values[i - 1];
         self.max_values[i] = self.max_values[i - 1];
@@ -457,115 +2060,39 @@ impl DynamicWindow {
         for i in 0..self.min_values.len() {
             // log::info!("{i}{}-{}", self.min_values[i], self.max_values[i]);
             print!("{i}");
-            for _ in 0..self.min_values[i].or(Some(0)).unwrap() {
+            for _ in 0..self.min_values[i].unwrap_or(0) {
                 print!(" ");
             }
-            for _ in
-                self.min_values[i].or(Some(0)).unwrap()..self.max_values[i].or(Some(0)).unwrap()
-            {
+            for _ in self.min_values[i].unwrap_or(0)..self.max_values[i].unwrap_or(0) {
                 print!("█");
             }
-            for _ in self.max_values[i].or(Some(self.width)).unwrap()..self.width {
+            for _ in self.max_values[i].unwrap_or(self.width)..self.width {
                 print!(" ");
             }
-            println!("");
+            println!();
         }
         println!("--------------")
     }
 }
 
-pub struct WindowedDTW<'a> {
-    window: DynamicWindow,
+/// Exact DTW over the cells of a window of the matrix. The gaps at the ends of the traces are
+/// only free inside of the window
+pub struct WindowedDTW<'a, S: Score = f64> {
     distance: &'a dyn Distance,
+    gotoh: Gotoh<S>,
 }
 
 impl<'a> WindowedDTW<'a> {
     pub fn new(window: DynamicWindow, distance: &'a dyn Distance) -> Self {
-        WindowedDTW { window, distance }
-    }
-}
-
-impl<'a> DTW for WindowedDTW<'a> {
-    fn calculate(&self, chain1: Box<dyn Accesor>, chain2: Box<dyn Accesor>) -> DTWResult {
-        // Do slices
-        // We do it with the max MEM possible
-        let mut dtw = vec![vec![std::f64::INFINITY; chain2.size() + 1]; chain1.size() + 1];
-        let dtw = dtw.as_mut_slice();
-
-        for i in 0..=chain1.size() {
-            let (min, max) = self.window.get_limits(i);
-
-            match (min, max) {
-                (Some(min), Some(max)) => {
-                    for j in min..=max.min(chain2.size()) {
-                        // patch here ?
-
-                        match (i, j) {
-                            (0, 0) => dtw[0][0] = 0.0,
-                            // First column
-                            (0, _) => dtw[0][j] = self.distance.gap_cost() * j as f64,
-                            // First row
-                            (i, 0) => dtw[i][0] = self.distance.gap_cost() * i as f64,
-                            _ => {
-                                let a = chain1.get(i - 1);
-                                let b = chain2.get(j - 1);
-
-                                // If i - 1, j - 1 are outside the window then return INFINITE
-                                let diagcost = if self.window.is_in_range(i - 1, j - 1)
-                                    || (i - 1) <= chain1.size()
-                                    || (j - 1) <= chain2.size()
-                                {
-                                    self.distance.distance(a, b) + dtw[i - 1][j - 1]
-                                } else {
-                                    std::f64::INFINITY / 2.0
-                                };
-
-                                let leftcost = if self.window.is_in_range(i - 1, j)
-                                    || (i - 1) <= chain1.size()
-                                    || (j) <= chain2.size()
-                                {
-                                    self.distance.gap_cost() + dtw[i - 1][j]
-                                } else {
-                                    std::f64::INFINITY / 2.0
-                                };
-
-                                let rightcost = if self.window.is_in_range(i, j - 1)
-                                    || (i) <= chain1.size()
-                                    || (j - 1) <= chain2.size()
-                                {
-                                    self.distance.gap_cost() + dtw[i][j - 1]
-                                } else {
-                                    std::f64::INFINITY / 2.0
-                                };
-
-                                let mini = diagcost.min(leftcost).min(rightcost);
-
-                                dtw[i][j] = mini;
-                            }
-                        }
-                    }
-                }
-                _ => {
-                    // Do nothing
-                }
-            }
+        WindowedDTW {
+            distance,
+            gotoh: Gotoh::new(Some(window)),
         }
-
-        /*
-        for i in 0..dtw.len() {
-            for j in 0..dtw[i].len() {
-                //print!("{} ", dtw[i][j]);
-            }
-            ://println!();
-        }*/
-
-        let cost = dtw[chain1.size() - 1][chain2.size() - 1];
-        let path = self.get_warp_path(&dtw, Some(self.window.clone()));
-
-        (cost, Some(path))
     }
 }
 
+gotoh_engine!(WindowedDTW);
+
 /*
 fn reduce_by_half<'a, T>(allocator: &'a dyn AccesorAllocator<T>, accessor: &mut Box<T>) -> T
 where
@@ -579,133 +2106,112 @@ where
     r
 }*/
 
-pub struct FastDTW<'a> {
+pub struct FastDTW<'a, D: DTW, S: Score = f64> {
     distance: &'a dyn Distance,
     radius: usize,
-    default_dtw: &'a dyn DTW,
+    default_dtw: &'a D,
     min_size: usize,
+    storage: Storage,
+    traceback: bool,
+    tie: TieBreak,
+    score: std::marker::PhantomData<S>,
 }
 
-impl<'a> FastDTW<'a> {
+impl<'a, D: DTW> FastDTW<'a, D> {
     pub fn new(
         distance: &'a dyn Distance,
         radius: usize,
         min_size: usize,
-        default_dtw: &'a dyn DTW,
-    ) -> FastDTW<'a> {
+        default_dtw: &'a D,
+    ) -> FastDTW<'a, D> {
         FastDTW {
             distance,
             radius,
             min_size,
             default_dtw,
+            storage: Storage::default(),
+            traceback: false,
+            tie: TieBreak::default(),
+            score: std::marker::PhantomData,
         }
     }
+}
 
-    // expands the path
-    fn expand(
-        ops: Vec<OP>,
-        radius: usize,
-        len1: usize,
-        len2: usize,
-        op_count: usize,
-        mini: usize,
-        minj: usize,
-    ) -> DynamicWindow {
-        let mut dynamic_window = DynamicWindow::new(len2 + 1, len1);
-        //let mut dynamic_window = dynamic_window.init(len1);
-
-        let mut lastwarpedi = usize::MAX;
-        let mut lastwarpedj = usize::MAX;
-
-        let blocksize = 2;
-        let mut currenti = mini;
-        let mut currentj = minj;
-
-        //println!("{} {}", len1, len2);
-        for i in (0..op_count).rev() {
-            let (warpedi, warpedj) = ops[i];
-            //println!("{} {}", warpedi + 1, warpedj + 1);
+impl<'a, D: DTW, S: Score> FastDTW<'a, D, S> {
+    /// The windowed levels hold `T` scores instead, see `StandardDTW::with_score`. The levels
+    /// of `default_dtw` keep its own scores
+    pub fn with_score<T: Score>(self) -> FastDTW<'a, D, T> {
+        FastDTW {
+            distance: self.distance,
+            radius: self.radius,
+            min_size: self.min_size,
+            default_dtw: self.default_dtw,
+            storage: self.storage,
+            traceback: self.traceback,
+            tie: self.tie,
+            score: std::marker::PhantomData,
+        }
+    }
 
-            if warpedi > lastwarpedi {
-                currenti += blocksize;
-            }
+    /// Keeps the matrices of the windowed levels in `storage`, see `StandardDTW::with_storage`.
+    /// The levels of `default_dtw` keep its own storage
+    pub fn with_storage(mut self, storage: Storage) -> Self {
+        self.storage = storage;
+        self
+    }
 
-            if warpedj > lastwarpedj {
-                currentj += blocksize;
-            }
+    /// The windowed levels follow the recorded moves, see `StandardDTW::with_traceback`
+    pub fn with_traceback(mut self) -> Self {
+        self.traceback = true;
+        self
+    }
 
-            if (warpedj > lastwarpedj) && (warpedi > lastwarpedi) {
-                dynamic_window.expand(currenti - 1, currentj);
-                dynamic_window.expand(currenti, currentj - 1);
-            }
+    /// The move of the paths of the windowed levels on ties, see `StandardDTW::with_tie_break`.
+    /// The levels of `default_dtw` keep its own policy
+    pub fn with_tie_break(mut self, tie: TieBreak) -> Self {
+        self.tie = tie;
+        self
+    }
 
-            for x in 0..blocksize {
-                dynamic_window.expand(currenti + x, currentj);
-                dynamic_window.expand(currenti + x, currentj + blocksize - 1);
+    /// Projects the path of the halved traces onto the full ones. Each cell of the coarse path
+    /// covers a 2x2 block of the finer matrix, then the blocks are widened by `radius` cells
+    fn expand(ops: &[OP], radius: usize, len1: usize, len2: usize) -> DynamicWindow {
+        let mut projected = DynamicWindow::new(len1 + 1, len2);
+
+        // The path does not include its last cell, the end of the halved matrix
+        let end = (len1.div_ceil(2), len2.div_ceil(2));
+        for &(i, j) in ops.iter().chain(std::iter::once(&end)) {
+            let cols = ((2 * j).saturating_sub(1), (2 * j).min(len2));
+            for row in (2 * i).saturating_sub(1)..=(2 * i).min(len1) {
+                projected.expand(row, cols.0);
+                projected.expand(row, cols.1);
             }
-
-            lastwarpedi = warpedi;
-            lastwarpedj = warpedj;
-        }
-
-        if len2 % 2 == 1 && len2 > 0 {
-            dynamic_window.replicate_last_row();
         }
 
-        let mut scaled = DynamicWindow::new(len2 + 1, len1);
-
-        for i in 0..len2 + 1 {
-            let min_col = dynamic_window.get_min(i);
-            let max_col = dynamic_window.get_max(i);
-
-            match (min_col, max_col) {
-                (Some(min_col), Some(max_col)) => {
-                    let up_row = 0.max(i as i32 - radius as i32);
-                    let down_row = len1.min(i + radius);
-
-                    scaled.expand(i, min_col);
-                    scaled.expand(i, max_col);
-
-                    let (sub, s) = min_col.overflowing_sub(radius);
-
-                    if s {
-                        scaled.expand(i, 0);
-                    } else {
-                        scaled.expand(i, sub);
-                    }
-
-                    let (val, s) = max_col.overflowing_add(radius);
-                    if s {
-                        scaled.expand(i, len1);
-                    } else {
-                        scaled.expand(i, len1.min(val));
-                    }
-
-                    for j in (up_row as usize..=i).rev() {
-                        scaled.expand(j, max_col);
-                        scaled.expand(j, scaled.get_min(j).or(None).unwrap());
-                    }
-
-                    for j in i..=down_row {
-                        scaled.expand(j, min_col);
-                        scaled.expand(j, scaled.get_max(j).or(None).unwrap());
-                    }
-                }
-                (_, _) => {
-                    scaled.replicate_prev(i);
+        let mut window = DynamicWindow::new(len1 + 1, len2);
+        for i in 0..=len1 {
+            let rows = i.saturating_sub(radius)..=i.saturating_add(radius).min(len1);
+            for row in rows {
+                if let (Some(min), Some(max)) = projected.get_limits(row) {
+                    window.expand(i, min.saturating_sub(radius));
+                    window.expand(i, max.saturating_add(radius).min(len2));
                 }
             }
-
-            //scaled.set(i, max_col.overflowing_add(radius).min(len1).0);
         }
 
-        scaled.clone()
+        window
     }
 }
 
-impl DTW for FastDTW<'_> {
-    fn calculate(&self, chain1: Box<dyn Accesor>, chain2: Box<dyn Accesor>) -> DTWResult {
-        if chain1.size() <= self.min_size || chain2.size() <= self.min_size {
+impl<D: DTW, S: Score> DTW for FastDTW<'_, D, S> {
+    fn calculate<A, B>(&self, chain1: &A, chain2: &B) -> DTWResult
+    where
+        A: Accesor + ?Sized,
+        B: Accesor + ?Sized,
+    {
+        // Traces of one token can not be halved any further
+        let min_size = self.min_size.max(1);
+        if chain1.size() <= min_size || chain2.size() <= min_size {
             log::info!(
                 "Min trace size reached in FastDTW {} {}",
                 chain1.size(),
@@ -718,33 +2224,38 @@ impl DTW for FastDTW<'_> {
             return r;
         }
 
-        let chain1_half = chain1.get_half();
-        let chain2_half = chain2.get_half();
+        let (chain1_half, chain2_half) = match (chain1.get_half(), chain2.get_half()) {
+            (Ok(chain1_half), Ok(chain2_half)) => (chain1_half, chain2_half),
+            (Err(e), _) | (_, Err(e)) => {
+                // Without the coarse level, the best we can do is the exact DTW
+                log::warn!("Could not halve the traces, running the basic DTW: {}", e);
+                return self.default_dtw.calculate(chain1, chain2);
+            }
+        };
 
         // TODO move this to a queue. Yet, we do not have that many stack calls, log(n) at most
-        let (_, path) = self.calculate(chain1_half, chain2_half);
+        let (_, path) = self.calculate(&*chain1_half, &*chain2_half);
 
         // Expand the path
 
-        if let Some((path, mini, minj)) = path {
+        if let Some((path, _, _)) = path {
             log::info!("Windowed fdtw {} {}", chain1.size(), chain2.size());
-            let opcount = path.len();
-            let window = FastDTW::expand(
-                path,
-                self.radius,
-                chain1.size(),
-                chain2.size(),
-                opcount,
-                mini,
-                minj,
-            );
-
-            // log::info!("{:?}", window);
-            return WindowedDTW::new(window, self.distance).calculate(chain1, chain2);
+            let window = Self::expand(&path, self.radius, chain1.size(), chain2.size());
+
+            let windowed = WindowedDTW::new(window, self.distance)
+                .with_score::<S>()
+                .with_storage(self.storage.clone())
+                .with_tie_break(self.tie);
+            return if self.traceback {
+                windowed.with_traceback().calculate(chain1, chain2)
+            } else {
+                windowed.calculate(chain1, chain2)
+            };
         }
 
-        // expand
-        panic!("This point should not be reached")
+        // The default DTW gives no path, e.g. FixedDTW without alignment
+        log::warn!("The coarse level has no warping path, running the basic DTW");
+        self.default_dtw.calculate(chain1, chain2)
     }
 }
 
@@ -757,9 +2268,9 @@ mod tests {
         assert_eq!(2 + 2, 4);
         let distance = STRACDistance::default();
         let dtw = StandardDTW::new(&distance);
-        let chain1 = Box::new(vec![1, 2, 3]);
-        let chain2 = Box::new(vec![1, 2, 3]);
-        let (result, ops) = dtw.calculate(chain1, chain2);
+        let chain1 = vec![1, 2, 3];
+        let chain2 = vec![1, 2, 3];
+        let (result, ops) = dtw.calculate(&chain1, &chain2);
 
         println!("{:?}", ops);
         assert_eq!(result, 0.0);
@@ -770,9 +2281,9 @@ mod tests {
         assert_eq!(2 + 2, 4);
         let distance = STRACDistance::default();
         let dtw = StandardDTW::new(&distance);
-        let chain1 = Box::new(vec![1, 2, 3, 5]);
-        let chain2 = Box::new(vec![1, 2, 4]);
-        let (result, ops) = dtw.calculate(chain1, chain2);
+        let chain1 = vec![1, 2, 3, 5];
+        let chain2 = vec![1, 2, 4];
+        let (result, ops) = dtw.calculate(&chain1, &chain2);
         println!("{:?}", ops);
         assert_eq!(result, 3.0);
     }
@@ -784,9 +2295,9 @@ mod tests {
         let dtw = StandardDTW::new(&distance);
 
         let fastdtw = FastDTW::new(&distance, 2, 100, &dtw);
-        let chain1 = Box::new(vec![1, 2, 3, 5, 1, 2, 3]);
-        let chain2 = Box::new(vec![1, 2, 4, 5, 6, 7, 8]);
-        let (result, ops) = fastdtw.calculate(chain1, chain2);
+        let chain1 = vec![1, 2, 3, 5, 1, 2, 3];
+        let chain2 = vec![1, 2, 4, 5, 6, 7, 8];
+        let (result, ops) = fastdtw.calculate(&chain1, &chain2);
         println!("{:?}", ops);
         assert_eq!(result, 8.0);
     }
@@ -798,7 +2309,7 @@ mod tests {
         let dtw = StandardDTW::new(&distance);
 
         let fastdtw = FastDTW::new(&distance, 2, 10, &dtw);
-        let chain2 = Box::new(vec![
+        let chain2 = vec![
             18446744071612399615,
             2692975965417482751,
             2676586395008836901,
@@ -865,8 +2376,8 @@ mod tests {
             18446743133734905125,
             18446744073694674943,
             18446508778221207551,
-        ]);
-        let chain1 = Box::new(vec![
+        ];
+        let chain1 = vec![
             18446744073709551615,
             2676827028518338559,
             18446743133734905125,
@@ -890,10 +2401,12 @@ mod tests {
             18446744073709551529,
             12225489209634929577,
             1873497191583055871,
-        ]);
-        let (result, ops) = fastdtw.calculate(chain1, chain2);
+        ];
+        let (result, ops) = fastdtw.calculate(&chain1, &chain2);
         println!("{:?}", ops);
-        assert_eq!(result, 73.0);
+        // The window is wide enough to contain the optimal path
+        assert_eq!(result, dtw.calculate(&chain1, &chain2).0);
+        assert_eq!(result, 75.0);
     }
 
     #[test]
@@ -903,7 +2416,7 @@ mod tests {
         let dtw = StandardDTW::new(&distance);
 
         let fastdtw = FastDTW::new(&distance, 2, 10, &dtw);
-        let chain2 = Box::new(vec![
+        let chain2 = vec![
             12184914412422823935,
             5044031582654955519,
             18374686479671601477,
@@ -917,8 +2430,8 @@ mod tests {
             2676586395008836901,
             18446744073709551615,
             18446744073709551615,
-        ]);
-        let chain1 = Box::new(vec![
+        ];
+        let chain1 = vec![
             6872398473367388159,
             2676586395008851807,
             2676586395008836901,
@@ -932,8 +2445,8 @@ mod tests {
             18446744073709551615,
             18446744073323675433,
             18446744073709551615,
-        ]);
-        let (result, ops) = fastdtw.calculate(chain1, chain2);
+        ];
+        let (result, ops) = fastdtw.calculate(&chain1, &chain2);
         println!("{:?}", ops);
         assert_eq!(result, 18.0);
     }
@@ -943,19 +2456,534 @@ mod tests {
         assert_eq!(2 + 2, 4);
         let distance = STRACDistance::default();
         let dtw = StandardDTW::new(&distance);
-        let chain1 = Box::new(vec![1, 2, 3, 5]);
-        let chain2 = Box::new(vec![1, 2, 4]);
-        let (result, ops) = dtw.calculate(chain1.clone(), chain2.clone());
+        let chain1 = vec![1, 2, 3, 5];
+        let chain2 = vec![1, 2, 4];
+        let (result, ops) = dtw.calculate(&chain1, &chain2);
 
-        let _ = FixedDTW::new(&distance);
-        let (result2, ops2) = dtw.calculate(chain1.clone(), chain2.clone());
+        let fixed = FixedDTW::new(&distance);
+        let (result2, ops2) = fixed.calculate(&chain1, &chain2);
 
-        let _ = UnsafeDTW::new(&distance);
-        let (result3, ops3) = dtw.calculate(chain1.clone(), chain2.clone());
+        let unsafedtw = UnsafeDTW::new(&distance);
+        let (result3, ops3) = unsafedtw.calculate(&chain1, &chain2);
 
         assert_eq!(result, result2);
         assert_eq!(result2, result3);
+        // The memoized version does not keep the matrix to get the path
+        assert_eq!(ops2, None);
+        assert_eq!(ops, ops3);
+    }
+
+    #[test]
+    fn test_borrowed_traces() {
+        let distance = STRACDistance::default();
+        let dtw = StandardDTW::new(&distance);
+        let chain1: Vec<TokenID> = vec![1, 2, 3, 5];
+        let chain2: Vec<TokenID> = vec![1, 2, 4];
+
+        // Slices, vectors and trait objects give the same result and the traces can be reused
+        let (result, ops) = dtw.calculate(&chain1, &chain2);
+        let (result2, ops2) = dtw.calculate(&chain1[..], &chain2[..]);
+        let boxed: Box<dyn Accesor> = Box::new(chain2.clone());
+        let (result3, ops3) = dtw.calculate(&chain1, &*boxed);
+
+        assert_eq!(result, 3.0);
+        assert_eq!(result, result2);
+        assert_eq!(result, result3);
         assert_eq!(ops, ops2);
-        assert_eq!(ops2, ops3);
+        assert_eq!(ops, ops3);
+
+        let fixed = FixedDTW::new(&distance);
+        assert_eq!(fixed.calculate(&chain1[..], &chain2).0, result);
+    }
+
+    #[test]
+    fn test_fixed_alignment() {
+        use rand::{Rng, SeedableRng};
+
+        let distance = STRACDistance::default();
+        let standard = StandardDTW::new(&distance);
+        let fixed = FixedDTW::with_alignment(&distance);
+        assert!(fixed.can_provide_alignment());
+        assert!(!FixedDTW::new(&distance).can_provide_alignment());
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        for _ in 0..200 {
+            let len1 = rng.gen_range(0..40);
+            let len2 = rng.gen_range(0..40);
+            let chain1: Vec<TokenID> = (0..len1).map(|_| rng.gen_range(0..4)).collect();
+            let chain2: Vec<TokenID> = (0..len2).map(|_| rng.gen_range(0..4)).collect();
+
+            let (expected, _) = standard.calculate(&chain1, &chain2);
+            let (cost, path) = fixed.calculate(&chain1, &chain2);
+            let (path, mini, minj) = path.unwrap();
+
+            assert_eq!(cost, expected);
+            let end = (chain1.len(), chain2.len());
+            assert_eq!(
+                path_cost(&distance, &chain1, &chain2, end, &path),
+                Some(cost)
+            );
+            if !path.is_empty() {
+                assert_eq!((mini, minj), (0, 0));
+            }
+        }
+    }
+
+    #[test]
+    fn test_fixed_alignment_swapped() {
+        let distance = STRACDistance::default();
+        let fixed = FixedDTW::with_alignment(&distance);
+        let chain1 = vec![1, 2, 3, 5, 1, 2, 3, 4, 4];
+        let chain2 = vec![1, 2, 4, 5];
+
+        let (cost, path) = fixed.calculate(&chain1, &chain2);
+        let (path, _, _) = path.unwrap();
+        assert_eq!(cost, fixed.calculate(&chain2, &chain1).0);
+        let end = (chain1.len(), chain2.len());
+        assert_eq!(
+            path_cost(&distance, &chain1, &chain2, end, &path),
+            Some(cost)
+        );
+        assert_eq!(path.last(), Some(&(0, 0)));
+    }
+
+    #[test]
+    fn test_banded() {
+        use rand::{Rng, SeedableRng};
+
+        let distance = STRACDistance::default();
+        let standard = StandardDTW::new(&distance);
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+        for _ in 0..200 {
+            let len1 = rng.gen_range(0..30);
+            let len2 = rng.gen_range(0..30);
+            let chain1: Vec<TokenID> = (0..len1).map(|_| rng.gen_range(0..4)).collect();
+            let chain2: Vec<TokenID> = (0..len2).map(|_| rng.gen_range(0..4)).collect();
+            let (expected, _) = standard.calculate(&chain1, &chain2);
+
+            let windows = [
+                DynamicWindow::sakoe_chiba(len1, len2, 0),
+                DynamicWindow::sakoe_chiba(len1, len2, 3),
+                DynamicWindow::itakura(len1, len2, 1.0),
+                DynamicWindow::itakura(len1, len2, 2.0),
+            ];
+            for window in windows {
+                let (cost, path) =
+                    WindowedDTW::new(window.clone(), &distance).calculate(&chain1, &chain2);
+                let (path, _, _) = path.unwrap();
+
+                // The band always contains a path, which can not beat the exact DTW
+                assert!(cost.is_finite());
+                assert!(cost >= expected);
+                let end = (chain1.len(), chain2.len());
+                assert_eq!(
+                    path_cost(&distance, &chain1, &chain2, end, &path),
+                    Some(cost)
+                );
+                assert!(path.iter().all(|&(i, j)| window.is_in_range(i, j)));
+                assert!(path.last().is_none_or(|cell| *cell == (0, 0)));
+            }
+
+            // Wide enough bands are the exact DTW
+            let window = DynamicWindow::sakoe_chiba(len1, len2, len1.max(len2));
+            let (cost, _) = WindowedDTW::new(window, &distance).calculate(&chain1, &chain2);
+            assert_eq!(cost, expected);
+
+            // Steeper slopes give larger parallelograms, but the ends are always pinned
+            let itakura = |slope| {
+                let window = DynamicWindow::itakura(len1, len2, slope);
+                WindowedDTW::new(window, &distance)
+                    .calculate(&chain1, &chain2)
+                    .0
+            };
+            assert!(itakura(4.0) <= itakura(2.0));
+        }
+    }
+
+    #[test]
+    fn test_window_ranges() {
+        let distance = STRACDistance::default();
+        let chain1 = vec![1, 2, 3, 4];
+        let chain2 = vec![2, 3, 4, 1];
+
+        // Only the diagonal, every pair is a mismatch
+        let ranges = [(0, 0), (1, 1), (2, 2), (3, 3), (4, 4)];
+        let window = DynamicWindow::from_ranges(&ranges, chain2.len());
+        let (cost, _) = WindowedDTW::new(window, &distance).calculate(&chain1, &chain2);
+        assert_eq!(cost, 12.0);
+
+        // The shift by one is allowed
+        let ranges = [(0, 1), (0, 2), (1, 3), (2, 4), (3, 4)];
+        let window = DynamicWindow::from_ranges(&ranges, chain2.len());
+        let (cost, path) = WindowedDTW::new(window, &distance).calculate(&chain1, &chain2);
+        assert_eq!(cost, 2.0);
+        assert_eq!(
+            path.unwrap().0,
+            vec![(4, 3), (3, 2), (2, 1), (1, 0), (0, 0)]
+        );
+
+        // Rows out of the window can not be crossed
+        let window = DynamicWindow::from_ranges(&ranges[..3], chain2.len());
+        let (cost, _) = WindowedDTW::new(window, &distance).calculate(&chain1, &chain2);
+        assert!(cost.is_infinite());
+    }
+
+    #[test]
+    fn test_affine_gaps() {
+        let mut distance = STRACDistance::new(1.0, 3.0, 0.0);
+        distance.gap_open = 5.0;
+        let chain1 = vec![1, 2, 3, 4];
+        // The same three insertions, in one block and scattered
+        let block = vec![1, 9, 9, 9, 2, 3, 4];
+        let scattered = vec![1, 9, 2, 9, 3, 9, 4];
+
+        let standard = StandardDTW::new(&distance);
+        let fixed = FixedDTW::with_alignment(&distance);
+        let window = DynamicWindow::sakoe_chiba(chain1.len(), block.len(), 10);
+        let windowed = WindowedDTW::new(window, &distance);
+
+        // The block is a single run, opened once
+        for (cost, path) in [
+            standard.calculate(&chain1, &block),
+            fixed.calculate(&chain1, &block),
+            windowed.calculate(&chain1, &block),
+        ] {
+            assert_eq!(cost, 8.0);
+            assert!(path.is_some());
+        }
+        let (_, path) = fixed.calculate(&chain1, &block);
+        assert_eq!(
+            path.unwrap().0,
+            vec![(3, 6), (2, 5), (1, 4), (1, 3), (1, 2), (1, 1), (0, 0)]
+        );
+
+        // The moves give the same block as the divide and conquer path
+        let traceback = StandardDTW::new(&distance).with_traceback();
+        assert_eq!(
+            traceback.calculate(&chain1, &block),
+            fixed.calculate(&chain1, &block)
+        );
+
+        let (cost, _) = standard.calculate(&chain1, &scattered);
+        assert!(cost > 8.0);
+        assert_eq!(cost, fixed.calculate(&chain1, &scattered).0);
+
+        // Without opening cost the gaps are linear again
+        distance.gap_open = 0.0;
+        let standard = StandardDTW::new(&distance);
+        assert_eq!(standard.calculate(&chain1, &block).0, 3.0);
+        assert_eq!(standard.calculate(&chain1, &scattered).0, 3.0);
+    }
+
+    #[test]
+    fn test_tie_break() {
+        // The mismatch costs as much as a gap in each trace
+        let distance = STRACDistance::new(1.0, 2.0, 0.0);
+        let (chain1, chain2) = (vec![1], vec![2]);
+
+        for (tie, path) in [
+            (TieBreak::Match, vec![(0, 0)]),
+            (TieBreak::GapIn1, vec![(1, 0), (0, 0)]),
+            (TieBreak::GapIn2, vec![(0, 1), (0, 0)]),
+        ] {
+            let expected = (2.0, Some((path, 0, 0)));
+            let standard = StandardDTW::new(&distance).with_tie_break(tie);
+            assert_eq!(standard.calculate(&chain1, &chain2), expected);
+            let traceback = StandardDTW::new(&distance)
+                .with_tie_break(tie)
+                .with_traceback();
+            assert_eq!(traceback.calculate(&chain1, &chain2), expected);
+            let fixed = FixedDTW::with_alignment(&distance).with_tie_break(tie);
+            assert_eq!(fixed.calculate(&chain1, &chain2), expected);
+
+            let (_, path) = expected;
+            let path = path.unwrap().0;
+            assert_eq!(
+                path_cost(&distance, &chain1, &chain2, (1, 1), &path),
+                Some(2.0)
+            );
+        }
+
+        // Paths that skip cells are not warping paths
+        let skips = [(0, 0)];
+        assert_eq!(
+            path_cost(&distance, &chain1, &vec![2, 3], (1, 2), &skips),
+            None
+        );
+        assert_eq!(path_cost(&distance, &chain1, &chain2, (2, 1), &[]), None);
+    }
+
+    #[test]
+    fn test_insertion_deletion_costs() {
+        let mut distance = STRACDistance::new(1.0, 10.0, 0.0);
+        distance.insertion_cost = Some(2.0);
+        distance.deletion_cost = Some(5.0);
+        let old = vec![1, 2, 3];
+        let new = vec![1, 2, 7, 7, 3];
+
+        // Two insertions one way, two deletions the other way
+        for (chain1, chain2, expected) in [(&old, &new, 4.0), (&new, &old, 10.0)] {
+            let standard = StandardDTW::new(&distance).calculate(chain1, chain2);
+            let fixed = FixedDTW::new(&distance).calculate(chain1, chain2);
+            let aligned = FixedDTW::with_alignment(&distance).calculate(chain1, chain2);
+            let window = DynamicWindow::sakoe_chiba(chain1.len(), chain2.len(), 5);
+            let windowed = WindowedDTW::new(window, &distance).calculate(chain1, chain2);
+
+            assert_eq!(standard.0, expected);
+            assert_eq!(fixed.0, expected);
+            assert_eq!(aligned.0, expected);
+            assert_eq!(windowed.0, expected);
+        }
+    }
+
+    #[test]
+    fn test_subsequence() {
+        let distance = STRACDistance::new(1.0, 3.0, 0.0);
+        let query = vec![4, 5, 6];
+        // The second occurrence has a token inserted
+        let reference = vec![1, 2, 4, 5, 9, 6, 3, 4, 5, 6, 7];
+
+        let found = StandardDTW::new(&distance).search(&query, &reference);
+        assert_eq!((found.start, found.end, found.cost), (7, 10, 0.0));
+        assert_eq!(found.path.unwrap().0, [(2, 9), (1, 8), (0, 7)]);
+
+        let aligned = FixedDTW::with_alignment(&distance).search(&query, &reference);
+        assert_eq!((aligned.start, aligned.end, aligned.cost), (7, 10, 0.0));
+        assert_eq!(aligned.path.unwrap().0, [(2, 9), (1, 8), (0, 7)]);
+
+        // Without the exact occurrence a gap is paid. Inserting the 9 costs as much as deleting
+        // the 6, and the match that ends first wins
+        let found = FixedDTW::new(&distance).search(&query, &reference[..7]);
+        assert_eq!((found.start, found.end, found.cost), (2, 4, 1.0));
+
+        // The global alignment pays for the whole reference
+        assert_eq!(
+            StandardDTW::new(&distance).calculate(&query, &reference).0,
+            8.0
+        );
+        let (cost, path) = StandardDTW::new(&distance)
+            .subsequence()
+            .calculate(&query, &reference);
+        assert_eq!((cost, path.unwrap().2), (0.0, 7));
+    }
+
+    #[test]
+    fn test_free_gaps() {
+        let distance = STRACDistance::new(1.0, 3.0, 0.0);
+        let prefix = vec![1, 2, 3];
+        let trace = vec![1, 2, 3, 4, 5, 6];
+        let noisy = vec![9, 9, 9, 1, 2, 3, 4, 5, 6];
+
+        // The first trace is a prefix of the second one
+        let free = FreeGaps {
+            end2: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            StandardDTW::new(&distance).calculate(&prefix, &trace).0,
+            3.0
+        );
+        let (cost, path) = StandardDTW::new(&distance)
+            .with_free_gaps(free)
+            .calculate(&prefix, &trace);
+        assert_eq!((cost, path.unwrap().0), (0.0, vec![(2, 2), (1, 1), (0, 0)]));
+        let fixed = FixedDTW::with_alignment(&distance).with_free_gaps(free);
+        assert_eq!(fixed.calculate(&prefix, &trace).0, 0.0);
+
+        // The startup noise of the second trace is ignored
+        let free = FreeGaps {
+            start2: true,
+            ..Default::default()
+        };
+        let (cost, path) = StandardDTW::new(&distance)
+            .with_free_gaps(free)
+            .calculate(&trace, &noisy);
+        assert_eq!((cost, path.unwrap().2), (0.0, 3));
+        let fixed = FixedDTW::with_alignment(&distance).with_free_gaps(free);
+        let (cost, path) = fixed.calculate(&trace, &noisy);
+        assert_eq!((cost, path.unwrap().2), (0.0, 3));
+        // But not its end
+        assert_eq!(fixed.calculate(&prefix, &noisy).0, 3.0);
+
+        // The same gaps of the traces in the other order
+        let (cost, path) = FixedDTW::with_alignment(&distance)
+            .with_free_gaps(free.transposed())
+            .calculate(&noisy, &trace);
+        assert_eq!((cost, path.unwrap().1), (0.0, 3));
+    }
+
+    #[test]
+    fn test_occurrences() {
+        let distance = STRACDistance::new(1.0, 3.0, 0.0);
+        // A loop body and a recorded execution with three iterations, the last one with a token
+        // inserted
+        let body = vec![1, 2, 3];
+        let execution = vec![0, 1, 2, 3, 1, 2, 3, 5, 1, 2, 4, 3, 0];
+
+        let found = FixedDTW::new(&distance).occurrences(&body, &execution, 1.0);
+        let found: Vec<_> = found.iter().map(|m| (m.start, m.end, m.cost)).collect();
+        assert_eq!(found, [(1, 4, 0.0), (4, 7, 0.0), (8, 10, 1.0)]);
+
+        let exact = FixedDTW::new(&distance).occurrences(&body, &execution, 0.0);
+        assert_eq!(exact.len(), 2);
+        assert!(FixedDTW::new(&distance)
+            .occurrences(&body, &execution[..3], 0.0)
+            .is_empty());
+
+        let aligned = FixedDTW::with_alignment(&distance).occurrences(&body, &execution, 1.0);
+        let paths: Vec<_> = aligned.into_iter().map(|m| m.path.unwrap().0).collect();
+        assert_eq!(paths[0], [(2, 3), (1, 2), (0, 1)]);
+        assert_eq!(paths[1], [(2, 6), (1, 5), (0, 4)]);
+        assert_eq!(paths[2], [(2, 10), (1, 9), (0, 8)]);
+    }
+
+    #[test]
+    fn test_threshold() {
+        let distance = STRACDistance::new(1.0, 3.0, 0.0);
+        let fixed = FixedDTW::new(&distance);
+
+        // Two tokens more, and a token that the other trace does not have
+        let chain1 = vec![1, 2, 3, 4, 5];
+        let chain2 = vec![1, 2, 6];
+        assert_eq!(fixed.calculate(&chain1, &chain2).0, 4.0);
+        assert_eq!(fixed.lower_bound(&chain1, &chain2), 3.0);
+        assert_eq!(fixed.lower_bound(&chain2, &chain1), 3.0);
+
+        // Different tokens in the same amounts, only the rows can tell
+        let chain1 = vec![1, 1, 1, 2, 2, 2];
+        let chain2 = vec![2, 2, 2, 1, 1, 1];
+        assert_eq!(fixed.lower_bound(&chain1, &chain2), 0.0);
+        assert_eq!(fixed.calculate(&chain1, &chain2).0, 6.0);
+        for (threshold, cost) in [(5.0, f64::INFINITY), (6.0, 6.0), (7.0, 6.0)] {
+            let fixed = FixedDTW::with_alignment(&distance).with_threshold(threshold);
+            let (found, path) = fixed.calculate(&chain1, &chain2);
+            assert_eq!((found, path.is_some()), (cost, cost.is_finite()));
+        }
+
+        // Free gaps can skip anything
+        let fixed = FixedDTW::new(&distance).subsequence();
+        assert_eq!(fixed.lower_bound(&vec![7, 8], &vec![1, 2, 3]), 0.0);
+
+        // Too many pairs of tokens, only the lengths are bounded
+        let fixed = FixedDTW::new(&distance);
+        let (chain1, chain2): (Vec<_>, Vec<_>) = (0..1025).map(|i| (i, i + 1025)).unzip();
+        assert_eq!(fixed.lower_bound(&chain1, &chain2), 0.0);
+        assert_eq!(fixed.lower_bound(&chain1[..1000], &chain2[..1000]), 1000.0);
+
+        // The first row is above the threshold, but the matches after it are negative
+        let distance = STRACDistance::new(1.0, 3.0, -1.0);
+        let (chain1, chain2) = (vec![9, 1, 1, 1, 1], vec![8, 1, 1, 1, 1]);
+        let fixed = FixedDTW::new(&distance).with_threshold(0.0);
+        assert_eq!(fixed.lower_bound(&chain1, &chain2), f64::NEG_INFINITY);
+        assert_eq!(fixed.calculate(&chain1, &chain2).0, -2.0);
+    }
+
+    #[test]
+    fn test_wavefront() {
+        let distance = STRACDistance::default();
+        let chain1 = (0..3000).map(|i| i % 7).collect::<Vec<_>>();
+        let chain2 = (0..2000).map(|i| (i * 3) % 11).collect::<Vec<_>>();
+
+        let (expected, _) = FixedDTW::new(&distance).calculate(&chain1, &chain2);
+        for chunk in [64, 1000, 4096] {
+            let wavefront = WavefrontDTW::new(&distance).with_chunk(chunk);
+            assert_eq!(wavefront.calculate(&chain1, &chain2), (expected, None));
+            assert_eq!(wavefront.calculate(&chain2, &chain1), (expected, None));
+        }
+        assert!(!WavefrontDTW::new(&distance).can_provide_alignment());
+
+        let empty: Vec<TokenID> = vec![];
+        let wavefront = WavefrontDTW::new(&distance);
+        assert_eq!(wavefront.calculate(&empty, &chain2[..3]), (3.0, None));
+        assert_eq!(wavefront.calculate(&empty, &empty), (0.0, None));
+    }
+
+    #[test]
+    fn test_distance_not_sync() {
+        // Only the wavefront needs distances shared between threads
+        struct Counting(std::cell::Cell<usize>);
+
+        impl Distance for Counting {
+            fn distance(&self, a: TokenID, b: TokenID) -> f64 {
+                self.0.set(self.0.get() + 1);
+                if a == b {
+                    0.0
+                } else {
+                    3.0
+                }
+            }
+
+            fn gap_cost(&self) -> f64 {
+                1.0
+            }
+        }
+
+        let distance = Counting(std::cell::Cell::new(0));
+        let (cost, _) = StandardDTW::new(&distance).calculate(&vec![1, 2, 3], &vec![1, 3]);
+        assert_eq!(cost, 1.0);
+        // Every pair of tokens is compared at least once
+        assert!(distance.0.get() >= 6);
+    }
+
+    #[test]
+    fn test_scores() {
+        let distance = STRACDistance::new(1.0, 20000.0, 0.0);
+        let chain1 = vec![1, 2, 3, 4];
+        let chain2 = vec![5, 6, 7, 8];
+
+        // The gaps are cheaper than the mismatches
+        let (cost, path) = StandardDTW::new(&distance).calculate(&chain1, &chain2);
+        let small = StandardDTW::new(&distance).with_score::<u16>();
+        assert_eq!(small.calculate(&chain1, &chain2), (cost, path));
+        assert_eq!(cost, 8.0);
+
+        // The sum of the mismatches does not fit in a u16, it saturates and is aligned again with
+        // f64 scores
+        let distance = STRACDistance::new(30000.0, 20000.0, 0.0);
+        let expected = StandardDTW::new(&distance).calculate(&chain1, &chain2);
+        assert_eq!(expected.0, 80000.0);
+        let small = StandardDTW::new(&distance).with_score::<u16>();
+        assert_eq!(small.calculate(&chain1, &chain2), expected);
+        let small = StandardDTW::new(&distance)
+            .with_score::<u16>()
+            .with_traceback();
+        assert_eq!(small.calculate(&chain1, &chain2).0, 80000.0);
+        let wide = StandardDTW::new(&distance).with_score::<u32>();
+        assert_eq!(wide.calculate(&chain1, &chain2), expected);
+
+        // Negative costs are not integer scores either
+        let distance = STRACDistance::new(1.0, 3.0, -1.0);
+        let expected = StandardDTW::new(&distance).calculate(&chain1, &chain1);
+        assert_eq!(expected.0, -4.0);
+        let integer = StandardDTW::new(&distance).with_score::<i32>();
+        assert_eq!(integer.calculate(&chain1, &chain1), expected);
+
+        // Fractional costs are not integer scores, the engines align them with f64 ones
+        let mut distance = STRACDistance::new(0.5, 3.0, 0.0);
+        let expected = StandardDTW::new(&distance).calculate(&chain1, &chain2);
+        assert_eq!(expected.0, 4.0);
+        let integer = StandardDTW::new(&distance).with_score::<i32>();
+        assert_eq!(integer.calculate(&chain1, &chain2), expected);
+        let integer = UnsafeDTW::new(&distance).with_score::<u16>();
+        assert_eq!(integer.calculate(&chain1, &chain2), expected);
+        let mut window = DynamicWindow::new(chain1.len() + 1, chain2.len());
+        window.init(chain1.len() + 1);
+        let integer = WindowedDTW::new(window, &distance).with_score::<u32>();
+        assert_eq!(integer.calculate(&chain1, &chain2), expected);
+
+        // Also a fractional opening cost, the path is the one of the affine gaps
+        distance.gap_cost = 1.0;
+        distance.gap_open = 0.5;
+        let (cost, path) = StandardDTW::new(&distance)
+            .with_score::<u16>()
+            .calculate(&chain1, &chain2);
+        let (path, _, _) = path.unwrap();
+        let end = (chain1.len(), chain2.len());
+        assert_eq!(cost, 9.0);
+        assert_eq!(
+            path_cost(&distance, &chain1, &chain2, end, &path),
+            Some(cost)
+        );
     }
 }
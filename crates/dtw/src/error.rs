@@ -0,0 +1,85 @@
+//! Errors of the dtw crate
+//!
+//!
+
+use crate::dtw::TokenID;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum DtwError {
+    /// Reading, writing or mapping a file failed
+    Io(std::io::Error),
+    /// The file does not start with the magic bytes of its format
+    BadMagic([u8; 4]),
+    /// The trace bin was written by a newer version of the tool
+    UnsupportedVersion(u32),
+    /// The header declares a token width that is not 1, 2, 4 or 8
+    UnsupportedTokenWidth(usize),
+    /// The header declares more tokens than a file can hold
+    TooManyTokens(u64),
+    /// The file is shorter than its header says, in bytes
+    Truncated { expected: u64, found: u64 },
+    /// The tokens do not match the checksum of the header
+    ChecksumMismatch { expected: u64, found: u64 },
+    /// The vocabulary is malformed or does not belong to the trace
+    Vocabulary(String),
+    /// The id is not in the vocabulary of the encoder
+    UnknownTokenId(TokenID),
+    /// A line of a cost matrix file is malformed, lines start at 1
+    CostMatrix { line: usize, reason: String },
+    /// A line of a rules file is malformed, lines start at 1
+    Rules { line: usize, reason: String },
+}
+
+pub type Result<T> = std::result::Result<T, DtwError>;
+
+impl fmt::Display for DtwError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DtwError::Io(e) => write!(f, "I/O error: {}", e),
+            DtwError::BadMagic(found) => write!(f, "bad magic bytes {:?}", found),
+            DtwError::UnsupportedVersion(version) => {
+                write!(f, "unsupported trace bin version {}", version)
+            }
+            DtwError::UnsupportedTokenWidth(width) => {
+                write!(f, "unsupported token width {}", width)
+            }
+            DtwError::TooManyTokens(count) => {
+                write!(f, "the trace bin declares too many tokens, {}", count)
+            }
+            DtwError::Truncated { expected, found } => write!(
+                f,
+                "truncated file, expected {} bytes but found {}",
+                expected, found
+            ),
+            DtwError::ChecksumMismatch { expected, found } => write!(
+                f,
+                "corrupted trace bin, expected checksum {:#x} but found {:#x}",
+                expected, found
+            ),
+            DtwError::Vocabulary(reason) => write!(f, "invalid vocabulary: {}", reason),
+            DtwError::UnknownTokenId(id) => write!(f, "unknown token id {}", id),
+            DtwError::CostMatrix { line, reason } => {
+                write!(f, "invalid cost matrix, line {}: {}", line, reason)
+            }
+            DtwError::Rules { line, reason } => {
+                write!(f, "invalid rules, line {}: {}", line, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DtwError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DtwError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for DtwError {
+    fn from(e: std::io::Error) -> Self {
+        DtwError::Io(e)
+    }
+}
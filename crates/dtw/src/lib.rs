@@ -1,4 +1,11 @@
+#[cfg(test)]
+mod differential;
+pub mod distance;
 pub mod dtw;
-#[cfg(target_arch = "x86_64")]
+pub mod error;
+pub mod matrix;
+#[cfg(unix)]
 pub mod mmap;
 pub mod parsing;
+pub mod score;
+pub mod simd;
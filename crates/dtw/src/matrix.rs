@@ -0,0 +1,353 @@
+//! Cost matrices of the engines that keep the whole matrix
+//!
+//! The cells are in a single allocation, row after row. It can be a temporary file mapped in
+//! memory, so the system writes to disk the parts of the matrices that do not fit in RAM.
+//!
+//! The warping path only needs the move that gave the cost of each cell, so the engines can
+//! record them in a `Traceback` instead, and keep only two rows of scores in `Rows`.
+
+use crate::error::Result;
+use std::path::PathBuf;
+
+/// Where the matrices of an engine are kept
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Storage {
+    #[default]
+    Memory,
+    /// A temporary file mapped in memory, in the directory or in the system temporary one. Only
+    /// on unix systems, like the mapped traces, elsewhere the matrices are kept in memory
+    Disk(Option<PathBuf>),
+}
+
+/// A matrix of `rows` by `columns` cells, indexed by row
+pub struct Matrix<S> {
+    rows: usize,
+    columns: usize,
+    cells: Cells<S>,
+}
+
+enum Cells<S> {
+    Memory(Vec<S>),
+    // The file is removed as soon as it is created, the mapping keeps it alive until unmapped
+    #[cfg(unix)]
+    Mapped {
+        ptr: *mut S,
+        len: usize,
+    },
+}
+
+impl<S: Copy> Matrix<S> {
+    /// A matrix in memory with every cell set to `value`
+    pub fn new(rows: usize, columns: usize, value: S) -> Self {
+        Matrix {
+            rows,
+            columns,
+            cells: Cells::Memory(vec![value; rows * columns]),
+        }
+    }
+
+    /// A matrix in `storage` with every cell set to `value`
+    pub fn with_storage(rows: usize, columns: usize, value: S, storage: &Storage) -> Result<Self> {
+        match storage {
+            Storage::Memory => Ok(Self::new(rows, columns, value)),
+            #[cfg(unix)]
+            Storage::Disk(dir) => Self::mapped(rows, columns, value, dir.as_deref()),
+            #[cfg(not(unix))]
+            Storage::Disk(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "matrices can only be mapped on unix systems",
+            )
+            .into()),
+        }
+    }
+
+    #[cfg(unix)]
+    fn mapped(
+        rows: usize,
+        columns: usize,
+        value: S,
+        dir: Option<&std::path::Path>,
+    ) -> Result<Self> {
+        let too_large =
+            || std::io::Error::new(std::io::ErrorKind::InvalidInput, "matrix too large to map");
+        let cells = rows.checked_mul(columns).ok_or_else(too_large)?;
+        // Empty mappings are not allowed
+        let len = cells
+            .max(1)
+            .checked_mul(std::mem::size_of::<S>())
+            .ok_or_else(too_large)?;
+
+        let file = match dir {
+            Some(dir) => tempfile::tempfile_in(dir)?,
+            None => tempfile::tempfile()?,
+        };
+        file.set_len(len as u64)?;
+        // The mapping is page aligned, so it is aligned for any cell
+        let ptr = crate::mmap::map_writable(&file, len)? as *mut S;
+
+        let mut matrix = Matrix {
+            rows,
+            columns,
+            cells: Cells::Mapped { ptr, len },
+        };
+        matrix.as_mut_slice().fill(value);
+        Ok(matrix)
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn columns(&self) -> usize {
+        self.columns
+    }
+
+    /// The cells, row after row
+    pub fn as_slice(&self) -> &[S] {
+        match &self.cells {
+            Cells::Memory(cells) => cells,
+            #[cfg(unix)]
+            Cells::Mapped { ptr, .. } => unsafe {
+                std::slice::from_raw_parts(*ptr, self.rows * self.columns)
+            },
+        }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [S] {
+        match &mut self.cells {
+            Cells::Memory(cells) => cells,
+            #[cfg(unix)]
+            Cells::Mapped { ptr, .. } => unsafe {
+                std::slice::from_raw_parts_mut(*ptr, self.rows * self.columns)
+            },
+        }
+    }
+}
+
+impl<S: Copy> std::ops::Index<usize> for Matrix<S> {
+    type Output = [S];
+
+    #[inline]
+    fn index(&self, row: usize) -> &[S] {
+        &self.as_slice()[row * self.columns..(row + 1) * self.columns]
+    }
+}
+
+impl<S: Copy> std::ops::IndexMut<usize> for Matrix<S> {
+    #[inline]
+    fn index_mut(&mut self, row: usize) -> &mut [S] {
+        let columns = self.columns;
+        &mut self.as_mut_slice()[row * columns..(row + 1) * columns]
+    }
+}
+
+impl<S> Drop for Matrix<S> {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Cells::Mapped { ptr, len } = self.cells {
+            crate::mmap::unmap(ptr as *const u8, len);
+        }
+    }
+}
+
+/// Move into a cell of the best path to it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Move {
+    /// The cell is the first one of the path
+    Start = 0,
+    Diagonal = 1,
+    /// From the previous row, a token of the first trace only
+    Up = 2,
+    /// From the previous column, a token of the second trace only
+    Left = 3,
+}
+
+impl Move {
+    const ALL: [Move; 4] = [Move::Start, Move::Diagonal, Move::Up, Move::Left];
+}
+
+/// Moves of the best paths, packed in 2 bits per cell. With affine gaps each cell takes 2 more
+/// bits, whether the runs of gaps that reach it from above and from the left go on before it.
+/// Either way, it is 16 or 32 times smaller than the `f64` scores of the same cells
+pub struct Traceback {
+    columns: usize,
+    affine: bool,
+    cells: Matrix<u8>,
+}
+
+impl Traceback {
+    /// Moves of a matrix of `rows` by `columns` cells in `storage`, all of them `Move::Start`
+    pub fn with_storage(
+        rows: usize,
+        columns: usize,
+        affine: bool,
+        storage: &Storage,
+    ) -> Result<Self> {
+        let bytes = columns.div_ceil(Self::cells_per_byte(affine));
+        Ok(Traceback {
+            columns,
+            affine,
+            cells: Matrix::with_storage(rows, bytes, 0, storage)?,
+        })
+    }
+
+    fn cells_per_byte(affine: bool) -> usize {
+        if affine {
+            2
+        } else {
+            4
+        }
+    }
+
+    pub fn rows(&self) -> usize {
+        self.cells.rows()
+    }
+
+    pub fn columns(&self) -> usize {
+        self.columns
+    }
+
+    /// The runs of gaps are recorded, see `set`
+    pub fn is_affine(&self) -> bool {
+        self.affine
+    }
+
+    #[inline]
+    fn slot(&self, j: usize) -> (usize, usize) {
+        let per_byte = Self::cells_per_byte(self.affine);
+        (j / per_byte, (j % per_byte) * (8 / per_byte))
+    }
+
+    /// Records the move into `(i, j)`, and whether the runs of gaps from above and from the left
+    /// go on before the cell instead of starting in the previous one. The runs are ignored
+    /// without affine gaps
+    #[inline]
+    pub fn set(&mut self, (i, j): (usize, usize), step: Move, up_runs: bool, left_runs: bool) {
+        let (byte, shift) = self.slot(j);
+        let mut bits = step as u8;
+        if self.affine {
+            bits |= (up_runs as u8) << 2 | (left_runs as u8) << 3;
+        }
+        let width = 8 / Self::cells_per_byte(self.affine);
+        let cell = &mut self.cells[i][byte];
+        *cell = *cell & !(((1 << width) - 1) << shift) | bits << shift;
+    }
+
+    #[inline]
+    fn bits(&self, (i, j): (usize, usize)) -> u8 {
+        let (byte, shift) = self.slot(j);
+        self.cells[i][byte] >> shift
+    }
+
+    /// Move into `(i, j)`
+    #[inline]
+    pub fn get(&self, cell: (usize, usize)) -> Move {
+        Move::ALL[(self.bits(cell) & 0b11) as usize]
+    }
+
+    /// The run of `Move::Up` or `Move::Left` gaps into `(i, j)` goes on before the cell
+    #[inline]
+    pub fn runs(&self, cell: (usize, usize), step: Move) -> bool {
+        match step {
+            Move::Up if self.affine => self.bits(cell) & 0b100 != 0,
+            Move::Left if self.affine => self.bits(cell) & 0b1000 != 0,
+            _ => false,
+        }
+    }
+}
+
+/// The last two rows of a matrix, row `i` is kept where row `i - 2` was
+pub(crate) struct Rows<S> {
+    rows: [Vec<S>; 2],
+}
+
+impl<S: Copy> Rows<S> {
+    pub(crate) fn new(columns: usize, value: S) -> Self {
+        Rows {
+            rows: [vec![value; columns], vec![value; columns]],
+        }
+    }
+}
+
+impl<S> std::ops::Index<usize> for Rows<S> {
+    type Output = [S];
+
+    #[inline]
+    fn index(&self, row: usize) -> &[S] {
+        &self.rows[row % 2]
+    }
+}
+
+impl<S> std::ops::IndexMut<usize> for Rows<S> {
+    #[inline]
+    fn index_mut(&mut self, row: usize) -> &mut [S] {
+        &mut self.rows[row % 2]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matrix() {
+        let dir = tempfile::tempdir().unwrap();
+        let storages = [
+            Storage::Memory,
+            Storage::Disk(Some(dir.path().to_path_buf())),
+        ];
+        for storage in storages {
+            let mut matrix = Matrix::with_storage(3, 4, f64::INFINITY, &storage).unwrap();
+            assert_eq!((matrix.rows(), matrix.columns()), (3, 4));
+            assert!(matrix[2].iter().all(|cell| *cell == f64::INFINITY));
+
+            matrix[1][2] = 5.0;
+            matrix[2][0] = matrix[1][2] + 1.0;
+            assert_eq!(matrix.as_slice()[4 + 2], 5.0);
+            assert_eq!(
+                matrix[2],
+                [6.0, f64::INFINITY, f64::INFINITY, f64::INFINITY]
+            );
+        }
+
+        // The files are removed as soon as they are created
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+        let empty = Matrix::<u16>::with_storage(0, 5, 0, &Storage::Disk(None)).unwrap();
+        assert!(empty.as_slice().is_empty());
+
+        let missing = Storage::Disk(Some(dir.path().join("missing")));
+        assert!(Matrix::with_storage(2, 2, 0u32, &missing).is_err());
+    }
+
+    #[test]
+    fn test_traceback() {
+        for affine in [false, true] {
+            let mut moves = Traceback::with_storage(2, 7, affine, &Storage::Memory).unwrap();
+            assert_eq!((moves.rows(), moves.columns()), (2, 7));
+            assert_eq!(moves.get((1, 6)), Move::Start);
+
+            let steps = [Move::Diagonal, Move::Up, Move::Left, Move::Start];
+            for j in 0..7 {
+                moves.set((1, j), steps[j % 4], j % 2 == 0, j % 3 == 0);
+            }
+            // Overwrites the cell without touching its neighbours
+            moves.set((1, 2), Move::Up, false, true);
+
+            for j in 0..7 {
+                let (step, up_runs, left_runs) = match j {
+                    2 => (Move::Up, false, true),
+                    j => (steps[j % 4], j % 2 == 0, j % 3 == 0),
+                };
+                assert_eq!(moves.get((1, j)), step);
+                assert_eq!(moves.runs((1, j), Move::Up), affine && up_runs);
+                assert_eq!(moves.runs((1, j), Move::Left), affine && left_runs);
+                assert_eq!(moves.get((0, j)), Move::Start);
+            }
+        }
+
+        let mut rows = Rows::new(3, 0u16);
+        rows[4][1] = 2;
+        assert_eq!(rows[2], [0, 2, 0]);
+        assert_eq!(rows[3], [0, 0, 0]);
+    }
+}
@@ -1,134 +1,422 @@
 use crate::dtw::{Accesor, TokenID};
-use std::io::Write;
-use std::sync::Arc;
-use std::sync::Mutex;
+use crate::error::{DtwError, Result};
+use crate::parsing::{Fnv1a, TraceHeader, V1_HEADER_SIZE};
+use std::io::{Seek, Write};
+use std::path::{Path, PathBuf};
 
+/// Read-only mapping of a trace bin
+///
+/// The mapping is never written, so it can be shared between threads and read without locking.
 pub struct MMapWrapper {
     pub(crate) name: String,
-    // True if the mapped file is temporary
-    pub(crate) tmp: bool,
+    // Where the halves for FastDTW are written, the system one if not set
+    pub(crate) temp_dir: Option<PathBuf>,
     pub size: usize,
-    pub ptr: Arc<Mutex<*mut u8>>,
+    // Bytes before the first token
+    pub(crate) offset: usize,
+    // Tells how the tokens are stored
+    pub(crate) header: TraceHeader,
+    // Start and length of the whole mapping, header included
+    ptr: *const u8,
+    len: usize,
+    // True if the tokens can be read in place with their stored width
+    native: bool,
+}
+
+/// Tokens of a mapped trace read in place, as they are stored
+#[derive(Clone, Copy, Debug)]
+pub enum Tokens<'a> {
+    U8(&'a [u8]),
+    U16(&'a [u16]),
+    U32(&'a [u32]),
+    U64(&'a [u64]),
+}
+
+impl Tokens<'_> {
+    #[inline]
+    pub fn get(&self, idx: usize) -> TokenID {
+        match self {
+            Tokens::U8(tokens) => tokens[idx] as TokenID,
+            Tokens::U16(tokens) => tokens[idx] as TokenID,
+            Tokens::U32(tokens) => tokens[idx] as TokenID,
+            Tokens::U64(tokens) => tokens[idx] as TokenID,
+        }
+    }
+}
+
+// The mapping is read-only and owned by the wrapper until it is dropped
+unsafe impl Send for MMapWrapper {}
+unsafe impl Sync for MMapWrapper {}
+
+/// Maps the first `len` bytes of the file as read-only
+fn map(file: &std::fs::File, len: usize) -> Result<*const u8> {
+    if len == 0 {
+        // Empty mappings are not allowed
+        return Err(DtwError::Truncated {
+            expected: V1_HEADER_SIZE as u64,
+            found: 0,
+        });
+    }
+
+    let ptr = unsafe {
+        rustix::mm::mmap(
+            std::ptr::null_mut(),
+            len,
+            rustix::mm::ProtFlags::READ,
+            rustix::mm::MapFlags::PRIVATE,
+            file,
+            0,
+        )
+        .map_err(std::io::Error::from)?
+    };
+    Ok(ptr as *const u8)
+}
+
+/// Maps the first `len` bytes of the file to be read and written, the writes go to the file
+pub(crate) fn map_writable(file: &std::fs::File, len: usize) -> Result<*mut u8> {
+    let ptr = unsafe {
+        rustix::mm::mmap(
+            std::ptr::null_mut(),
+            len,
+            rustix::mm::ProtFlags::READ | rustix::mm::ProtFlags::WRITE,
+            rustix::mm::MapFlags::SHARED,
+            file,
+            0,
+        )
+        .map_err(std::io::Error::from)?
+    };
+    Ok(ptr as *mut u8)
+}
+
+/// Unmaps a mapping of `map` or `map_writable`
+pub(crate) fn unmap(ptr: *const u8, len: usize) {
+    if let Err(e) = unsafe { rustix::mm::munmap(ptr as *mut _, len) } {
+        log::warn!("File could not be unmapped: {}", e);
+    }
+}
+
+impl MMapWrapper {
+    /// Maps a trace bin, checking its header, length and checksum
+    pub fn open(from: &Path) -> Result<Self> {
+        let file = std::fs::File::open(from)?;
+        let basename = from
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        Self::from_file(&file, basename)
+    }
+
+    fn from_file(file: &std::fs::File, name: String) -> Result<Self> {
+        let len = file.metadata()?.len();
+        let len = usize::try_from(len).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "file too large to map")
+        })?;
+        let ptr = map(file, len)?;
+        let bytes = unsafe { std::slice::from_raw_parts(ptr, len) };
+
+        // `payload` and `tokens` trust the header, so all the tokens must be in the mapping
+        // before the wrapper is built
+        let header = match TraceHeader::parse(bytes).and_then(|header| {
+            header.check_len(len as u64)?;
+            Ok(header)
+        }) {
+            Ok(header) => header,
+            Err(e) => {
+                unmap(ptr, len);
+                return Err(e);
+            }
+        };
+        let offset = header.size();
+        // The wrapper takes care of unmapping from here on
+        let wrapper = MMapWrapper {
+            name,
+            temp_dir: None,
+            // The tokens fit in the mapping, so their number fits in usize
+            size: header.count as usize,
+            offset,
+            native: Self::is_native(&header, ptr, offset),
+            header,
+            ptr,
+            len,
+        };
+        wrapper.header.check_payload(wrapper.payload())?;
+
+        Ok(wrapper)
+    }
+
+    /// Sets the directory of the temporary files written by `get_half`
+    pub fn set_temp_dir(&mut self, dir: Option<PathBuf>) {
+        self.temp_dir = dir;
+    }
+
+    // Tokens in the machine endianness and aligned to their width can be used in place. Version
+    // 2 headers keep every width aligned, the tokens of version 1 bins are always decoded
+    fn is_native(header: &TraceHeader, ptr: *const u8, offset: usize) -> bool {
+        header.big_endian == cfg!(target_endian = "big")
+            && (ptr as usize + offset).is_multiple_of(header.token_width)
+    }
+
+    /// Header of the mapped trace bin
+    pub fn header(&self) -> &TraceHeader {
+        &self.header
+    }
+
+    /// Stored tokens, without the header
+    pub fn payload(&self) -> &[u8] {
+        let len = self.size * self.header.token_width;
+        unsafe { std::slice::from_raw_parts(self.ptr.add(self.offset), len) }
+    }
+
+    /// Tokens as a slice of their stored width, if they are in the machine endianness
+    ///
+    /// Foreign endianness traces return `None` and are decoded on every `get`
+    pub fn tokens(&self) -> Option<Tokens<'_>> {
+        if !self.native {
+            return None;
+        }
+        let ptr = unsafe { self.ptr.add(self.offset) };
+        // SAFETY: the tokens are in the mapping and aligned to their width, see `is_native`
+        let tokens = unsafe {
+            match self.header.token_width {
+                1 => Tokens::U8(std::slice::from_raw_parts(ptr, self.size)),
+                2 => Tokens::U16(std::slice::from_raw_parts(ptr as *const u16, self.size)),
+                4 => Tokens::U32(std::slice::from_raw_parts(ptr as *const u32, self.size)),
+                _ => Tokens::U64(std::slice::from_raw_parts(ptr as *const u64, self.size)),
+            }
+        };
+        Some(tokens)
+    }
 }
 
 // This only works for UNIX like systems
 // It is faster than the naive vectorization of the tokens :)
 impl Accesor for MMapWrapper {
+    #[inline]
     fn get(&self, idx: usize) -> TokenID {
-        // get 8 bytes from the pointer
-        let ptr = unsafe { self.ptr.lock().unwrap().add(12 + idx * 8) } as *mut TokenID;
-        unsafe { ptr.read() }
+        if let Some(tokens) = self.tokens() {
+            return tokens.get(idx);
+        }
+        let width = self.header.token_width;
+        self.header
+            .decode(&self.payload()[idx * width..(idx + 1) * width])
     }
 
     fn size(&self) -> usize {
         self.size
     }
 
-    fn get_half(&self) -> Box<dyn Accesor> {
-        let halfname = format!("{}.2.bin", self.name);
-        // Create a tmp file with half of the size
-        let mut file = std::fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(halfname.clone())
-            .unwrap();
+    fn get_half(&self) -> Result<Box<dyn Accesor>> {
+        // The file is unlinked as soon as it is created, so it is removed even if we panic
+        let file = match &self.temp_dir {
+            Some(dir) => tempfile::tempfile_in(dir)?,
+            None => tempfile::tempfile()?,
+        };
+
+        // Same halving as for vectors, the first of the consecutive pairs
+        let size = self.size.div_ceil(2);
+        let width = self.header.token_width;
 
-        let size = self.size / 2;
+        let mut token = [0; 8];
+        let token = &mut token[..width];
 
-        log::info!("Copying {} elements", size);
-        for i in 0..size{
-            //println!("{} {}", i, self.size);
-            let element = self.get(i*2);
-            file.write_all(element.to_le_bytes().as_ref()).unwrap();
+        let mut header = TraceHeader::new(width, size as u64, 0, 0);
+        header.big_endian = self.header.big_endian;
+        header.vocabulary_hash = self.header.vocabulary_hash;
+
+        log::info!("Copying {} elements of {}", size, self.name);
+        let mut writer = std::io::BufWriter::new(&file);
+        writer.write_all(&header.to_bytes())?;
+        let mut checksum = Fnv1a::default();
+        for i in 0..size {
+            self.header.encode(self.get(i * 2), token);
+            checksum.update(token);
+            writer.write_all(token)?;
         }
 
+        // The checksum is known once the tokens are written
+        header.checksum = Some(checksum.finish());
+        writer.seek(std::io::SeekFrom::Start(0))?;
+        writer.write_all(&header.to_bytes())?;
+        writer.flush()?;
+        drop(writer);
 
-        let ptr = unsafe {
-            rustix::mm::mmap(
-                std::ptr::null_mut(),
-                self.size / 2 * 8,
-                rustix::mm::ProtFlags::READ | rustix::mm::ProtFlags::WRITE,
-                rustix::mm::MapFlags::PRIVATE,
-                &file,
-                0,
-            )
-            .expect(&format!("mmap failed to allocate {:#x} bytes", self.size / 2 * 8))
-        } as *mut TokenID;
-        Box::new(MMapWrapper {
-            name: halfname,
-            size,
-            tmp: true,
-            ptr: Arc::new(Mutex::new(ptr as *mut u8)),
-        })
+        let mut half = Self::from_file(&file, format!("{}.2", self.name))?;
+        half.set_temp_dir(self.temp_dir.clone());
+        Ok(Box::new(half))
     }
-
 }
 
 impl Drop for MMapWrapper {
     fn drop(&mut self) {
         // Unmap the file
-        if self.tmp {
-            std::fs::remove_file(self.name.clone()).expect("File could not be removed");
-        }
+        unmap(self.ptr, self.len);
     }
 }
 
-
 #[cfg(test)]
 mod tests {
-    use std::{path::PathBuf, io::Write};
-
-    use crate::{parsing::TraceEncoder, dtw::{Accesor, TokenID}};
+    use std::io::Write;
 
+    use super::{MMapWrapper, Tokens};
+    use crate::{
+        dtw::{Accesor, FastDTW, STRACDistance, StandardDTW, TokenID, DTW},
+        error::DtwError,
+        parsing::{Fnv1a, TraceEncoder, TraceHeader},
+    };
 
     #[test]
     pub fn test_mmap() {
-        let  encoder = crate::parsing::ToMemoryParser::default();
-        let r1 = encoder.deserialize(PathBuf::from("tests/t1.txt.trace.bin"));
+        let dir = tempfile::tempdir().unwrap();
+        let mut encoder = crate::parsing::ToMemoryParser::default();
+        let tokens = (0..101).map(|i| format!("token {}", i % 7)).collect();
+        let ids = encoder
+            .create_bin(tokens, dir.path().join("test_mmap.bin"))
+            .unwrap();
+        let r1 = encoder
+            .deserialize(dir.path().join("test_mmap.bin"))
+            .unwrap();
+
+        // Compact tokens are read in place with their width
+        let wrapper = MMapWrapper::open(&dir.path().join("test_mmap.bin")).unwrap();
+        assert!(matches!(wrapper.tokens(), Some(Tokens::U8(_))));
+        for (i, id) in ids.iter().enumerate() {
+            assert_eq!(wrapper.get(i), *id);
+        }
 
         // Test half getting
-        let _r2: Box<dyn Accesor> = r1.get_half();
+        let _r2: Box<dyn Accesor> = r1.get_half().unwrap();
     }
 
+    #[test]
+    pub fn test_mmap_half() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut encoder = crate::parsing::ToMemoryParser::default();
+        let tokens = (0..301).map(|i| format!("token {}", i % 13)).collect();
+        let ids = encoder
+            .create_bin(tokens, dir.path().join("test_mmap_half.bin"))
+            .unwrap();
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        encoder.set_temp_dir(Some(temp_dir.path().to_path_buf()));
+        let r1 = encoder
+            .deserialize(dir.path().join("test_mmap_half.bin"))
+            .unwrap();
+
+        // Odd sizes keep the last token, as for vectors
+        let mut expected = ids.get_half().unwrap();
+        let mut half = r1.get_half().unwrap();
+        for _ in 0..4 {
+            assert_eq!(half.size(), expected.size());
+            for i in 0..half.size() {
+                assert_eq!(half.get(i), expected.get(i));
+            }
+            expected = expected.get_half().unwrap();
+            half = half.get_half().unwrap();
+        }
 
+        // The halves are never visible in the temporary directory
+        assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+        drop(half);
+        assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+
+        // A missing directory is an error, not a panic
+        encoder.set_temp_dir(Some(temp_dir.path().join("missing")));
+        let r1 = encoder
+            .deserialize(dir.path().join("test_mmap_half.bin"))
+            .unwrap();
+        assert!(r1.get_half().is_err());
+    }
 
     #[test]
-    pub fn test_larger_files() {
-        // Create a super large file
-        let mut file = std::fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(String::from("large.bin"))
+    pub fn test_mmap_fastdtw() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut encoder = crate::parsing::ToMemoryParser::default();
+        let tokens1 = (0..301).map(|i| format!("token {}", i % 11)).collect();
+        let tokens2 = (0..150).map(|i| format!("token {}", i % 7)).collect();
+        let ids1 = encoder
+            .create_bin(tokens1, dir.path().join("test_mmap_fastdtw1.bin"))
             .unwrap();
-        
-        println!("Writing data");
-        let size = 1000000000;
-        // Write up to 10Gb
-        for i in 0..size{
-            let b: usize = i;
-            file.write_all(b.to_le_bytes().as_ref()).unwrap();
-        }
+        let ids2 = encoder
+            .create_bin(tokens2, dir.path().join("test_mmap_fastdtw2.bin"))
+            .unwrap();
+        let r1 = encoder
+            .deserialize(dir.path().join("test_mmap_fastdtw1.bin"))
+            .unwrap();
+        let r2 = encoder
+            .deserialize(dir.path().join("test_mmap_fastdtw2.bin"))
+            .unwrap();
+
+        // The halves of the mapped traces are the same as the ones of the vectors
+        let distance = STRACDistance::default();
+        let standard = StandardDTW::new(&distance);
+        let fastdtw = FastDTW::new(&distance, 1, 10, &standard);
+        assert_eq!(
+            fastdtw.calculate(&ids1, &ids2),
+            fastdtw.calculate(&*r1, &*r2)
+        );
+    }
+
+    #[test]
+    pub fn test_mmap_bad_count() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test_mmap_bad_count.bin");
+
+        // The header declares more tokens than the mapping has, with and without overflowing
+        for count in [(1 << 61) + 1, 2] {
+            let header = TraceHeader::new(8, count, Fnv1a::default().finish(), 0);
+            let mut bytes = header.to_bytes();
+            bytes.extend_from_slice(&[0; 8]);
+            std::fs::write(&path, &bytes).unwrap();
 
-        let ptr = unsafe {
-            rustix::mm::mmap(
-                std::ptr::null_mut(),
-                size * 8,
-                rustix::mm::ProtFlags::READ | rustix::mm::ProtFlags::WRITE,
-                rustix::mm::MapFlags::PRIVATE,
-                &file,
-                0,
-            )
-            .expect(&format!("mmap failed to allocate {:#x} bytes", size * 8))
-        } as *mut TokenID;
-
-        // Get element by element
-        for i in 0..size{
-            let ptr = unsafe { ptr.add(i) } as *mut TokenID;
-            let element = unsafe { ptr.read() };
-            assert_eq!(element, i);
+            let r = MMapWrapper::open(&path);
+            assert!(matches!(
+                r,
+                Err(DtwError::TooManyTokens(_) | DtwError::Truncated { .. })
+            ));
         }
     }
 
-}
\ No newline at end of file
+    #[test]
+    pub fn test_larger_files() {
+        let dir = tempfile::tempdir().unwrap();
+        // Create a large trace of full width tokens
+        let size = 1 << 22;
+        let mut header = TraceHeader::new(8, size as u64, 0, 0);
+        header.big_endian = cfg!(target_endian = "big");
+
+        let mut checksum = Fnv1a::default();
+        let mut payload = Vec::with_capacity(size * 8);
+        for i in 0..size {
+            let mut token = [0; 8];
+            header.encode(i, &mut token);
+            checksum.update(&token);
+            payload.extend_from_slice(&token);
+        }
+        header.checksum = Some(checksum.finish());
+
+        let mut file = std::fs::File::create(dir.path().join("large.bin")).unwrap();
+        file.write_all(&header.to_bytes()).unwrap();
+        file.write_all(&payload).unwrap();
+        drop(file);
+
+        let wrapper = MMapWrapper::open(&dir.path().join("large.bin")).unwrap();
+        let Some(Tokens::U64(tokens)) = wrapper.tokens() else {
+            panic!("full width tokens are not read in place");
+        };
+        assert_eq!(tokens.len(), size);
+
+        // The mapping is shared between threads without locking
+        std::thread::scope(|scope| {
+            let wrapper = &wrapper;
+            for t in 0..4 {
+                scope.spawn(move || {
+                    for i in (t..size).step_by(4) {
+                        assert_eq!(wrapper.get(i), i);
+                    }
+                });
+            }
+        });
+        assert!(tokens.iter().enumerate().all(|(i, t)| *t as TokenID == i));
+    }
+}
@@ -5,27 +5,305 @@
 //!
 
 use crate::dtw::*;
-#[cfg(not(target_arch = "x86_64"))]
-use byteorder::{BigEndian, LittleEndian, ReadBytesExt};
+use crate::error::{DtwError, Result};
+use byteorder::{LittleEndian, ReadBytesExt};
 use std::collections::HashMap;
-#[cfg(not(target_arch = "x86_64"))]
 use std::io::Read;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+/// Extension appended to a trace bin path to get its vocabulary sidecar
+pub const VOCABULARY_EXTENSION: &str = "vocab";
+/// Magic bytes at the beginning of every vocabulary sidecar
+pub const VOCABULARY_MAGIC: &[u8; 4] = b"dtwv";
+
+/// Magic bytes at the beginning of every trace bin
+pub const TRACE_MAGIC: &[u8; 4] = b"dtw\0";
+/// Version of the trace bins written by `create_bin`
+pub const TRACE_VERSION: u32 = 2;
+/// Size of the version 1 header
+pub const V1_HEADER_SIZE: usize = 12;
+/// Size of the version 2 header. It keeps the tokens 8 bytes aligned in the mapped file
+pub const V2_HEADER_SIZE: usize = 40;
+
+/// Header of a trace bin.
+///
+/// Version 1 (only read)
+/// - 4 bytes, the magic 'dtw\0'
+/// - 4 bytes, the version as 0x00000001
+/// - 4 bytes, the number of tokens, little endian
+/// - 8 bytes per token, little endian
+///
+/// Version 2
+/// - 4 bytes, the magic 'dtw\0'
+/// - 4 bytes, the version, little endian
+/// - 1 byte, the endianness of everything that follows, 0 for little and 1 for big endian
+/// - 1 byte, the width in bytes of every token, 1, 2, 4 or 8. It is the smallest width that
+///   fits the vocabulary, see `width_for`
+/// - 2 bytes, reserved
+/// - 8 bytes, the number of tokens
+/// - 8 bytes, FNV-1a checksum of the tokens as they are stored
+/// - 8 bytes, FNV-1a hash of the vocabulary written next to the trace, see `vocabulary_hash`
+/// - 4 bytes, reserved
+/// - `width` bytes per token
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceHeader {
+    pub version: u32,
+    pub big_endian: bool,
+    pub token_width: usize,
+    pub count: u64,
+    /// Version 1 bins do not have checksum
+    pub checksum: Option<u64>,
+    /// Version 1 bins do not have vocabulary hash
+    pub vocabulary_hash: Option<u64>,
+}
+
+impl TraceHeader {
+    /// Header for a version 2 little endian trace
+    pub fn new(token_width: usize, count: u64, checksum: u64, vocabulary_hash: u64) -> Self {
+        TraceHeader {
+            version: TRACE_VERSION,
+            big_endian: false,
+            token_width,
+            count,
+            checksum: Some(checksum),
+            vocabulary_hash: Some(vocabulary_hash),
+        }
+    }
+
+    /// Number of bytes before the first token
+    pub fn size(&self) -> usize {
+        if self.version == 1 {
+            V1_HEADER_SIZE
+        } else {
+            V2_HEADER_SIZE
+        }
+    }
+
+    /// Number of bytes needed to parse the header whose first `V1_HEADER_SIZE` bytes are `bytes`
+    pub fn size_from_prefix(bytes: &[u8]) -> usize {
+        if bytes[4..8] == [0x00, 0x00, 0x00, 0x01] {
+            V1_HEADER_SIZE
+        } else {
+            V2_HEADER_SIZE
+        }
+    }
+
+    /// Number of bytes of the tokens. Headers with more tokens than a file can hold are an error
+    pub fn payload_size(&self) -> Result<u64> {
+        self.count
+            .checked_mul(self.token_width as u64)
+            .ok_or(DtwError::TooManyTokens(self.count))
+    }
+
+    /// Checks that a file of `len` bytes holds the header and all the tokens
+    pub fn check_len(&self, len: u64) -> Result<()> {
+        let expected = self
+            .payload_size()?
+            .checked_add(self.size() as u64)
+            .ok_or(DtwError::TooManyTokens(self.count))?;
+        if len < expected {
+            return Err(DtwError::Truncated {
+                expected,
+                found: len,
+            });
+        }
+        Ok(())
+    }
+
+    /// Parses the header at the beginning of `bytes`
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        let truncated = |expected: usize| DtwError::Truncated {
+            expected: expected as u64,
+            found: bytes.len() as u64,
+        };
+
+        if bytes.len() < V1_HEADER_SIZE {
+            return Err(truncated(V1_HEADER_SIZE));
+        }
+        if &bytes[0..4] != TRACE_MAGIC {
+            return Err(DtwError::BadMagic(bytes[0..4].try_into().unwrap()));
+        }
+
+        // The first version wrote the version number as big endian
+        if bytes[4..8] == [0x00, 0x00, 0x00, 0x01] {
+            return Ok(TraceHeader {
+                version: 1,
+                big_endian: false,
+                token_width: 8,
+                count: u32::from_le_bytes(bytes[8..12].try_into().unwrap()) as u64,
+                checksum: None,
+                vocabulary_hash: None,
+            });
+        }
+
+        let version = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+        if version != TRACE_VERSION {
+            return Err(DtwError::UnsupportedVersion(version));
+        }
+        if bytes.len() < V2_HEADER_SIZE {
+            return Err(truncated(V2_HEADER_SIZE));
+        }
+
+        let big_endian = bytes[8] == 1;
+        let token_width = bytes[9] as usize;
+        if !matches!(token_width, 1 | 2 | 4 | 8) {
+            return Err(DtwError::UnsupportedTokenWidth(token_width));
+        }
+        let field = |at: usize| {
+            let field = bytes[at..at + 8].try_into().unwrap();
+            if big_endian {
+                u64::from_be_bytes(field)
+            } else {
+                u64::from_le_bytes(field)
+            }
+        };
+
+        Ok(TraceHeader {
+            version,
+            big_endian,
+            token_width,
+            count: field(12),
+            checksum: Some(field(20)),
+            vocabulary_hash: Some(field(28)),
+        })
+    }
+
+    /// Checks the tokens, as they are stored, against the checksum of the header
+    pub fn check_payload(&self, payload: &[u8]) -> Result<()> {
+        if let Some(expected) = self.checksum {
+            let mut checksum = Fnv1a::default();
+            checksum.update(payload);
+            if checksum.finish() != expected {
+                return Err(DtwError::ChecksumMismatch {
+                    expected,
+                    found: checksum.finish(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Serializes the header, always as a version 2 header. Version 1 headers are only read
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let field = |value: u64| {
+            if self.big_endian {
+                value.to_be_bytes()
+            } else {
+                value.to_le_bytes()
+            }
+        };
+
+        let mut bytes = Vec::with_capacity(V2_HEADER_SIZE);
+        bytes.extend_from_slice(TRACE_MAGIC);
+        bytes.extend_from_slice(&TRACE_VERSION.to_le_bytes());
+        bytes.push(self.big_endian as u8);
+        bytes.push(self.token_width as u8);
+        bytes.extend_from_slice(&[0; 2]);
+        bytes.extend_from_slice(&field(self.count));
+        bytes.extend_from_slice(&field(self.checksum.unwrap_or(0)));
+        bytes.extend_from_slice(&field(self.vocabulary_hash.unwrap_or(0)));
+        bytes.extend_from_slice(&[0; 4]);
+
+        bytes
+    }
+
+    /// Decodes a stored token, `bytes` has exactly `token_width` bytes
+    #[inline]
+    pub fn decode(&self, bytes: &[u8]) -> TokenID {
+        macro_rules! decode {
+            ($ty:ty) => {{
+                let bytes = bytes.try_into().unwrap();
+                if self.big_endian {
+                    <$ty>::from_be_bytes(bytes) as TokenID
+                } else {
+                    <$ty>::from_le_bytes(bytes) as TokenID
+                }
+            }};
+        }
+
+        match self.token_width {
+            1 => bytes[0] as TokenID,
+            2 => decode!(u16),
+            4 => decode!(u32),
+            _ => decode!(u64),
+        }
+    }
+
+    /// Encodes a token as it is stored, `out` has exactly `token_width` bytes
+    #[inline]
+    pub fn encode(&self, token: TokenID, out: &mut [u8]) {
+        macro_rules! encode {
+            ($ty:ty) => {{
+                let token = token as $ty;
+                if self.big_endian {
+                    out.copy_from_slice(&token.to_be_bytes())
+                } else {
+                    out.copy_from_slice(&token.to_le_bytes())
+                }
+            }};
+        }
+
+        match self.token_width {
+            1 => out[0] = token as u8,
+            2 => encode!(u16),
+            4 => encode!(u32),
+            _ => encode!(u64),
+        }
+    }
+
+    /// Smallest token width, in bytes, that can store every id of a vocabulary
+    pub fn width_for(vocabulary_size: usize) -> usize {
+        match vocabulary_size as u64 {
+            0..=0x100 => 1,
+            0x101..=0x1_0000 => 2,
+            0x1_0001..=0x1_0000_0000 => 4,
+            _ => 8,
+        }
+    }
+}
+
+/// FNV-1a hash, used for the checksums of the trace bins
+#[derive(Clone, Copy, Debug)]
+pub struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Fnv1a(0xcbf29ce484222325)
+    }
+}
+
+impl Fnv1a {
+    #[inline]
+    pub fn update(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= *b as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
 
 pub trait TraceEncoder<'a> {
-    /// Creates a trace bin file
-    fn create_bin(&mut self, tokens: Vec<String>, to: PathBuf) -> Vec<TokenID>;
+    /// Creates a trace bin file.
+    /// The vocabulary known at this point is written next to it, see `VOCABULARY_EXTENSION`
+    fn create_bin(&mut self, tokens: Vec<String>, to: PathBuf) -> Result<Vec<TokenID>>;
 
     /// Maps the token to a unique id
     fn token_to_id(&mut self, token: &str) -> TokenID;
 
     /// Maps the id to the token.
     /// This is helpful to generate the alignment
-    fn id_to_token(&self, id: TokenID) -> String;
+    fn id_to_token(&self, id: TokenID) -> Result<String>;
 
-    /// Loads a trace bin from file
-    fn deserialize(&self, from: PathBuf) -> Box<dyn Accesor>;
+    /// Loads a trace bin from file.
+    /// If the bin has a vocabulary sidecar, it is loaded into the encoder as well, so the ids can
+    /// be mapped back to tokens. The ids of the trace are the ones of the encoder, also when the
+    /// bin was encoded in another run
+    fn deserialize(&mut self, from: PathBuf) -> Result<Box<dyn Accesor>>;
 }
 
 #[derive(Default)]
@@ -34,16 +312,213 @@ pub struct ToMemoryParser {
     token_to_id: HashMap<String, TokenID>,
     id_to_token: HashMap<TokenID, String>,
     largest_token: usize,
+    // Where the mapped traces write their temporary halves
+    temp_dir: Option<PathBuf>,
 }
 
 impl ToMemoryParser {
+    /// Sets the directory of the temporary files written while halving the loaded traces.
+    /// The system temporary directory is used if it is not set
+    pub fn set_temp_dir(&mut self, dir: Option<PathBuf>) {
+        self.temp_dir = dir;
+    }
+
+    pub fn temp_dir(&self) -> Option<&Path> {
+        self.temp_dir.as_deref()
+    }
+
     pub fn get_largest_token(&self) -> usize {
         self.largest_token
     }
+
+    /// Returns the id of a token without adding it to the vocabulary
+    pub fn lookup(&self, token: &str) -> Option<TokenID> {
+        self.token_to_id.get(token).copied()
+    }
+
+    /// Number of distinct tokens known by the encoder
+    pub fn vocabulary_size(&self) -> usize {
+        self.id_to_token.len()
+    }
+
+    /// The tokens of the vocabulary, the index of every token is its id
+    pub fn vocabulary(&self) -> Vec<&str> {
+        (0..self.id_to_token.len())
+            .map(|id| self.id_to_token[&(id as TokenID)].as_str())
+            .collect()
+    }
+
+    /// Returns the path of the vocabulary sidecar of a trace bin, e.g. `t1.trace.bin.vocab`
+    pub fn vocabulary_path(bin: &Path) -> PathBuf {
+        let mut name = bin.as_os_str().to_owned();
+        name.push(".");
+        name.push(VOCABULARY_EXTENSION);
+        PathBuf::from(name)
+    }
+
+    /// Hash of the vocabulary as written by `write_vocabulary`, the length and bytes of every
+    /// token in increasing id order
+    pub fn vocabulary_hash(&self) -> u64 {
+        let mut hash = Fnv1a::default();
+        for id in 0..self.id_to_token.len() {
+            let token = &self.id_to_token[&(id as TokenID)];
+            hash.update(&(token.len() as u64).to_le_bytes());
+            hash.update(token.as_bytes());
+        }
+        hash.finish()
+    }
+
+    /// Writes the vocabulary of the encoder
+    /// First 4 bytes the header 'dtwv'
+    /// Second 4 bytes the version of the vocabulary format, little endian
+    /// Third 8 bytes the number of tokens, little endian
+    /// Then, for every id in increasing order, 8 bytes with the length of the token followed by
+    /// the token as UTF-8
+    pub fn write_vocabulary(&self, to: &Path) -> Result<()> {
+        let f = std::fs::File::create(to)?;
+        let mut bw = std::io::BufWriter::new(f);
+
+        bw.write_all(VOCABULARY_MAGIC)?;
+        bw.write_all(&1u32.to_le_bytes())?;
+        bw.write_all(&(self.id_to_token.len() as u64).to_le_bytes())?;
+
+        // Ids are given in insertion order, so they go from 0 to len - 1
+        for id in 0..self.id_to_token.len() {
+            let token = &self.id_to_token[&(id as TokenID)];
+            bw.write_all(&(token.len() as u64).to_le_bytes())?;
+            bw.write_all(token.as_bytes())?;
+        }
+
+        bw.flush()?;
+        Ok(())
+    }
+
+    /// Loads a vocabulary written by `write_vocabulary` into the encoder. Returns its hash and,
+    /// when the encoder gives other ids to the tokens, the new id of every id of the file.
+    /// Traces encoded in separate runs give the same ids to different tokens, so the tokens
+    /// already known by the encoder keep their id and the others get the next free ids
+    pub fn load_vocabulary(&mut self, from: &Path) -> Result<(u64, Option<Vec<TokenID>>)> {
+        let (hash, tokens) = ToMemoryParser::read_vocabulary(from)?;
+        Ok((hash, self.extend_vocabulary(&tokens, from)))
+    }
+
+    /// Reads a vocabulary written by `write_vocabulary` without changing the encoder. Returns
+    /// its hash and its tokens in increasing id order
+    pub fn read_vocabulary(from: &Path) -> Result<(u64, Vec<String>)> {
+        let f = std::fs::File::open(from)?;
+        let mut br = std::io::BufReader::new(f);
+
+        let mut header = [0; 4];
+        br.read_exact(&mut header)?;
+        if &header != VOCABULARY_MAGIC {
+            return Err(DtwError::BadMagic(header));
+        }
+        let version = br.read_u32::<LittleEndian>()?;
+        if version != 1 {
+            return Err(DtwError::UnsupportedVersion(version));
+        }
+
+        let count = br.read_u64::<LittleEndian>()?;
+        let mut hash = Fnv1a::default();
+        let mut tokens = vec![];
+
+        for id in 0..count {
+            let len = br.read_u64::<LittleEndian>()?;
+            let mut token = vec![];
+            (&mut br).take(len).read_to_end(&mut token)?;
+            if token.len() as u64 != len {
+                return Err(DtwError::Vocabulary(format!(
+                    "the token {} is truncated",
+                    id
+                )));
+            }
+            hash.update(&len.to_le_bytes());
+            hash.update(&token);
+            let token = String::from_utf8(token).map_err(|_| {
+                DtwError::Vocabulary(format!("the token {} is not valid UTF-8", id))
+            })?;
+            tokens.push(token);
+        }
+
+        Ok((hash.finish(), tokens))
+    }
+
+    /// Adds the tokens of a vocabulary read from `from` to the encoder, returns the new id of
+    /// every id of the vocabulary if any of them changes
+    fn extend_vocabulary(&mut self, tokens: &[String], from: &Path) -> Option<Vec<TokenID>> {
+        // New tokens are added in the order of the file, so the ids do not change when the
+        // vocabulary extends the one of the encoder
+        let ids: Vec<TokenID> = tokens.iter().map(|t| self.token_to_id(t)).collect();
+        let remap = if ids.iter().enumerate().all(|(id, new)| id == *new) {
+            None
+        } else {
+            log::debug!("{:?} gives other ids to the tokens, remapping them", from);
+            Some(ids)
+        };
+
+        remap
+    }
+
+    /// Adds the vocabulary of the trace, if any, to the encoder and maps the ids of the trace to
+    /// the ones of the encoder. Version 1 bins have no vocabulary hash, so every id is checked
+    /// against the vocabulary first
+    fn remapped(
+        &mut self,
+        trace: Box<dyn Accesor>,
+        vocabulary: Option<(&Path, Vec<String>)>,
+    ) -> Result<Box<dyn Accesor>> {
+        let Some((from, tokens)) = vocabulary else {
+            return Ok(trace);
+        };
+        check_ids(trace.as_ref(), tokens.len())?;
+        Ok(match self.extend_vocabulary(&tokens, from) {
+            Some(ids) => Box::new(RemappedAccesor {
+                inner: trace,
+                ids: ids.into(),
+            }),
+            None => trace,
+        })
+    }
+}
+
+/// Checks that every id of the trace is one of the `count` ids of its vocabulary
+fn check_ids(trace: &dyn Accesor, count: usize) -> Result<()> {
+    match (0..trace.size())
+        .map(|i| trace.get(i))
+        .find(|&id| id >= count)
+    {
+        Some(id) => Err(DtwError::UnknownTokenId(id)),
+        None => Ok(()),
+    }
+}
+
+/// Trace whose ids are mapped to the ones of the encoder that loaded it, see
+/// `ToMemoryParser::load_vocabulary`
+pub struct RemappedAccesor {
+    inner: Box<dyn Accesor>,
+    ids: std::sync::Arc<[TokenID]>,
+}
+
+impl Accesor for RemappedAccesor {
+    #[inline]
+    fn get(&self, idx: usize) -> TokenID {
+        self.ids[self.inner.get(idx)]
+    }
+
+    fn size(&self) -> usize {
+        self.inner.size()
+    }
+
+    fn get_half(&self) -> Result<Box<dyn Accesor>> {
+        Ok(Box::new(RemappedAccesor {
+            inner: self.inner.get_half()?,
+            ids: self.ids.clone(),
+        }))
+    }
 }
 
 impl<'a> TraceEncoder<'a> for ToMemoryParser {
-    fn create_bin(&mut self, tokens: Vec<String>, to: PathBuf) -> Vec<TokenID> {
+    fn create_bin(&mut self, tokens: Vec<String>, to: PathBuf) -> Result<Vec<TokenID>> {
         // The tokens are already extracted...the extractor is a regular split
         // The default implementation is to get one token per line
         let mut r = vec![];
@@ -52,27 +527,34 @@ impl<'a> TraceEncoder<'a> for ToMemoryParser {
             r.push(self.token_to_id(&t))
         }
 
-        // Write the trace file
-        // First 4 bytes the header 'dtw\0'
-        // Second 4 bytes the version of this tool 0x00000001
-        // Third 4 bytes the size f the vector
-        // Then 4 bytes per token (the TypeID should fit into 4 bytes)
-        //
-        let f = std::fs::File::create(to).expect("File coudl not be created");
+        // Write the trace file, see `TraceHeader` for the layout
+        let width = TraceHeader::width_for(self.vocabulary_size());
+        let mut header = TraceHeader::new(width, r.len() as u64, 0, self.vocabulary_hash());
+        let mut token = [0; 8];
+        let token = &mut token[..width];
+
+        let mut checksum = Fnv1a::default();
+        for i in &r {
+            header.encode(*i, token);
+            checksum.update(token);
+        }
+        header.checksum = Some(checksum.finish());
+
+        let f = std::fs::File::create(&to)?;
         let mut bw = std::io::BufWriter::new(f);
 
         // Write the header
-        bw.write_all(b"dtw\0").expect("Could not write the header");
-        bw.write_all(&[0x00, 0x00, 0x00, 0x01])
-            .expect("Could not write the version");
-        let _ = bw.write_all(&(r.len() as u32).to_le_bytes());
+        bw.write_all(&header.to_bytes())?;
         // Write the bytes
         for i in &r {
-            bw.write_all(&i.to_le_bytes())
-                .expect("Could not write the token");
+            header.encode(*i, token);
+            bw.write_all(token)?;
         }
+        bw.flush()?;
+
+        self.write_vocabulary(&ToMemoryParser::vocabulary_path(&to))?;
 
-        r
+        Ok(r)
     }
 
     fn token_to_id(&mut self, token: &str) -> TokenID {
@@ -94,81 +576,70 @@ impl<'a> TraceEncoder<'a> for ToMemoryParser {
         id as TokenID
     }
 
-    fn id_to_token(&self, id: TokenID) -> String {
-        return self.id_to_token.get(&id).unwrap().clone();
+    fn id_to_token(&self, id: TokenID) -> Result<String> {
+        self.id_to_token
+            .get(&id)
+            .cloned()
+            .ok_or(DtwError::UnknownTokenId(id))
     }
 
-    fn deserialize(&self, from: PathBuf) -> Box<dyn Accesor> {
-        // Use rustix to mmap file
-        #[cfg(target_arch = "x86_64")]
-        {
-            let file = std::fs::File::open(from.clone()).expect("failed to open file");
-            let len = file.metadata().expect("failed to get file metadata").len();
-
-            let len = usize::try_from(len).expect("file too large to map");
-            let ptr = unsafe {
-                rustix::mm::mmap(
-                    std::ptr::null_mut(),
-                    len,
-                    rustix::mm::ProtFlags::READ | rustix::mm::ProtFlags::WRITE,
-                    rustix::mm::MapFlags::PRIVATE,
-                    &file,
-                    0,
-                )
-                .expect(&format!("mmap failed to allocate {:#x} bytes", len))
+    fn deserialize(&mut self, from: PathBuf) -> Result<Box<dyn Accesor>> {
+        // The vocabulary only changes the encoder once it is known to belong to the trace
+        let vocabulary = ToMemoryParser::vocabulary_path(&from);
+        let (vocabulary_hash, tokens) = if vocabulary.exists() {
+            let (hash, tokens) = ToMemoryParser::read_vocabulary(&vocabulary)?;
+            (Some(hash), Some((vocabulary.as_path(), tokens)))
+        } else {
+            log::debug!("{:?} has no vocabulary", from);
+            (None, None)
+        };
+        let check_vocabulary =
+            |header: &TraceHeader| match (header.vocabulary_hash, vocabulary_hash) {
+                (Some(expected), Some(found)) if expected != found => Err(DtwError::Vocabulary(
+                    format!("{:?} does not belong to the trace", vocabulary),
+                )),
+                _ => Ok(()),
             };
 
-            let ptr = ptr as *mut u8;
-
-            let header = unsafe { std::slice::from_raw_parts(ptr, 4) };
-            assert_eq!(&header, b"dtw\0");
-            let version = unsafe { std::slice::from_raw_parts(ptr.add(4), 4) };
-            assert_eq!(version, &[0x00, 0x00, 0x00, 0x01]);
-
-            let count = unsafe { std::slice::from_raw_parts(ptr.add(8), 4) };
-            let count = u32::from_le_bytes([count[0], count[1], count[2], count[3]]);
-
-            use crate::mmap::*;
-            let fr = from.clone();
-            let basename = fr.file_name().unwrap().to_str().unwrap();
-            let wrapper = MMapWrapper {
-                name: basename.into(),
-                // The first trace cration should be temporary
-                tmp: false,
-                size: count as usize,
-                ptr: std::sync::Arc::new(std::sync::Mutex::new(ptr)),
-            };
+        // Use rustix to mmap file
+        #[cfg(unix)]
+        {
+            let mut wrapper = crate::mmap::MMapWrapper::open(&from)?;
+            check_vocabulary(wrapper.header())?;
+            wrapper.set_temp_dir(self.temp_dir.clone());
 
-            Box::new(wrapper)
+            self.remapped(Box::new(wrapper), tokens)
         }
-        #[cfg(not(target_arch = "x86_64"))]
+        #[cfg(not(unix))]
         {
             // Wasm32 probably
             // Windows also
-            let f = std::fs::File::open(from).expect("File could not be opened");
+            let f = std::fs::File::open(from.clone())?;
+            let len = f.metadata()?.len();
 
             let mut br = std::io::BufReader::new(f);
-            // Read the header
-            // First 4 bytes the header 'dtw\0'
-            // Read as bytes
-            let header: [u8; 4] = {
-                let mut r = [0; 4];
-                br.read_exact(&mut r).unwrap();
-                r
-            };
-            assert_eq!(&header, b"dtw\0");
-            let version = br.read_u32::<BigEndian>().unwrap();
-            assert_eq!(version, 0x00000001);
+            // Read the header, the first bytes tell how large it is
+            let mut bytes = vec![0; V1_HEADER_SIZE.min(len as usize)];
+            br.read_exact(&mut bytes)?;
+            if bytes.len() == V1_HEADER_SIZE {
+                bytes.resize(TraceHeader::size_from_prefix(&bytes).min(len as usize), 0);
+                br.read_exact(&mut bytes[V1_HEADER_SIZE..])?;
+            }
 
-            let count = br.read_u32::<LittleEndian>().unwrap();
-            let mut r = vec![];
+            let header = TraceHeader::parse(&bytes)?;
+            header.check_len(len)?;
+            check_vocabulary(&header)?;
 
-            // 8 bytes per ID...that is too much :|
-            for _ in 0..count {
-                r.push(br.read_u64::<LittleEndian>().unwrap() as TokenID);
-            }
+            let mut payload = vec![0; header.payload_size()? as usize];
+            br.read_exact(&mut payload)?;
+            header.check_payload(&payload)?;
+
+            let r: Vec<TokenID> = payload
+                .chunks_exact(header.token_width)
+                .map(|token| header.decode(token))
+                .collect();
 
-            return Box::new(r);
+            self.remapped(Box::new(r), tokens)
         }
     }
 }
@@ -179,10 +650,12 @@ mod tests {
 
     #[test]
     fn test_parsing_unparsing() {
+        let dir = tempfile::tempdir().unwrap();
         let mut parser = ToMemoryParser {
             id_to_token: HashMap::new(),
             token_to_id: HashMap::new(),
             largest_token: 0,
+            temp_dir: None,
         };
 
         let tokens = vec![
@@ -308,14 +781,284 @@ mod tests {
             String::from("sub 2 2"),
         ];
 
-        let tokens = parser.create_bin(tokens, PathBuf::from("test.bin"));
+        let tokens = parser
+            .create_bin(tokens, dir.path().join("test.bin"))
+            .unwrap();
 
         println!("{:?}", tokens);
 
-        let accessor = parser.deserialize(PathBuf::from("test.bin"));
+        let accessor = parser.deserialize(dir.path().join("test.bin")).unwrap();
+
+        for (i, token) in tokens.iter().enumerate() {
+            assert_eq!(*token, accessor.get(i) as TokenID);
+        }
+    }
+
+    #[test]
+    fn test_vocabulary_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut parser = ToMemoryParser::default();
+
+        let tokens = vec![
+            String::from("call malloc"),
+            String::from("ret"),
+            String::from("call free"),
+            String::from("ret"),
+        ];
+
+        let ids = parser
+            .create_bin(tokens.clone(), dir.path().join("test_vocab.bin"))
+            .unwrap();
+
+        // A fresh encoder does not know the tokens until the bin is loaded
+        let mut other = ToMemoryParser::default();
+        let accessor = other
+            .deserialize(dir.path().join("test_vocab.bin"))
+            .unwrap();
+
+        assert_eq!(other.vocabulary_size(), 3);
+        assert_eq!(other.get_largest_token(), "call malloc".len());
+        for (i, token) in tokens.iter().enumerate() {
+            assert_eq!(ids[i], accessor.get(i));
+            assert_eq!(&other.id_to_token(accessor.get(i)).unwrap(), token);
+        }
+
+        // New tokens get ids after the loaded ones
+        assert_eq!(other.token_to_id("jmp"), 3);
+    }
+
+    #[test]
+    fn test_vocabulary_remap() {
+        let dir = tempfile::tempdir().unwrap();
+        // Encoded in separate runs, the ids of the bins mean different tokens
+        let first = vec![String::from("a"), String::from("b"), String::from("a")];
+        let second = vec![String::from("c"), String::from("a"), String::from("c")];
+        let _ = ToMemoryParser::default()
+            .create_bin(first.clone(), dir.path().join("test_remap1.bin"))
+            .unwrap();
+        let _ = ToMemoryParser::default()
+            .create_bin(second.clone(), dir.path().join("test_remap2.bin"))
+            .unwrap();
+
+        let mut parser = ToMemoryParser::default();
+        let r1 = parser
+            .deserialize(dir.path().join("test_remap1.bin"))
+            .unwrap();
+        let r2 = parser
+            .deserialize(dir.path().join("test_remap2.bin"))
+            .unwrap();
+
+        assert_eq!(parser.vocabulary_size(), 3);
+        for (trace, tokens) in [(&r1, &first), (&r2, &second)] {
+            for (i, token) in tokens.iter().enumerate() {
+                assert_eq!(&parser.id_to_token(trace.get(i)).unwrap(), token);
+            }
+        }
+        // Equal tokens get equal ids across the traces
+        assert_eq!(r1.get(0), r2.get(1));
+
+        let half = r2.get_half().unwrap();
+        assert_eq!(half.get(0), r2.get(0));
+        assert_eq!(half.get(1), r2.get(2));
+
+        // Loading it again gives the same ids
+        let again = parser
+            .deserialize(dir.path().join("test_remap2.bin"))
+            .unwrap();
+        assert_eq!(again.get(0), r2.get(0));
+    }
+
+    #[test]
+    fn test_read_v1() {
+        let dir = tempfile::tempdir().unwrap();
+        // Written as the first version of the tool did
+        let tokens: Vec<u64> = vec![3, 1, 2, 1];
+        let mut bytes = b"dtw\0".to_vec();
+        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x01]);
+        bytes.extend_from_slice(&(tokens.len() as u32).to_le_bytes());
+        for t in &tokens {
+            bytes.extend_from_slice(&t.to_le_bytes());
+        }
+        std::fs::write(dir.path().join("test_v1.bin"), bytes).unwrap();
+
+        let mut parser = ToMemoryParser::default();
+        let accessor = parser.deserialize(dir.path().join("test_v1.bin")).unwrap();
+
+        assert_eq!(accessor.size(), tokens.len());
+        for (i, token) in tokens.iter().enumerate() {
+            assert_eq!(accessor.get(i), *token as TokenID);
+        }
+    }
+
+    #[test]
+    fn test_stale_vocabulary() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut other = ToMemoryParser::default();
+        other.token_to_id("a");
+        other.token_to_id("b");
+
+        // Version 1 bins have no vocabulary hash, the ids are checked against the vocabulary
+        let mut bytes = b"dtw\0".to_vec();
+        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x01]);
+        bytes.extend_from_slice(&2u32.to_le_bytes());
+        for t in [1u64, 3] {
+            bytes.extend_from_slice(&t.to_le_bytes());
+        }
+        let v1 = dir.path().join("test_stale_v1.bin");
+        std::fs::write(&v1, bytes).unwrap();
+        other
+            .write_vocabulary(&ToMemoryParser::vocabulary_path(&v1))
+            .unwrap();
+
+        let mut parser = ToMemoryParser::default();
+        parser.token_to_id("c");
+        let r = parser.deserialize(v1);
+        assert!(matches!(r, Err(DtwError::UnknownTokenId(3))));
+
+        // A vocabulary of another trace is rejected before it changes the encoder
+        let v2 = dir.path().join("test_stale_v2.bin");
+        let tokens = vec![String::from("x"), String::from("y")];
+        let _ = ToMemoryParser::default()
+            .create_bin(tokens, v2.clone())
+            .unwrap();
+        other
+            .write_vocabulary(&ToMemoryParser::vocabulary_path(&v2))
+            .unwrap();
+        let r = parser.deserialize(v2);
+        assert!(matches!(r, Err(DtwError::Vocabulary(_))));
+
+        assert_eq!(parser.vocabulary(), vec!["c"]);
+    }
+
+    #[test]
+    fn test_header_roundtrip() {
+        let mut header = TraceHeader::new(8, 5_000_000_000, 42, 7);
+        assert_eq!(TraceHeader::parse(&header.to_bytes()).unwrap(), header);
+        assert_eq!(header.to_bytes().len(), V2_HEADER_SIZE);
+
+        header.big_endian = true;
+        assert_eq!(TraceHeader::parse(&header.to_bytes()).unwrap(), header);
+    }
+
+    #[test]
+    fn test_read_big_endian() {
+        let dir = tempfile::tempdir().unwrap();
+        let tokens: Vec<TokenID> = vec![5, 0, 256, 5];
+        let mut header = TraceHeader::new(8, tokens.len() as u64, 0, 0);
+        header.big_endian = true;
 
-        for i in 0..tokens.len() {
-            assert_eq!(tokens[i], accessor.get(i) as TokenID);
+        let mut checksum = Fnv1a::default();
+        let mut payload = vec![];
+        for t in &tokens {
+            let mut token = [0; 8];
+            header.encode(*t, &mut token);
+            checksum.update(&token);
+            payload.extend_from_slice(&token);
+        }
+        header.checksum = Some(checksum.finish());
+
+        let mut bytes = header.to_bytes();
+        bytes.extend_from_slice(&payload);
+        std::fs::write(dir.path().join("test_be.bin"), bytes).unwrap();
+
+        let mut parser = ToMemoryParser::default();
+        let accessor = parser.deserialize(dir.path().join("test_be.bin")).unwrap();
+        for (i, token) in tokens.iter().enumerate() {
+            assert_eq!(accessor.get(i), *token);
+        }
+    }
+
+    #[test]
+    fn test_corrupted_checksum() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut parser = ToMemoryParser::default();
+        let tokens = vec![String::from("a"), String::from("b")];
+        let _ = parser
+            .create_bin(tokens, dir.path().join("test_corrupted.bin"))
+            .unwrap();
+
+        let mut bytes = std::fs::read(dir.path().join("test_corrupted.bin")).unwrap();
+        bytes[V2_HEADER_SIZE] ^= 0xff;
+        std::fs::write(dir.path().join("test_corrupted.bin"), bytes).unwrap();
+
+        let r = parser.deserialize(dir.path().join("test_corrupted.bin"));
+        assert!(matches!(r, Err(DtwError::ChecksumMismatch { .. })));
+    }
+
+    #[test]
+    fn test_invalid_bins() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut parser = ToMemoryParser::default();
+
+        std::fs::write(
+            dir.path().join("test_bad_magic.bin"),
+            b"abc\0\0\0\0\x01\0\0\0\0",
+        )
+        .unwrap();
+        let r = parser.deserialize(dir.path().join("test_bad_magic.bin"));
+        assert!(matches!(r, Err(DtwError::BadMagic(_))));
+
+        let mut header = TraceHeader::new(1, 4, 0, 0).to_bytes();
+        header[4] = 3;
+        std::fs::write(dir.path().join("test_bad_version.bin"), &header).unwrap();
+        let r = parser.deserialize(dir.path().join("test_bad_version.bin"));
+        assert!(matches!(r, Err(DtwError::UnsupportedVersion(3))));
+
+        // The header says 4 tokens but there are none
+        std::fs::write(
+            dir.path().join("test_truncated.bin"),
+            TraceHeader::new(1, 4, 0, 0).to_bytes(),
+        )
+        .unwrap();
+        let r = parser.deserialize(dir.path().join("test_truncated.bin"));
+        assert!(matches!(r, Err(DtwError::Truncated { .. })));
+
+        // The size of the tokens does not fit in 64 bits, the checksum is the one of no tokens
+        let header = TraceHeader::new(8, (1 << 61) + 1, Fnv1a::default().finish(), 0);
+        let mut bytes = header.to_bytes();
+        bytes.extend_from_slice(&[0; 8]);
+        std::fs::write(dir.path().join("test_overflow.bin"), &bytes).unwrap();
+        let r = parser.deserialize(dir.path().join("test_overflow.bin"));
+        assert!(matches!(r, Err(DtwError::TooManyTokens(_))));
+        assert!(header.check_len(u64::MAX).is_err());
+
+        let r = parser.deserialize(dir.path().join("test_does_not_exist.bin"));
+        assert!(matches!(r, Err(DtwError::Io(_))));
+
+        assert!(matches!(
+            parser.id_to_token(42),
+            Err(DtwError::UnknownTokenId(42))
+        ));
+    }
+
+    #[test]
+    fn test_compact_widths() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(TraceHeader::width_for(0), 1);
+        assert_eq!(TraceHeader::width_for(256), 1);
+        assert_eq!(TraceHeader::width_for(257), 2);
+        assert_eq!(TraceHeader::width_for(65536), 2);
+        assert_eq!(TraceHeader::width_for(65537), 4);
+        assert_eq!(TraceHeader::width_for(1 << 32), 4);
+        assert_eq!(TraceHeader::width_for((1 << 32) + 1), 8);
+
+        for (distinct, width) in [(10, 1), (300, 2), (70000, 4)] {
+            let mut parser = ToMemoryParser::default();
+            let tokens: Vec<String> = (0..distinct + 5)
+                .map(|i| format!("token {}", i % distinct))
+                .collect();
+            let name = format!("test_width_{}.bin", width);
+            let ids = parser
+                .create_bin(tokens.clone(), dir.path().join(&name))
+                .unwrap();
+
+            let len = std::fs::metadata(dir.path().join(&name)).unwrap().len() as usize;
+            assert_eq!(len, V2_HEADER_SIZE + width * tokens.len());
+
+            let accessor = parser.deserialize(dir.path().join(&name)).unwrap();
+            for (i, id) in ids.iter().enumerate() {
+                assert_eq!(accessor.get(i), *id);
+            }
         }
     }
 }
@@ -0,0 +1,123 @@
+//! Score types of the DTW matrices
+//!
+//! The engines that keep the whole matrix, `StandardDTW`, `UnsafeDTW`, `WindowedDTW` and
+//! `FastDTW`, store one score per cell, an `f64` by default. Integer costs, like the ones of
+//! `STRACDistance`, fit in integer scores that take a half or a quarter of the memory. The
+//! engines align the distances whose costs do not fit, e.g. fractional or negative ones, with
+//! `f64` scores, and so the alignments whose cost saturates.
+//! Only these engines are generic, `FixedDTW` and `WavefrontDTW` keep a few rows and always use
+//! `f64` scores.
+
+/// A score of a cell of the matrix
+pub trait Score: Copy + PartialOrd + std::fmt::Debug {
+    const ZERO: Self;
+    /// Score of the cells that no alignment reaches, it is also the largest score
+    const INFINITY: Self;
+    /// The additions stop at `INFINITY`, so an infinite score can be a finite cost
+    const SATURATES: bool;
+
+    /// The score of the cost, `None` if the score type cannot hold it. Integer scores only hold
+    /// non-negative integer costs and saturate at `INFINITY`, `f32` rounds the cost to the
+    /// nearest one
+    fn from_cost(cost: f64) -> Option<Self>;
+
+    /// The cost of the score, `INFINITY` is the infinite cost
+    fn to_cost(self) -> f64;
+
+    /// The sum of the scores, integer scores saturate at `INFINITY`
+    fn add(self, other: Self) -> Self;
+
+    #[inline]
+    fn min(self, other: Self) -> Self {
+        if other < self {
+            other
+        } else {
+            self
+        }
+    }
+}
+
+macro_rules! float_score {
+    ($($float:ty),*) => {$(
+        impl Score for $float {
+            const ZERO: Self = 0.0;
+            const INFINITY: Self = <$float>::INFINITY;
+            const SATURATES: bool = false;
+
+            #[inline]
+            fn from_cost(cost: f64) -> Option<Self> {
+                Some(cost as $float)
+            }
+
+            #[inline]
+            fn to_cost(self) -> f64 {
+                self as f64
+            }
+
+            #[inline]
+            fn add(self, other: Self) -> Self {
+                self + other
+            }
+        }
+    )*};
+}
+
+macro_rules! integer_score {
+    ($($integer:ty),*) => {$(
+        impl Score for $integer {
+            const ZERO: Self = 0;
+            const INFINITY: Self = <$integer>::MAX;
+            const SATURATES: bool = true;
+
+            #[inline]
+            fn from_cost(cost: f64) -> Option<Self> {
+                // The infinite cost is the largest score
+                if cost >= Self::INFINITY as f64 {
+                    return Some(Self::INFINITY);
+                }
+                // Negative costs would make the saturated sums finite again
+                (cost.fract() == 0.0 && cost >= 0.0).then_some(cost as $integer)
+            }
+
+            #[inline]
+            fn to_cost(self) -> f64 {
+                if self == Self::INFINITY {
+                    f64::INFINITY
+                } else {
+                    self as f64
+                }
+            }
+
+            #[inline]
+            fn add(self, other: Self) -> Self {
+                self.saturating_add(other)
+            }
+        }
+    )*};
+}
+
+float_score!(f32, f64);
+integer_score!(u16, u32, i32);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scores() {
+        assert_eq!(u16::from_cost(3.0), Some(3));
+        assert_eq!(u16::from_cost(2.6), None);
+        assert_eq!(u16::from_cost(-1.0), None);
+        assert_eq!(u16::from_cost(f64::NAN), None);
+        assert_eq!(u16::from_cost(1e9), Some(u16::INFINITY));
+        assert_eq!(u16::from_cost(f64::INFINITY), Some(u16::INFINITY));
+        assert_eq!(u16::INFINITY.to_cost(), f64::INFINITY);
+        assert_eq!(60000u16.add(10000), u16::INFINITY);
+        assert_eq!(u32::INFINITY.add(1), u32::INFINITY);
+        assert_eq!(i32::from_cost(-2.0), None);
+        assert_eq!(i32::from_cost(2.0).unwrap().add(5), 7);
+        assert_eq!(f32::from_cost(0.5).unwrap().add(0.25).to_cost(), 0.75);
+        assert_eq!(f64::INFINITY.add(1.0), f64::INFINITY);
+        assert_eq!(Score::min(2u16, 1), 1);
+    }
+}
@@ -0,0 +1,524 @@
+//! Vectorized kernel of `FixedDTW`
+//!
+//! The matrix is filled by anti-diagonals, whose cells only depend on the two previous ones, with
+//! the distances of the tokens of the traces in a table. With AVX2, detected at runtime, eight
+//! cells of an anti-diagonal are filled at once, otherwise one by one.
+
+use crate::dtw::{Accesor, Distance, FreeGaps, TokenID};
+use std::collections::HashMap;
+
+/// Score type of the vectorized kernel, see `FixedDTW::with_kernel`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kernel {
+    /// Only for integer costs while the scores are below 2^24, where `f32` is exact. Other
+    /// distances fall back to the `f64` rows
+    F32,
+    /// Only for integer costs, other distances fall back to the `f64` rows
+    I32,
+}
+
+/// Largest table of distances, in cells. Traces with more different tokens fall back to the
+/// `f64` rows
+const TABLE_LIMIT: usize = 1 << 20;
+
+/// A score of a lane of the kernel. Unlike the ones of `score::Score`, its additions do not
+/// saturate
+trait Lane: Copy {
+    const ZERO: Self;
+    /// Cost of the cells that no alignment goes through
+    const INFINITY: Self;
+    /// Highest cost of an alignment
+    const LIMIT: f64;
+
+    /// The score of a cost, if the kernel can use it
+    fn from_cost(cost: f64) -> Option<Self>;
+
+    fn to_cost(self) -> f64;
+
+    fn add(self, other: Self) -> Self;
+
+    fn min(self, other: Self) -> Self;
+
+    /// Fills the first cells of `cells` eight at a time, returns how many
+    ///
+    /// # Safety
+    /// The CPU must support AVX2
+    #[cfg(target_arch = "x86_64")]
+    unsafe fn fill_avx2(cells: &mut Cells<Self>, costs: &Costs<Self>) -> usize;
+}
+
+impl Lane for f32 {
+    const ZERO: Self = 0.0;
+    const INFINITY: Self = f32::INFINITY;
+    // Larger integers are not all representable
+    const LIMIT: f64 = (1 << 24) as f64;
+
+    fn from_cost(cost: f64) -> Option<Self> {
+        (cost.fract() == 0.0 && cost.abs() < Self::LIMIT).then_some(cost as f32)
+    }
+
+    fn to_cost(self) -> f64 {
+        self as f64
+    }
+
+    #[inline]
+    fn add(self, other: Self) -> Self {
+        self + other
+    }
+
+    #[inline]
+    fn min(self, other: Self) -> Self {
+        f32::min(self, other)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "avx2")]
+    unsafe fn fill_avx2(cells: &mut Cells<Self>, costs: &Costs<Self>) -> usize {
+        use std::arch::x86_64::*;
+
+        let (open, insert, delete) = (
+            _mm256_set1_ps(costs.open),
+            _mm256_set1_ps(costs.insert),
+            _mm256_set1_ps(costs.delete),
+        );
+        let load = |slice: &[f32], k: usize| _mm256_loadu_ps(slice.as_ptr().add(k));
+        let index =
+            |slice: &[i32], k: usize| _mm256_loadu_si256(slice.as_ptr().add(k) as *const __m256i);
+
+        let mut k = 0;
+        while k + 8 <= cells.total.len() {
+            let token = _mm256_add_epi32(index(cells.row1, k), index(cells.column2, k));
+            let distance = _mm256_i32gather_ps::<4>(costs.table.as_ptr(), token);
+            let diag = _mm256_add_ps(distance, load(cells.diag, k));
+            let up = _mm256_add_ps(
+                _mm256_min_ps(
+                    _mm256_add_ps(load(cells.up_total, k), open),
+                    load(cells.up, k),
+                ),
+                delete,
+            );
+            let left = _mm256_add_ps(
+                _mm256_min_ps(
+                    _mm256_add_ps(load(cells.left_total, k), open),
+                    load(cells.left, k),
+                ),
+                insert,
+            );
+            let total = _mm256_min_ps(_mm256_min_ps(diag, up), left);
+
+            _mm256_storeu_ps(cells.total.as_mut_ptr().add(k), total);
+            _mm256_storeu_ps(cells.new_up.as_mut_ptr().add(k), up);
+            _mm256_storeu_ps(cells.new_left.as_mut_ptr().add(k), left);
+            k += 8;
+        }
+        k
+    }
+}
+
+impl Lane for i32 {
+    const ZERO: Self = 0;
+    // Two costs can be added to it without overflowing, see `Costs::new`
+    const INFINITY: Self = i32::MAX / 4;
+    const LIMIT: f64 = Self::INFINITY as f64;
+
+    fn from_cost(cost: f64) -> Option<Self> {
+        (cost.fract() == 0.0 && cost.abs() < Self::INFINITY as f64).then_some(cost as i32)
+    }
+
+    fn to_cost(self) -> f64 {
+        if self >= Self::INFINITY {
+            f64::INFINITY
+        } else {
+            self as f64
+        }
+    }
+
+    #[inline]
+    fn add(self, other: Self) -> Self {
+        self + other
+    }
+
+    #[inline]
+    fn min(self, other: Self) -> Self {
+        Ord::min(self, other)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "avx2")]
+    unsafe fn fill_avx2(cells: &mut Cells<Self>, costs: &Costs<Self>) -> usize {
+        use std::arch::x86_64::*;
+
+        let (open, insert, delete) = (
+            _mm256_set1_epi32(costs.open),
+            _mm256_set1_epi32(costs.insert),
+            _mm256_set1_epi32(costs.delete),
+        );
+        let load =
+            |slice: &[i32], k: usize| _mm256_loadu_si256(slice.as_ptr().add(k) as *const __m256i);
+        let store = |slice: &mut [i32], k: usize, value| {
+            _mm256_storeu_si256(slice.as_mut_ptr().add(k) as *mut __m256i, value)
+        };
+
+        let mut k = 0;
+        while k + 8 <= cells.total.len() {
+            let token = _mm256_add_epi32(load(cells.row1, k), load(cells.column2, k));
+            let distance = _mm256_i32gather_epi32::<4>(costs.table.as_ptr(), token);
+            let diag = _mm256_add_epi32(distance, load(cells.diag, k));
+            let up = _mm256_add_epi32(
+                _mm256_min_epi32(
+                    _mm256_add_epi32(load(cells.up_total, k), open),
+                    load(cells.up, k),
+                ),
+                delete,
+            );
+            let left = _mm256_add_epi32(
+                _mm256_min_epi32(
+                    _mm256_add_epi32(load(cells.left_total, k), open),
+                    load(cells.left, k),
+                ),
+                insert,
+            );
+            let total = _mm256_min_epi32(_mm256_min_epi32(diag, up), left);
+
+            store(cells.total, k, total);
+            store(cells.new_up, k, up);
+            store(cells.new_left, k, left);
+            k += 8;
+        }
+        k
+    }
+}
+
+/// The costs of the distance as scores
+struct Costs<S> {
+    /// Distance of the `i`-th different token of the first trace and the `j`-th one of the
+    /// second trace, in `i * columns + j`
+    table: Vec<S>,
+    open: S,
+    insert: S,
+    delete: S,
+    /// Some cost is negative, the cells of later anti-diagonals can be cheaper
+    negative: bool,
+}
+
+/// The cells of an anti-diagonal that are not in the first row or column, all the slices have
+/// one element per cell
+struct Cells<'a, S> {
+    /// Index in the table of the rows of the tokens of the first trace
+    row1: &'a [i32],
+    /// Index in the table of the columns of the tokens of the second trace
+    column2: &'a [i32],
+    /// Totals of the cells in the diagonal, two anti-diagonals back
+    diag: &'a [S],
+    /// Totals and gaps of the cells above and to the left, in the previous anti-diagonal
+    up_total: &'a [S],
+    up: &'a [S],
+    left_total: &'a [S],
+    left: &'a [S],
+    total: &'a mut [S],
+    new_up: &'a mut [S],
+    new_left: &'a mut [S],
+}
+
+impl<S: Lane> Cells<'_, S> {
+    /// Fills the cells from the `k`-th one, one by one
+    fn fill(&mut self, costs: &Costs<S>, from: usize) {
+        for k in from..self.total.len() {
+            let token = (self.row1[k] + self.column2[k]) as usize;
+            let diag = costs.table[token].add(self.diag[k]);
+            let up = gap(self.up_total[k], self.up[k], costs.open, costs.delete);
+            let left = gap(self.left_total[k], self.left[k], costs.open, costs.insert);
+
+            self.total[k] = diag.min(up).min(left);
+            self.new_up[k] = up;
+            self.new_left[k] = left;
+        }
+    }
+}
+
+/// See `dtw::gap`
+#[inline]
+fn gap<S: Lane>(total: S, run: S, open: S, extend: S) -> S {
+    total.add(open).min(run).add(extend)
+}
+
+/// Best costs of the cells of an anti-diagonal, by the last move and indexed by row
+struct Diagonal<S> {
+    total: Vec<S>,
+    up: Vec<S>,
+    left: Vec<S>,
+}
+
+/// Cost of the alignment of `chain1` and `chain2` with the free gaps, as `FixedDTW` calculates
+/// it. `None` if the costs do not fit in the score type or the traces have too many different
+/// tokens for the table.
+/// The filling stops once every cell of two consecutive anti-diagonals is above `threshold`, the
+/// cost is then above it as well. Not with negative costs, which can lower it again
+pub(crate) fn calculate<A, B>(
+    kernel: Kernel,
+    distance: &dyn Distance,
+    chain1: &A,
+    chain2: &B,
+    free: FreeGaps,
+    threshold: Option<f64>,
+) -> Option<f64>
+where
+    A: Accesor + ?Sized,
+    B: Accesor + ?Sized,
+{
+    #[cfg(target_arch = "x86_64")]
+    let avx2 = is_x86_feature_detected!("avx2");
+    #[cfg(not(target_arch = "x86_64"))]
+    let avx2 = false;
+
+    match kernel {
+        Kernel::F32 => align::<f32, _, _>(distance, chain1, chain2, free, threshold, avx2),
+        Kernel::I32 => align::<i32, _, _>(distance, chain1, chain2, free, threshold, avx2),
+    }
+}
+
+/// Numbers the different tokens of the trace, returns the number of each token and how many
+/// different ones there are
+fn encode<A>(chain: &A) -> (Vec<i32>, Vec<TokenID>)
+where
+    A: Accesor + ?Sized,
+{
+    let mut numbers = HashMap::new();
+    let mut tokens = vec![];
+    let encoded = (0..chain.size())
+        .map(|i| {
+            let token = chain.get(i);
+            *numbers.entry(token).or_insert_with(|| {
+                tokens.push(token);
+                tokens.len() as i32 - 1
+            })
+        })
+        .collect();
+    (encoded, tokens)
+}
+
+impl<S: Lane> Costs<S> {
+    /// `None` if a cost is not a score, or if the costs of an alignment of `steps` moves could
+    /// reach the infinite score
+    fn new(
+        distance: &dyn Distance,
+        tokens1: &[TokenID],
+        tokens2: &[TokenID],
+        steps: usize,
+    ) -> Option<Self> {
+        let mut table = Vec::with_capacity(tokens1.len() * tokens2.len());
+        let (mut lowest, mut highest) = (0.0f64, 0.0f64);
+        for &a in tokens1 {
+            for &b in tokens2 {
+                let cost = distance.distance(a, b);
+                (lowest, highest) = (lowest.min(cost), highest.max(cost));
+                table.push(S::from_cost(cost)?);
+            }
+        }
+
+        let (open, insert, delete) = (
+            distance.gap_open(),
+            distance.insertion_cost(),
+            distance.deletion_cost(),
+        );
+        highest = highest.max(open + insert).max(open + delete);
+        lowest = lowest.min(open).min(insert).min(delete);
+        if (steps + 1) as f64 * highest >= S::LIMIT {
+            return None;
+        }
+
+        Some(Costs {
+            table,
+            open: S::from_cost(open)?,
+            insert: S::from_cost(insert)?,
+            delete: S::from_cost(delete)?,
+            negative: lowest < 0.0,
+        })
+    }
+}
+
+fn align<S, A, B>(
+    distance: &dyn Distance,
+    chain1: &A,
+    chain2: &B,
+    free: FreeGaps,
+    threshold: Option<f64>,
+    avx2: bool,
+) -> Option<f64>
+where
+    S: Lane,
+    A: Accesor + ?Sized,
+    B: Accesor + ?Sized,
+{
+    let (n, m) = (chain1.size(), chain2.size());
+    let (codes1, tokens1) = encode(chain1);
+    let (codes2, tokens2) = encode(chain2);
+    if tokens1.len() * tokens2.len() > TABLE_LIMIT {
+        return None;
+    }
+    let costs = Costs::<S>::new(distance, &tokens1, &tokens2, n + m)?;
+    let threshold = threshold.filter(|_| !costs.negative);
+
+    // The second trace backwards, so the tokens of both traces are read in order along an
+    // anti-diagonal
+    let columns = tokens2.len() as i32;
+    let row1 = codes1.iter().map(|code| code * columns).collect::<Vec<_>>();
+    let column2 = codes2.into_iter().rev().collect::<Vec<_>>();
+
+    let diagonal = || Diagonal {
+        total: vec![S::INFINITY; n + 1],
+        up: vec![S::INFINITY; n + 1],
+        left: vec![S::INFINITY; n + 1],
+    };
+    let (mut prev, mut cur) = (diagonal(), diagonal());
+    let mut prev2 = vec![S::INFINITY; n + 1];
+
+    let mut best = S::INFINITY;
+    // Lowest total of the previous anti-diagonal
+    let mut prev_lowest = S::INFINITY;
+    for d in 0..=n + m {
+        // Rows of the cells of the anti-diagonal, and of the ones out of the first row and column
+        let (first, last) = (d.saturating_sub(m), d.min(n));
+        let (inner_first, inner_last) = (first.max(1), last.min(d.saturating_sub(1)));
+
+        // First row
+        if first == 0 {
+            let (total, left) = if d == 0 || free.start2 {
+                (S::ZERO, S::INFINITY)
+            } else {
+                let left = gap(prev.total[0], prev.left[0], costs.open, costs.insert);
+                (left, left)
+            };
+            (cur.total[0], cur.up[0], cur.left[0]) = (total, S::INFINITY, left);
+        }
+        // First column
+        if last == d && d > 0 {
+            let (total, up) = if free.start1 {
+                (S::ZERO, S::INFINITY)
+            } else {
+                let up = gap(prev.total[d - 1], prev.up[d - 1], costs.open, costs.delete);
+                (up, up)
+            };
+            (cur.total[d], cur.up[d], cur.left[d]) = (total, up, S::INFINITY);
+        }
+
+        if inner_first <= inner_last {
+            let cells = inner_first..inner_last + 1;
+            let above = inner_first - 1..inner_last;
+            let mut cells = Cells {
+                row1: &row1[above.clone()],
+                column2: &column2[m + inner_first - d..m + inner_last + 1 - d],
+                diag: &prev2[above.clone()],
+                up_total: &prev.total[above.clone()],
+                up: &prev.up[above],
+                left_total: &prev.total[cells.clone()],
+                left: &prev.left[cells.clone()],
+                total: &mut cur.total[cells.clone()],
+                new_up: &mut cur.up[cells.clone()],
+                new_left: &mut cur.left[cells],
+            };
+
+            let mut from = 0;
+            #[cfg(target_arch = "x86_64")]
+            if avx2 {
+                // SAFETY: AVX2 is supported, it was detected at runtime
+                from = unsafe { S::fill_avx2(&mut cells, &costs) };
+            }
+            #[cfg(not(target_arch = "x86_64"))]
+            let _ = avx2;
+            cells.fill(&costs, from);
+        }
+
+        // The ends of the alignment that the free gaps allow
+        if (free.end2 && last == n) || d == n + m {
+            best = best.min(cur.total[n]);
+        }
+        if free.end1 && d >= m {
+            best = best.min(cur.total[first]);
+        }
+
+        // A diagonal move skips an anti-diagonal, so every path still to end goes through this
+        // one or the previous one, and no cost is negative
+        if let Some(threshold) = threshold {
+            let lowest = cur.total[first..=last]
+                .iter()
+                .fold(S::INFINITY, |a, b| a.min(*b));
+            if lowest.min(prev_lowest).to_cost() > threshold {
+                return Some(best.to_cost());
+            }
+            prev_lowest = lowest;
+        }
+
+        std::mem::swap(&mut prev2, &mut prev.total);
+        std::mem::swap(&mut prev, &mut cur);
+    }
+
+    Some(best.to_cost())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dtw::{FixedDTW, STRACDistance, DTW};
+
+    #[test]
+    fn test_kernels() {
+        let mut distance = STRACDistance::new(1.0, 3.0, 0.0);
+        distance.gap_open = 2.0;
+        let chain1 = (0..300).map(|i| (i * i) % 13).collect::<Vec<_>>();
+        let chain2 = (0..170).map(|i| (i * 5) % 11).collect::<Vec<_>>();
+
+        let free = FreeGaps::default();
+        let (expected, _) = FixedDTW::new(&distance).calculate(&chain1, &chain2);
+        for avx2 in [false, true] {
+            #[cfg(target_arch = "x86_64")]
+            if avx2 && !is_x86_feature_detected!("avx2") {
+                continue;
+            }
+            let cost = align::<f32, _, _>(&distance, &chain1, &chain2, free, None, avx2);
+            assert_eq!(cost, Some(expected));
+            let cost = align::<i32, _, _>(&distance, &chain1, &chain2, free, None, avx2);
+            assert_eq!(cost, Some(expected));
+            let cost = align::<i32, _, _>(&distance, &chain2, &chain1, free, None, avx2);
+            assert_eq!(cost, Some(expected));
+
+            let free = FreeGaps::subsequence();
+            let (expected, _) = FixedDTW::new(&distance)
+                .with_free_gaps(free)
+                .calculate(&chain1, &chain2);
+            let cost = align::<i32, _, _>(&distance, &chain1, &chain2, free, None, avx2);
+            assert_eq!(cost, Some(expected));
+        }
+
+        // Only integers are scores, and the costs must stay exact
+        for (kernel, gap_open) in [
+            (Kernel::I32, 0.5),
+            (Kernel::I32, 1e12),
+            (Kernel::F32, 0.5),
+            (Kernel::F32, 0.1),
+            (Kernel::F32, 1e6),
+        ] {
+            distance.gap_open = gap_open;
+            let cost = calculate(kernel, &distance, &chain1, &chain2, free, None);
+            assert_eq!(cost, None);
+        }
+
+        distance.gap_open = 2.0;
+        // Above the threshold, the filling stops before reaching the end
+        let cost = calculate(Kernel::I32, &distance, &chain1, &chain2, free, Some(10.0));
+        assert_eq!(cost, Some(f64::INFINITY));
+        let cost = calculate(
+            Kernel::I32,
+            &distance,
+            &chain1,
+            &chain2,
+            free,
+            Some(expected),
+        );
+        assert_eq!(cost, Some(expected));
+
+        let empty: Vec<TokenID> = vec![];
+        let cost = calculate(Kernel::I32, &distance, &empty, &empty, free, None);
+        assert_eq!(cost, Some(0.0));
+    }
+}
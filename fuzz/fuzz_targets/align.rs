@@ -32,7 +32,7 @@ fuzz_target!(|data: (Vec<usize>, Vec<usize>, usize, usize)| {
 
     let fastdtw = FastDTW::new(&distance, rad, th, &dtw);
 
-    let distance1 = fastdtw.calculate(Box::new(t1.clone()), Box::new(t2.clone()));
+    let distance1 = fastdtw.calculate(&t1, &t2);
 
     log::debug!("Result {}", distance1.0);
 
@@ -0,0 +1,141 @@
+use anyhow::Context;
+use clap::Parser;
+use dtw_core::dtw::DynamicWindow;
+use dtw_core::dtw::WindowedDTW;
+use dtw_core::dtw::DTW;
+use dtw_tools::{ScoreType, TieBreak};
+use std::path::PathBuf;
+
+/// Exact DTW restricted to a band around the diagonal.
+/// Cells out of the band are never calculated, traces that are known to be near-diagonal are
+/// aligned much faster
+#[derive(Parser, Clone)]
+pub struct Opts {
+    #[clap(flatten)]
+    io: dtw_tools::InputOutput,
+
+    /// Shape of the band
+    #[arg(long, value_enum, default_value = "sakoe-chiba")]
+    constraint: Constraint,
+
+    /// Maximum distance to the diagonal, in tokens, of the Sakoe-Chiba band
+    #[arg(long, default_value = "10")]
+    radius: usize,
+
+    /// Maximum slope of the Itakura parallelogram, it should be larger than 1
+    #[arg(long, default_value = "2.0")]
+    slope: f64,
+
+    /// File with the custom band. One line with the first and last column, inclusive and
+    /// separated by spaces, for each row. Rows are the prefixes of the shortest trace, so a trace
+    /// of n tokens needs n + 1 lines
+    #[arg(long, required_if_eq("constraint", "ranges"))]
+    ranges: Option<PathBuf>,
+
+    /// Type of the scores of the matrix
+    #[arg(long, value_enum, default_value = "f64")]
+    score: ScoreType,
+
+    /// Keeps the matrix in a temporary file mapped in memory, see --temp-dir. The system writes
+    /// to disk the parts that do not fit in RAM
+    #[arg(long)]
+    on_disk: bool,
+
+    /// Follows the moves recorded in 2 bits per cell to get the alignment, instead of keeping the
+    /// scores of every cell. It takes 16 to 32 times less memory than the f64 scores
+    #[arg(long)]
+    traceback: bool,
+
+    /// Move of the alignment when several moves give the same cost
+    #[arg(long, value_enum, default_value = "match")]
+    tie_break: TieBreak,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum Constraint {
+    SakoeChiba,
+    Itakura,
+    Ranges,
+}
+
+impl Opts {
+    pub fn general_opts(&self) -> &dtw_tools::GeneralOpts {
+        self.io.general_opts()
+    }
+
+    pub fn io(&self) -> &dtw_tools::InputOutput {
+        &self.io
+    }
+
+    fn read_ranges(path: &PathBuf) -> anyhow::Result<Vec<(usize, usize)>> {
+        let text =
+            std::fs::read_to_string(path).with_context(|| format!("could not read {:?}", path))?;
+
+        let mut ranges = vec![];
+        for (line, row) in text.lines().enumerate() {
+            if row.trim().is_empty() {
+                continue;
+            }
+            let invalid = || format!("invalid range {:?} in line {} of {:?}", row, line + 1, path);
+
+            let mut limits = row.split_whitespace().map(|limit| limit.parse::<usize>());
+            let min = limits.next().with_context(invalid)?.with_context(invalid)?;
+            let max = limits.next().with_context(invalid)?.with_context(invalid)?;
+            if limits.next().is_some() || min > max {
+                anyhow::bail!(invalid());
+            }
+            ranges.push((min, max));
+        }
+        Ok(ranges)
+    }
+
+    pub fn run(
+        &self,
+        tr1: &dyn dtw::dtw::Accesor,
+        tr2: &dyn dtw::dtw::Accesor,
+        distance: &dyn dtw::dtw::Distance,
+        free: dtw::dtw::FreeGaps,
+    ) -> anyhow::Result<dtw::dtw::DTWResult> {
+        let window = match self.constraint {
+            Constraint::SakoeChiba => {
+                DynamicWindow::sakoe_chiba(tr1.size(), tr2.size(), self.radius)
+            }
+            Constraint::Itakura => DynamicWindow::itakura(tr1.size(), tr2.size(), self.slope),
+            Constraint::Ranges => {
+                // clap makes sure the file is given
+                let path = self.ranges.as_ref().context("missing --ranges")?;
+                let ranges = Self::read_ranges(path)?;
+                if ranges.len() != tr1.size() + 1 {
+                    anyhow::bail!(
+                        "{:?} has {} ranges, but the trace needs {}",
+                        path,
+                        ranges.len(),
+                        tr1.size() + 1
+                    );
+                }
+                DynamicWindow::from_ranges(&ranges, tr2.size())
+            }
+        };
+
+        let dtw = WindowedDTW::new(window, distance)
+            .with_free_gaps(free)
+            .with_storage(self.io.storage(self.on_disk))
+            .with_tie_break(self.tie_break.policy());
+        let dtw = if self.traceback {
+            dtw.with_traceback()
+        } else {
+            dtw
+        };
+        let result = match self.score {
+            ScoreType::U16 => dtw.with_score::<u16>().calculate(tr1, tr2),
+            ScoreType::U32 => dtw.with_score::<u32>().calculate(tr1, tr2),
+            ScoreType::I32 => dtw.with_score::<i32>().calculate(tr1, tr2),
+            ScoreType::F32 => dtw.with_score::<f32>().calculate(tr1, tr2),
+            ScoreType::F64 => dtw.calculate(tr1, tr2),
+        };
+        if result.0.is_infinite() {
+            log::warn!("The band does not contain any alignment of the traces");
+        }
+        Ok(result)
+    }
+}
@@ -1,6 +1,6 @@
-
 use clap::Parser;
 use dtw_core::dtw::DTW;
+use dtw_tools::{ScoreType, TieBreak};
 
 /// Standard DTW implementation.
 
@@ -8,6 +8,24 @@ use dtw_core::dtw::DTW;
 pub struct Opts {
     #[clap(flatten)]
     io: dtw_tools::InputOutput,
+
+    /// Type of the scores of the matrix
+    #[arg(long, value_enum, default_value = "f64")]
+    score: ScoreType,
+
+    /// Keeps the matrix in a temporary file mapped in memory, see --temp-dir. The system writes
+    /// to disk the parts that do not fit in RAM
+    #[arg(long)]
+    on_disk: bool,
+
+    /// Follows the moves recorded in 2 bits per cell to get the alignment, instead of keeping the
+    /// scores of every cell. It takes 16 to 32 times less memory than the f64 scores
+    #[arg(long)]
+    traceback: bool,
+
+    /// Move of the alignment when several moves give the same cost
+    #[arg(long, value_enum, default_value = "match")]
+    tie_break: TieBreak,
 }
 
 impl Opts {
@@ -19,12 +37,31 @@ impl Opts {
         &self.io
     }
 
-    pub fn run(&self, tr1: Box<dyn dtw::dtw::Accesor>, tr2: Box<dyn dtw::dtw::Accesor>, distance: Box<dyn dtw::dtw::Distance>) ->  dtw::dtw::DTWResult {
+    pub fn run(
+        &self,
+        tr1: &dyn dtw::dtw::Accesor,
+        tr2: &dyn dtw::dtw::Accesor,
+        distance: &dyn dtw::dtw::Distance,
+        free: dtw::dtw::FreeGaps,
+    ) -> anyhow::Result<dtw::dtw::DTWResult> {
         // Initialize the DTWStandard
         let _general = &self.io;
 
-        let dtw = dtw::dtw::StandardDTW::new(&*distance);
-        dtw.calculate(tr1, tr2)
+        let dtw = dtw::dtw::StandardDTW::new(distance)
+            .with_free_gaps(free)
+            .with_storage(self.io.storage(self.on_disk))
+            .with_tie_break(self.tie_break.policy());
+        let dtw = if self.traceback {
+            dtw.with_traceback()
+        } else {
+            dtw
+        };
+        Ok(match self.score {
+            ScoreType::U16 => dtw.with_score::<u16>().calculate(tr1, tr2),
+            ScoreType::U32 => dtw.with_score::<u32>().calculate(tr1, tr2),
+            ScoreType::I32 => dtw.with_score::<i32>().calculate(tr1, tr2),
+            ScoreType::F32 => dtw.with_score::<f32>().calculate(tr1, tr2),
+            ScoreType::F64 => dtw.calculate(tr1, tr2),
+        })
     }
 }
-
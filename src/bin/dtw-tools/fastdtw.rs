@@ -1,7 +1,9 @@
 use clap::Parser;
 use dtw_core::dtw::FastDTW;
+use dtw_core::dtw::FreeGaps;
 use dtw_core::dtw::StandardDTW;
 use dtw_core::dtw::DTW;
+use dtw_tools::TieBreak;
 
 /// Standard DTW implementation.
 #[derive(Parser, Clone)]
@@ -14,6 +16,20 @@ pub struct Opts {
 
     #[arg(long, default_value = "100")]
     min_dtw_size: usize,
+
+    /// Keeps the matrices in a temporary file mapped in memory, see --temp-dir. The system writes
+    /// to disk the parts that do not fit in RAM
+    #[arg(long)]
+    on_disk: bool,
+
+    /// Follows the moves recorded in 2 bits per cell to get the alignment, instead of keeping the
+    /// scores of every cell. It takes 16 to 32 times less memory than the f64 scores
+    #[arg(long)]
+    traceback: bool,
+
+    /// Move of the alignment when several moves give the same cost
+    #[arg(long, value_enum, default_value = "match")]
+    tie_break: TieBreak,
 }
 
 impl Opts {
@@ -27,15 +43,35 @@ impl Opts {
 
     pub fn run(
         &self,
-        tr1: Box<dyn dtw::dtw::Accesor>,
-        tr2: Box<dyn dtw::dtw::Accesor>,
-        distance: Box<dyn dtw::dtw::Distance>,
-    ) -> dtw::dtw::DTWResult {
+        tr1: &dyn dtw::dtw::Accesor,
+        tr2: &dyn dtw::dtw::Accesor,
+        distance: &dyn dtw::dtw::Distance,
+        free: dtw::dtw::FreeGaps,
+    ) -> anyhow::Result<dtw::dtw::DTWResult> {
+        if free != FreeGaps::default() {
+            anyhow::bail!("fastdtw does not support free gaps, use dtw or memodtw");
+        }
+
         // Initialize the DTWStandard
-        let dtw = StandardDTW::new(&*distance);
+        let storage = self.io.storage(self.on_disk);
+        let dtw = StandardDTW::new(distance)
+            .with_storage(storage.clone())
+            .with_tie_break(self.tie_break.policy());
+        let dtw = if self.traceback {
+            dtw.with_traceback()
+        } else {
+            dtw
+        };
 
-        let fastdtw = FastDTW::new(&*distance, self.window_size, self.min_dtw_size, &dtw);
+        let fastdtw = FastDTW::new(distance, self.window_size, self.min_dtw_size, &dtw)
+            .with_storage(storage)
+            .with_tie_break(self.tie_break.policy());
+        let fastdtw = if self.traceback {
+            fastdtw.with_traceback()
+        } else {
+            fastdtw
+        };
 
-        fastdtw.calculate(tr1, tr2)
+        Ok(fastdtw.calculate(tr1, tr2))
     }
 }
@@ -1,14 +1,17 @@
 extern crate anyhow;
 extern crate clap;
 extern crate dtw as dtw_core;
-extern crate termcolor;
 extern crate dtw_tools;
+extern crate termcolor;
 
+use anyhow::{Context, Result};
 use clap::Parser;
+use dtw_core::distance::{ContentDistance, MatrixDistance, RuleDistance, Similarity};
+use dtw_core::dtw::{Distance, STRACDistance};
 use dtw_core::parsing::TraceEncoder;
-use dtw_tools::CleanerArg;
+use dtw_tools::{CleanerArg, TokenDistance};
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 // This code is copied and transformed from the wasm-tools repo
 //
 //
@@ -34,11 +37,11 @@ macro_rules! subcommands {
         }
 
         impl DTWTools {
-            fn run(self, tr1: Box<dyn dtw_core::dtw::Accesor>, tr2: Box<dyn dtw_core::dtw::Accesor>, distance: Box<dyn dtw_core::dtw::Distance>) -> dtw_core::dtw::DTWResult {
+            fn run(self, tr1: &dyn dtw_core::dtw::Accesor, tr2: &dyn dtw_core::dtw::Accesor, distance: &(dyn dtw_core::dtw::Distance + Sync), free: dtw_core::dtw::FreeGaps) -> Result<dtw_core::dtw::DTWResult> {
                 match self {
                     $(
                         // #[cfg(feature = $string)]
-                        Self::$name(opts) => opts.run(tr1, tr2, distance),
+                        Self::$name(opts) => opts.run(tr1, tr2, distance, free),
                     )*
                 }
             }
@@ -69,18 +72,22 @@ subcommands! {
     (dtw, "dtw")
     (memodtw, "memodtw")
     (fastdtw, "fastdtw")
+    (banded, "banded")
+    (search, "search")
+    (wavefront, "wavefront")
 }
 
-fn split_by_reg(reg: &String, text: &String, cleaner: CleanerArg) -> Vec<String> {
-    let re = regex::Regex::new(reg).unwrap();
+fn split_by_reg(reg: &str, text: &str, cleaner: CleanerArg) -> Result<Vec<String>> {
+    let re = regex::Regex::new(reg).with_context(|| format!("invalid separator {:?}", reg))?;
     let mut vec = vec![];
     // Split the text by the regex
     let cleanerre = match &cleaner.cleaner_regex {
         Some(arg) => {
-            let re = regex::Regex::new(arg).unwrap();
+            let re = regex::Regex::new(arg)
+                .with_context(|| format!("invalid cleaner regex {:?}", arg))?;
             Some(re)
-        },
-        None => None
+        }
+        None => None,
     };
     re.split(text).for_each(|x| {
         // Now we clean x if
@@ -92,48 +99,94 @@ fn split_by_reg(reg: &String, text: &String, cleaner: CleanerArg) -> Vec<String>
                     // Get the group of the arg.cleaner_extract
                     match m {
                         Some(_) => {
-                            let g = cleanerre.captures(x).unwrap()
-                            .get(cleaner.cleaner_extract.or(Some(0)).unwrap()).unwrap().as_str();
+                            // A group that did not participate in the match is empty
+                            let g = cleanerre
+                                .captures(x)
+                                .and_then(|c| c.get(cleaner.cleaner_extract.unwrap_or(0)))
+                                .map(|g| g.as_str())
+                                .unwrap_or_default();
                             vec.push(g.to_string());
-                        },
-                        None => vec.push(x.to_string())
+                        }
+                        None => vec.push(x.to_string()),
                     }
                 }
-                
-            },
-            None => vec.push(x.to_string())
+            }
+            None => vec.push(x.to_string()),
         }
-        
     });
-    vec
+    Ok(vec)
 }
 
-fn main() {
-    let args = <DTWTools as Parser>::parse();
-    args.general_opts().init_logger();
-    let mut encoder = dtw_core::parsing::ToMemoryParser::default();
+/// Traces ending with this suffix are already encoded and are loaded as they are
+const TRACE_BIN_SUFFIX: &str = ".trace.bin";
 
-    log::debug!("Preprocessing as text files");
-    // Check if the flag is bin or text file
-    // Read the input files
-    // Read from fs as text
-    let trace1 = std::fs::read_to_string(&args.io().input1).expect("Could not read file");
-    let trace2 = std::fs::read_to_string(&args.io().input2).expect("Could not read file");
+fn is_trace_bin(path: &Path) -> bool {
+    path.to_str()
+        .map(|p| p.ends_with(TRACE_BIN_SUFFIX))
+        .unwrap_or(false)
+}
 
-    // Separate the tokens by endline
-    // TODO replace with a custom separator
-    log::debug!("Separating by {:?}", args.io().separator);
-    let trace1 = split_by_reg(&args.io().separator, &trace1, args.io().cleaner.clone());
-    let trace2 = split_by_reg(&args.io().separator, &trace2, args.io().cleaner.clone());
+fn file_name(path: &Path) -> Result<&str> {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .with_context(|| format!("{:?} is not a valid trace file name", path))
+}
 
+fn load(
+    encoder: &mut dtw_core::parsing::ToMemoryParser,
+    bin: &Path,
+) -> Result<Box<dyn dtw_core::dtw::Accesor>> {
+    encoder
+        .deserialize(bin.to_path_buf())
+        .with_context(|| format!("could not load {:?}", bin))
+}
 
-    // Swap if they are larger
-    log::debug!("Generating bin traces");
+/// Wraps the base distance with the token costs given in the command line
+fn build_distance(
+    io: &dtw_tools::InputOutput,
+    encoder: &dtw_core::parsing::ToMemoryParser,
+    base: STRACDistance,
+    swapped: bool,
+) -> Result<Box<dyn Distance + Sync>> {
+    if let Some(path) = &io.cost_matrix {
+        let mut matrix = MatrixDistance::load(path, encoder, base)
+            .with_context(|| format!("could not load {:?}", path))?;
+        if swapped {
+            matrix.transpose();
+        }
+        log::debug!("Using {} pairs of the cost matrix", matrix.len());
+        return Ok(Box::new(matrix));
+    }
+
+    if let Some(path) = &io.rules {
+        let rules = RuleDistance::load(path, encoder, base)
+            .with_context(|| format!("could not load {:?}", path))?;
+        log::debug!("The rules give {} classes of tokens", rules.class_count());
+        return Ok(Box::new(rules));
+    }
+
+    if let Some(token_distance) = io.token_distance {
+        let similarity = match token_distance {
+            TokenDistance::Levenshtein => Similarity::Levenshtein,
+            TokenDistance::Jaccard => Similarity::Jaccard,
+            TokenDistance::Numeric => Similarity::Numeric,
+        };
+        return Ok(Box::new(ContentDistance::new(base, similarity, encoder)));
+    }
+
+    Ok(Box::new(base))
+}
+
+fn main() -> Result<()> {
+    let args = <DTWTools as Parser>::parse();
+    args.general_opts().init_logger();
+    let mut encoder = dtw_core::parsing::ToMemoryParser::default();
+    encoder.set_temp_dir(args.io().temp_dir.clone());
 
     // Get the name of the file
     let argsclone = args.clone();
-    let name1 = argsclone.io().input1.file_name().unwrap().to_str().unwrap();
-    let mut name2 = argsclone.io().input2.file_name().unwrap().to_str().unwrap();
+    let name1 = file_name(&argsclone.io().input1)?;
+    let mut name2 = file_name(&argsclone.io().input2)?;
     let n2 = format!("{}_2", name2);
 
     if name1 == name2 {
@@ -141,115 +194,152 @@ fn main() {
         name2 = &n2;
     }
 
-    let (trace1, trace2, name1, name2) = if trace2.len() < trace1.len() {
+    // Already encoded traces are used as they are. They are loaded before encoding the text
+    // traces, so their vocabulary is checked and the new tokens do not take ids already in use.
+    // Bins encoded in separate runs are remapped to the ids of the encoder
+    let mut loaded = vec![];
+    for input in [&args.io().input1, &args.io().input2] {
+        loaded.push(if is_trace_bin(input) {
+            Some(load(&mut encoder, input)?)
+        } else {
+            None
+        });
+    }
+
+    log::debug!("Generating bin traces");
+    let mut bins = vec![];
+    for (input, name) in [(&args.io().input1, name1), (&args.io().input2, name2)] {
+        if is_trace_bin(input) {
+            log::debug!("Using {:?} as an encoded trace", input);
+            bins.push(input.clone());
+            continue;
+        }
+
+        // Read from fs as text
+        let trace = std::fs::read_to_string(input)
+            .with_context(|| format!("could not read {:?}", input))?;
+        // Separate the tokens
+        log::debug!("Separating by {:?}", args.io().separator);
+        let trace = split_by_reg(&args.io().separator, &trace, args.io().cleaner.clone())?;
+
+        let bin = PathBuf::from(format!("{}{}", name, TRACE_BIN_SUFFIX));
+        let _ = encoder
+            .create_bin(trace, bin.clone())
+            .with_context(|| format!("could not write {:?}", bin))?;
+        bins.push(bin);
+    }
+
+    // Load the bins as MMAP
+    let r1 = match loaded[0].take() {
+        Some(trace) => trace,
+        None => load(&mut encoder, &bins[0])?,
+    };
+    let r2 = match loaded[1].take() {
+        Some(trace) => trace,
+        None => load(&mut encoder, &bins[1])?,
+    };
+
+    // Swap if they are larger. The query of a search is always the first trace
+    let swapped = !matches!(args, DTWTools::search(_)) && r2.size() < r1.size();
+    let (r1, r2, bin1, bin2) = if swapped {
         log::debug!("Swapping traces");
-        (trace2, trace1, name2, name1)
+        (r2, r1, bins[1].clone(), bins[0].clone())
     } else {
-        (trace1, trace2, name1, name2)
+        (r1, r2, bins[0].clone(), bins[1].clone())
     };
 
-    let n1clone = name1.clone();
-    let n2clone = name2.clone();
-
-    let _ = encoder.create_bin(
-        trace1,
-        PathBuf::from(format!("{}.trace.bin", name1.clone())),
-    );
-    let _ = encoder.create_bin(
-        trace2,
-        PathBuf::from(format!("{}.trace.bin", name2.clone())),
-    );
-
     log::debug!("Runnning DTW");
 
-    let distance = dtw_core::dtw::STRACDistance::new(
-        args.io().gap_cost.unwrap_or(1.0),
+    let mut distance = STRACDistance::new(
+        args.io().gap_extend.or(args.io().gap_cost).unwrap_or(1.0),
         args.io().missmatch_cost.unwrap_or(3.0),
         0.0,
     );
+    distance.gap_open = args.io().gap_open.unwrap_or(0.0);
+    // Insertions are the tokens of the second input, also when the traces are swapped
+    (distance.insertion_cost, distance.deletion_cost) = if swapped {
+        (args.io().delete_cost, args.io().insert_cost)
+    } else {
+        (args.io().insert_cost, args.io().delete_cost)
+    };
+    let distance = build_distance(args.io(), &encoder, distance, swapped)?;
     let output_alignment = args.io().output.output_alignment.clone();
-    let gap_symbol = args.io().output.gap_symbol.clone();
-
-    let distance = Box::new(distance);
-    // Load the bins as MMAP
-    let r1 = encoder.deserialize(PathBuf::from(format!("{}.trace.bin", name1)));
-    let r2 = encoder.deserialize(PathBuf::from(format!("{}.trace.bin", name2)));
+    let gap_symbol = args.io().output.gap_symbol;
 
-    
+    let free = if swapped {
+        args.io().free_gaps().transposed()
+    } else {
+        args.io().free_gaps()
+    };
 
-    let (distance, wp) = args.run(r1, r2, distance);
+    let (n, m) = (r1.size(), r2.size());
+    // Last cell of the alignment, a match of a search ends before the end of the second trace
+    let (distance, wp, end) = match args {
+        DTWTools::search(opts) => match opts.locate(&*r1, &*r2, &*distance, free)? {
+            Some(found) => (found.cost, found.path, (n, found.end)),
+            None => (f64::INFINITY, None, (n, m)),
+        },
+        args => {
+            let (distance, wp) = args.run(&*r1, &*r2, &*distance, free)?;
+            (distance, wp, (n, m))
+        }
+    };
 
     log::debug!("Generating alignment file");
     // Now we create the alignment using the warping path
     if let Some((wp, _, _)) = wp {
         if let Some(pb) = output_alignment {
             // Open the file for writing
-            let mut file = std::fs::File::create(pb).unwrap();
-
-            let mut tr1p: Vec<Option<usize>> = vec![];
-            let mut tr2p: Vec<Option<usize>> = vec![];
-            // Traverse the warping path in reverse order
-
-            for index in 0..wp.len() - 1 {
-                let reversed = index;
-                let i2 = wp[reversed];
-                let i1 = wp[reversed + 1];
-
-                if i2.0 > i1.0 && i2.1 > i1.1 {
-                    // Write the alignment
-                    tr1p.push(Some(i1.0));
-                    tr2p.push(Some(i1.1));
-                } else if i2.1 > i1.1 {
-                    tr1p.push(None);
-                    tr2p.push(Some(i1.1));
-                } else if i2.0 > i1.0 {
-                    tr2p.push(None);
-                    tr1p.push(Some(i1.0));
+            let mut file =
+                std::fs::File::create(&pb).with_context(|| format!("could not create {:?}", pb))?;
+
+            // Pairs of tokens of the alignment, `None` is a gap, from the end of the path
+            let mut pairs: Vec<(Option<usize>, Option<usize>)> = vec![];
+            let cells: Vec<_> = std::iter::once(end).chain(wp).collect();
+            for step in cells.windows(2) {
+                let (to, from) = (step[0], step[1]);
+                if to == (from.0 + 1, from.1 + 1) {
+                    pairs.push((Some(from.0), Some(from.1)));
+                    continue;
                 }
+                // The free gaps at the end of the traces are left in a single jump
+                pairs.extend((from.1..to.1).rev().map(|i2| (None, Some(i2))));
+                pairs.extend((from.0..to.0).rev().map(|i1| (Some(i1), None)));
             }
 
-            assert_eq!(tr1p.len(), tr2p.len());
+            let r1 = load(&mut encoder, &bin1)?;
+            let r2 = load(&mut encoder, &bin2)?;
 
-            let _pad1 = " ".repeat(encoder.get_largest_token() - n1clone.len());
-            let _pad2 = " ".repeat(encoder.get_largest_token() - n2clone.len());
-            let _div = "-".repeat(2 * encoder.get_largest_token() + 3);
-            // writeln!(file, "{}{} | {}{}", pad1, n1clone, n2clone, pad2).unwrap();
-            // writeln!(file, "{}", div).unwrap();
-
-            let r1 = encoder.deserialize(PathBuf::from(format!("{}.trace.bin", name1)));
-            let r2 = encoder.deserialize(PathBuf::from(format!("{}.trace.bin", name2)));
-                 
-
-            for (i1, i2) in tr1p.iter().rev().zip(tr2p.iter().rev()) {
+            for (i1, i2) in pairs.iter().rev() {
                 match (i1, i2) {
                     (Some(i1), Some(i2)) => {
                         let t1 = r1.get(*i1);
                         let t2 = r2.get(*i2);
-                        let t1 = encoder.id_to_token(t1);
-                        let t2 = encoder.id_to_token(t2);
+                        let t1 = encoder.id_to_token(t1)?;
+                        let t2 = encoder.id_to_token(t2)?;
                         let eq = if t1 == t2 { "|" } else { "!" };
                         // align the tokens
                         let pad1 = " ".repeat(encoder.get_largest_token() - t1.len());
                         let pad2 = " ".repeat(encoder.get_largest_token() - t2.len());
-                        writeln!(file, "{}{} {} {}{}", pad1, t1, eq, t2, pad2).unwrap();
+                        writeln!(file, "{}{} {} {}{}", pad1, t1, eq, t2, pad2)?;
                     }
                     (None, Some(i2)) => {
                         let t2 = r2.get(*i2);
-                        let t2 = encoder.id_to_token(t2);
+                        let t2 = encoder.id_to_token(t2)?;
 
                         let pad = " ".repeat(encoder.get_largest_token() - t2.len());
-                        let pad1 = " ".repeat(encoder.get_largest_token() - 1);
+                        let pad1 = " ".repeat(encoder.get_largest_token().saturating_sub(1));
 
-                        writeln!(file, "{}{} > {}{}", pad1, gap_symbol, t2, pad).unwrap();
+                        writeln!(file, "{}{} > {}{}", pad1, gap_symbol, t2, pad)?;
                     }
                     (Some(i1), None) => {
                         let t1 = r1.get(*i1);
-                        let t1 = encoder.id_to_token(t1);
+                        let t1 = encoder.id_to_token(t1)?;
                         let pad = " ".repeat(encoder.get_largest_token() - t1.len());
 
-                        let pad1 = " ".repeat(encoder.get_largest_token() - 1);
+                        let pad1 = " ".repeat(encoder.get_largest_token().saturating_sub(1));
 
-                        writeln!(file, "{}{} < {}{}", pad, t1, gap_symbol, pad1).unwrap();
+                        writeln!(file, "{}{} < {}{}", pad, t1, gap_symbol, pad1)?;
                     }
                     _ => {}
                 }
@@ -258,4 +348,6 @@ fn main() {
     }
 
     println!("{}", distance);
+
+    Ok(())
 }
@@ -1,12 +1,33 @@
-
+use anyhow::bail;
 use clap::Parser;
 use dtw_core::dtw::DTW;
+use dtw_tools::TieBreak;
 
 /// Standard DTW implementation.
 #[derive(Parser, Clone)]
 pub struct Opts {
     #[clap(flatten)]
     io: dtw_tools::InputOutput,
+
+    /// Stops as soon as the cost is known to be above this value, and prints inf instead
+    #[arg(long)]
+    threshold: Option<f64>,
+
+    /// Fills the matrix with a vectorized kernel with these scores. The result is the same,
+    /// distances that the scores cannot hold exactly are not vectorized. There is no path with
+    /// it, so it cannot be used with --output-alignment
+    #[arg(long, value_enum)]
+    kernel: Option<Kernel>,
+
+    /// Move of the alignment when several moves give the same cost
+    #[arg(long, value_enum, default_value = "match")]
+    tie_break: TieBreak,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum Kernel {
+    F32,
+    I32,
 }
 
 impl Opts {
@@ -20,14 +41,35 @@ impl Opts {
 
     pub fn run(
         &self,
-        tr1: Box<dyn dtw::dtw::Accesor>,
-        tr2: Box<dyn dtw::dtw::Accesor>,
-        distance: Box<dyn dtw::dtw::Distance>,
-    ) -> dtw::dtw::DTWResult {
+        tr1: &dyn dtw::dtw::Accesor,
+        tr2: &dyn dtw::dtw::Accesor,
+        distance: &dyn dtw::dtw::Distance,
+        free: dtw::dtw::FreeGaps,
+    ) -> anyhow::Result<dtw::dtw::DTWResult> {
         // Initialize the DTWStandard
         let _general = &self.io;
+        if self.kernel.is_some() && self.io.output.output_alignment.is_some() {
+            bail!("the vectorized kernel does not calculate the warping path, drop --kernel");
+        }
 
-        let dtw = dtw::dtw::FixedDTW::new(&*distance);
-        dtw.calculate(tr1, tr2)
+        // The path is only needed for the alignment, it costs a second pass over the traces
+        let dtw = if self.io.output.output_alignment.is_some() {
+            dtw::dtw::FixedDTW::with_alignment(distance)
+        } else {
+            dtw::dtw::FixedDTW::new(distance)
+        };
+        let dtw = dtw
+            .with_free_gaps(free)
+            .with_tie_break(self.tie_break.policy());
+        let dtw = match self.threshold {
+            Some(threshold) => dtw.with_threshold(threshold),
+            None => dtw,
+        };
+        let dtw = match self.kernel {
+            Some(Kernel::F32) => dtw.with_kernel(dtw::simd::Kernel::F32),
+            Some(Kernel::I32) => dtw.with_kernel(dtw::simd::Kernel::I32),
+            None => dtw,
+        };
+        Ok(dtw.calculate(tr1, tr2))
     }
 }
@@ -0,0 +1,88 @@
+use clap::Parser;
+use dtw_core::dtw::FixedDTW;
+use dtw_core::dtw::FreeGaps;
+use dtw_core::dtw::Match;
+
+/// Locates the first trace, the query, inside the second one.
+/// The tokens of the second trace before and after the match are free. Prints the offset of the
+/// first token of the match and the offset after its last token, then the cost.
+/// With --threshold or --top every non-overlapping occurrence is printed in order, one per line
+/// with its cost, and the last line is the cost of the cheapest one
+#[derive(Parser, Clone)]
+pub struct Opts {
+    #[clap(flatten)]
+    io: dtw_tools::InputOutput,
+
+    /// Prints every non-overlapping occurrence whose cost is not above this value
+    #[arg(long)]
+    threshold: Option<f64>,
+
+    /// Prints only the k cheapest non-overlapping occurrences
+    #[arg(long, value_name = "K")]
+    top: Option<usize>,
+}
+
+impl Opts {
+    pub fn general_opts(&self) -> &dtw_tools::GeneralOpts {
+        self.io.general_opts()
+    }
+
+    pub fn io(&self) -> &dtw_tools::InputOutput {
+        &self.io
+    }
+
+    pub fn run(
+        &self,
+        tr1: &dyn dtw::dtw::Accesor,
+        tr2: &dyn dtw::dtw::Accesor,
+        distance: &dyn dtw::dtw::Distance,
+        free: dtw::dtw::FreeGaps,
+    ) -> anyhow::Result<dtw::dtw::DTWResult> {
+        Ok(match self.locate(tr1, tr2, distance, free)? {
+            Some(found) => (found.cost, found.path),
+            None => (f64::INFINITY, None),
+        })
+    }
+
+    /// The best match, the one whose alignment is written. `None` if the query does not occur
+    pub fn locate(
+        &self,
+        tr1: &dyn dtw::dtw::Accesor,
+        tr2: &dyn dtw::dtw::Accesor,
+        distance: &dyn dtw::dtw::Distance,
+        free: dtw::dtw::FreeGaps,
+    ) -> anyhow::Result<Option<Match>> {
+        if free != FreeGaps::default() {
+            anyhow::bail!("the second trace is always free around the match of a search");
+        }
+
+        // The memory only grows with the query, the reference can be as long as needed
+        let dtw = if self.io.output.output_alignment.is_some() {
+            FixedDTW::with_alignment(distance)
+        } else {
+            FixedDTW::new(distance)
+        };
+
+        if self.threshold.is_none() && self.top.is_none() {
+            let found = dtw.search(tr1, tr2);
+            println!("{} {}", found.start, found.end);
+            return Ok(Some(found));
+        }
+
+        let mut found = dtw.occurrences(tr1, tr2, self.threshold.unwrap_or(f64::INFINITY));
+        if let Some(k) = self.top {
+            found.sort_by(|a, b| a.cost.total_cmp(&b.cost));
+            found.truncate(k);
+            found.sort_by_key(|m| m.start);
+        }
+        for m in &found {
+            println!("{} {} {}", m.start, m.end, m.cost);
+        }
+
+        let best = found.into_iter().min_by(|a, b| a.cost.total_cmp(&b.cost));
+        if best.is_none() {
+            log::warn!("The query does not occur in the second trace");
+        }
+        Ok(best)
+    }
+}
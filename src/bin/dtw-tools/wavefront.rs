@@ -0,0 +1,44 @@
+use anyhow::bail;
+use clap::Parser;
+use dtw_core::dtw::WavefrontDTW;
+use dtw_core::dtw::DTW;
+
+/// Exact DTW filled by anti-diagonals in parallel, for very long traces.
+/// The memory is linear, but there is no warping path. The number of threads is the one of
+/// rayon, RAYON_NUM_THREADS by default
+#[derive(Parser, Clone)]
+pub struct Opts {
+    #[clap(flatten)]
+    io: dtw_tools::InputOutput,
+
+    /// Cells of an anti-diagonal filled by each task
+    #[arg(long, default_value = "4096")]
+    chunk: usize,
+}
+
+impl Opts {
+    pub fn general_opts(&self) -> &dtw_tools::GeneralOpts {
+        self.io.general_opts()
+    }
+
+    pub fn io(&self) -> &dtw_tools::InputOutput {
+        &self.io
+    }
+
+    pub fn run(
+        &self,
+        tr1: &dyn dtw::dtw::Accesor,
+        tr2: &dyn dtw::dtw::Accesor,
+        distance: &(dyn dtw::dtw::Distance + Sync),
+        free: dtw::dtw::FreeGaps,
+    ) -> anyhow::Result<dtw::dtw::DTWResult> {
+        if self.io.output.output_alignment.is_some() {
+            bail!("wavefront does not calculate the warping path, use dtw or memodtw");
+        }
+
+        let dtw = WavefrontDTW::new(distance)
+            .with_free_gaps(free)
+            .with_chunk(self.chunk);
+        Ok(dtw.calculate(tr1, tr2))
+    }
+}
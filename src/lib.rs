@@ -57,10 +57,39 @@ pub struct InputOutput {
     /// The cost of a gap
     #[arg(long)]
     pub gap_cost: Option<f64>,
+    /// Extra cost paid once for every run of consecutive gaps, so a block of insertions is
+    /// scored as a single event
+    #[arg(long)]
+    pub gap_open: Option<f64>,
+    /// The cost of every gap of a run. Defaults to the gap cost
+    #[arg(long)]
+    pub gap_extend: Option<f64>,
+    /// The cost of every token that is only in the second trace. Defaults to the gap extend cost
+    #[arg(long)]
+    pub insert_cost: Option<f64>,
+    /// The cost of every token that is only in the first trace. Defaults to the gap extend cost
+    #[arg(long)]
+    pub delete_cost: Option<f64>,
     /// The cost of aligning two tokens that mismatch
     #[arg(long)]
     pub missmatch_cost: Option<f64>,
 
+    /// CSV file with the cost of aligning specific pairs of tokens, one `token1,token2,cost` line
+    /// per pair. The other pairs cost the mismatch cost. Other formats, e.g. TOML, are not
+    /// supported
+    #[arg(long)]
+    pub cost_matrix: Option<PathBuf>,
+
+    /// Compare the text of the tokens, so tokens that only differ by a register or an address
+    /// cost less than a full mismatch
+    #[arg(long, value_enum, conflicts_with = "cost_matrix")]
+    pub token_distance: Option<TokenDistance>,
+
+    /// CSV file with ordered rules, one `pattern1,pattern2,cost` line per rule. Two different
+    /// tokens cost the cost of the first rule whose patterns match them, in any order
+    #[arg(long, conflicts_with_all = ["cost_matrix", "token_distance"])]
+    pub rules: Option<PathBuf>,
+
     /// Separator as a regular expression
     #[arg(long, default_value = "\n")]
     pub separator: String,
@@ -70,11 +99,71 @@ pub struct InputOutput {
     #[clap(flatten)]
     pub cleaner: CleanerArg,
 
+    /// Input, 1 or 2, whose tokens before the alignment are not paid. Can be repeated
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=2))]
+    pub free_start: Vec<u8>,
+
+    /// Input, 1 or 2, whose tokens after the alignment are not paid. Can be repeated
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=2))]
+    pub free_end: Vec<u8>,
+
+    /// Directory for the temporary traces written by FastDTW and the matrices of --on-disk.
+    /// Defaults to the system one
+    #[arg(long)]
+    pub temp_dir: Option<PathBuf>,
+
     /// If the output alignemtn flag is set, then the cleaned trace is outputted
-    #[arg(long, default_value="false")]
-    pub output_cleaned_trace: bool
+    #[arg(long, default_value = "false")]
+    pub output_cleaned_trace: bool,
 }
 
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum TokenDistance {
+    /// Edit distance between the tokens
+    Levenshtein,
+    /// Fields, separated by spaces and commas, that are only in one of the tokens
+    Jaccard,
+    /// Difference of the numeric fields, e.g. addresses
+    Numeric,
+}
+
+/// Type of the scores of the matrix. Integer scores take less memory, but they only hold
+/// non-negative integer costs, other costs are aligned with f64 scores. So are the alignments
+/// whose cost saturates an integer score
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum ScoreType {
+    /// Integer costs, the alignments above 65534 are aligned again with f64 scores
+    U16,
+    /// Integer costs
+    U32,
+    /// Integer costs
+    I32,
+    /// Costs with about 7 significant digits
+    F32,
+    /// Any cost
+    F64,
+}
+
+/// Move of the alignment when several moves give the same cost
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum TieBreak {
+    /// Aligns the tokens of both inputs
+    Match,
+    /// A token of the second input against a gap in the first one
+    GapIn1,
+    /// A token of the first input against a gap in the second one
+    GapIn2,
+}
+
+impl TieBreak {
+    pub fn policy(self) -> dtw::dtw::TieBreak {
+        match self {
+            TieBreak::Match => dtw::dtw::TieBreak::Match,
+            TieBreak::GapIn1 => dtw::dtw::TieBreak::GapIn1,
+            TieBreak::GapIn2 => dtw::dtw::TieBreak::GapIn2,
+        }
+    }
+}
 
 #[derive(clap::Parser, Clone)]
 pub struct CleanerArg {
@@ -101,5 +190,24 @@ impl InputOutput {
     pub fn general_opts(&self) -> &GeneralOpts {
         &self.general
     }
+
+    /// The free gaps of the inputs, in the order they are given
+    pub fn free_gaps(&self) -> dtw::dtw::FreeGaps {
+        dtw::dtw::FreeGaps {
+            start1: self.free_start.contains(&1),
+            end1: self.free_end.contains(&1),
+            start2: self.free_start.contains(&2),
+            end2: self.free_end.contains(&2),
+        }
+    }
+
+    /// Where the engines keep their matrices, a temporary file in `temp_dir` with `on_disk`
+    pub fn storage(&self, on_disk: bool) -> dtw::matrix::Storage {
+        if on_disk {
+            dtw::matrix::Storage::Disk(self.temp_dir.clone())
+        } else {
+            dtw::matrix::Storage::Memory
+        }
+    }
 }
 impl OutputArg {}